@@ -0,0 +1,112 @@
+//! amounts
+//!
+//! Amounts are counted in the smallest unit, COIN units make a coin. Every
+//! amount, and every sum of amounts, must stay within MAX_MONEY.
+
+use super::*;
+use failure::format_err;
+
+/// Amount is a number of the smallest unit
+pub type Amount = u64;
+
+/// DECIMALS is the number of decimal places of a coin
+pub const DECIMALS: usize = 8;
+/// COIN is the number of units of a coin
+pub const COIN: Amount = 100_000_000;
+/// MAX_MONEY bounds any output value and any sum of values
+pub const MAX_MONEY: Amount = 21_000_000 * COIN;
+
+/// MoneyRange checks that value does not exceed MAX_MONEY
+pub fn money_range(value: Amount) -> bool {
+    value <= MAX_MONEY
+}
+
+/// CheckedAdd adds two amounts, failing when the sum exceeds MAX_MONEY
+pub fn checked_add(a: Amount, b: Amount) -> Result<Amount> {
+    match a.checked_add(b) {
+        Some(sum) if money_range(sum) => Ok(sum),
+        _ => Err(format_err!("ERROR: Amount out of range")),
+    }
+}
+
+/// CheckedSum adds amounts, failing when the sum exceeds MAX_MONEY
+pub fn checked_sum<I: IntoIterator<Item = Amount>>(values: I) -> Result<Amount> {
+    values.into_iter().try_fold(0, checked_add)
+}
+
+/// ParseAmount parses a number of coins with up to DECIMALS decimal places
+/// into units
+pub fn parse_amount(text: &str) -> Result<Amount> {
+    let text = text.trim();
+    let (coins, fraction) = match text.find('.') {
+        Some(point) => (&text[..point], &text[point + 1..]),
+        None => (text, ""),
+    };
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if (coins.is_empty() && fraction.is_empty())
+        || !is_digits(coins)
+        || !is_digits(fraction)
+        || fraction.len() > DECIMALS
+    {
+        return Err(format_err!("ERROR: Invalid amount {}", text));
+    }
+
+    let coins: Amount = if coins.is_empty() {
+        0
+    } else {
+        coins
+            .parse()
+            .map_err(|_| format_err!("ERROR: Amount out of range"))?
+    };
+    let fraction: Amount = format!("{:0<width$}", fraction, width = DECIMALS).parse()?;
+    match coins.checked_mul(COIN) {
+        Some(units) => checked_add(units, fraction),
+        None => Err(format_err!("ERROR: Amount out of range")),
+    }
+}
+
+/// FormatAmount formats units as a number of coins with DECIMALS decimal places
+pub fn format_amount(value: Amount) -> String {
+    format!(
+        "{}.{:0width$}",
+        value / COIN,
+        value % COIN,
+        width = DECIMALS
+    )
+}
+
+/// FormatSignedAmount formats a change of balance in units as coins
+pub fn format_signed_amount(value: i64) -> String {
+    if value < 0 {
+        format!("-{}", format_amount(value.unsigned_abs()))
+    } else {
+        format_amount(value as Amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_amount() {
+        assert_eq!(parse_amount("1").unwrap(), COIN);
+        assert_eq!(parse_amount("0.5").unwrap(), COIN / 2);
+        assert_eq!(parse_amount(".00000001").unwrap(), 1);
+        assert_eq!(parse_amount("21000000").unwrap(), MAX_MONEY);
+        parse_amount("21000000.00000001").unwrap_err();
+        parse_amount("0.000000001").unwrap_err();
+        parse_amount("-1").unwrap_err();
+        parse_amount("1e3").unwrap_err();
+        parse_amount(".").unwrap_err();
+        parse_amount("99999999999999999999").unwrap_err();
+
+        assert_eq!(format_amount(150_000_000), "1.50000000");
+        assert_eq!(format_amount(1), "0.00000001");
+        assert_eq!(format_signed_amount(-(COIN as i64)), "-1.00000000");
+
+        assert_eq!(checked_sum(vec![MAX_MONEY - 1, 1]).unwrap(), MAX_MONEY);
+        checked_sum(vec![MAX_MONEY, 1]).unwrap_err();
+        checked_add(u64::MAX, 1).unwrap_err();
+    }
+}
@@ -5,31 +5,109 @@ use crate::transaction::Transaction;
 use bincode::serialize;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use failure::format_err;
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::time::SystemTime;
 
-const TARGET_HEXS: usize = 4;
+/// INITIAL_BITS is the number of leading zero bits required of the genesis block hash
+pub const INITIAL_BITS: u32 = 16;
+/// MIN_BITS and MAX_BITS bound the difficulty the retargeting rule may choose
+pub const MIN_BITS: u32 = 8;
+pub const MAX_BITS: u32 = 64;
+
+/// BLOCK_VERSION is the version of the blocks made by this node
+pub const BLOCK_VERSION: i32 = 1;
+
+/// BlockHeader is the part of a block its hash is computed from
+///
+/// The header commits to the transactions through their merkle root, so
+/// the proof-of-work is checked and searched without the transactions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub timestamp: u128,
+    /// Bits is the number of leading zero bits the block hash must have
+    pub bits: u32,
+    /// Nonce is the last field, so that the miner rewrites only its bytes
+    pub nonce: u64,
+}
 
 /// Block keeps block headers
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
-    timestamp: u128,
+    header: BlockHeader,
     transactions: Vec<Transaction>,
-    prev_block_hash: String,
     hash: String,
-    nonce: i32,
     height: i32,
 }
 
+impl BlockHeader {
+    /// Hash returns the hash of the header
+    pub fn hash(&self) -> Result<String> {
+        let data = serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.input(&data[..]);
+        Ok(hasher.result_str())
+    }
+
+    /// CheckProofOfWork checks that the header hash meets its own target
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        Ok(meets_target(&serialize(self)?, self.bits))
+    }
+
+    /// SearchNonce tries the nonces of range, given as first, step and last,
+    /// until one meets the target, found is set by a worker or cancel is set
+    ///
+    /// The header is serialized once and only the bytes of the nonce are
+    /// rewritten for each attempt
+    fn search_nonce(
+        &self,
+        (first, step, last): (u64, u64, u64),
+        found: &AtomicBool,
+        cancel: &AtomicBool,
+        hashes: &AtomicU64,
+    ) -> Result<Option<u64>> {
+        let mut data = serialize(self)?;
+        let at = data.len() - std::mem::size_of::<u64>();
+        let mut nonce = first;
+        let mut tried = 0;
+        let result = loop {
+            if nonce > last || found.load(Ordering::Relaxed) || cancel.load(Ordering::Relaxed) {
+                break None;
+            }
+            tried += 1;
+            data[at..].copy_from_slice(&nonce.to_le_bytes());
+            if meets_target(&data, self.bits) {
+                found.store(true, Ordering::Relaxed);
+                break Some(nonce);
+            }
+            nonce = match nonce.checked_add(step) {
+                Some(nonce) => nonce,
+                None => break None,
+            };
+        };
+        hashes.fetch_add(tried, Ordering::Relaxed);
+        Ok(result)
+    }
+}
+
 impl Block {
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+        self.header.prev_block_hash.clone()
     }
 
     pub fn get_transaction(&self) -> &Vec<Transaction> {
@@ -40,77 +118,191 @@ impl Block {
         self.height
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+
+    pub fn get_nonce(&self) -> u64 {
+        self.header.nonce
+    }
+
+    /// GetBits returns the number of leading zero bits the block hash must have
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
     /// NewBlock creates and returns Block
     pub fn new_block(
         transactions: Vec<Transaction>,
         prev_block_hash: String,
         height: i32,
+        bits: u32,
+    ) -> Result<Block> {
+        let mut block = Block::new_template(transactions, prev_block_hash, height, bits)?;
+        block.run_proof_of_work()?;
+        Ok(block)
+    }
+
+    /// NewTemplate creates a Block whose proof-of-work is still to be done
+    pub fn new_template(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        bits: u32,
     ) -> Result<Block> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
-        let mut block = Block {
-            timestamp,
+        Ok(Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_block_hash,
+                merkle_root: merkle_root(&transactions)?,
+                timestamp,
+                bits,
+                nonce: 0,
+            },
             transactions,
-            prev_block_hash,
             hash: String::new(),
-            nonce: 0,
             height,
-        };
-        block.run_proof_of_work()?;
-        Ok(block)
+        })
     }
 
     /// NewGenesisBlock creates and returns genesis Block
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+        Block::new_block(vec![coinbase], String::new(), 0, INITIAL_BITS).unwrap()
     }
 
     /// Run performs a proof-of-work
     fn run_proof_of_work(&mut self) -> Result<()> {
         info!("Mining the block");
-        while !self.validate()? {
-            self.nonce += 1;
-        }
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
+        self.run_parallel_proof_of_work(1, &AtomicBool::new(false), &AtomicU64::new(0))?;
         Ok(())
     }
 
-    /// HashTransactions returns a hash of the transactions in the block
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
-        }
-        let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(transactions);
+    /// RunParallelProofOfWork searches the nonce across threads workers, worker
+    /// i trying the nonces i, i + threads, ...
+    ///
+    /// Once every nonce was tried the extra-nonce of the coinbase is changed
+    /// and the nonces are tried again. It gives up only when cancel is set,
+    /// and returns whether a nonce was found. The hashes tried are added to hashes
+    pub fn run_parallel_proof_of_work(
+        &mut self,
+        threads: usize,
+        cancel: &AtomicBool,
+        hashes: &AtomicU64,
+    ) -> Result<bool> {
+        self.search_nonces(threads, u64::MAX, cancel, hashes)
+    }
+
+    /// SearchNonces searches the nonces up to max_nonce, rolling the
+    /// extra-nonce each time they are exhausted
+    fn search_nonces(
+        &mut self,
+        threads: usize,
+        max_nonce: u64,
+        cancel: &AtomicBool,
+        hashes: &AtomicU64,
+    ) -> Result<bool> {
+        let threads = threads.max(1);
+        loop {
+            let found = AtomicBool::new(false);
+            let header = &self.header;
+            let nonce = thread::scope(|s| {
+                let workers: Vec<_> = (0..threads)
+                    .map(|first| {
+                        let found = &found;
+                        s.spawn(move || {
+                            let range = (first as u64, threads as u64, max_nonce);
+                            header.search_nonce(range, found, cancel, hashes)
+                        })
+                    })
+                    .collect();
+                let mut nonce = None;
+                for worker in workers {
+                    match worker.join() {
+                        Ok(result) => nonce = nonce.or(result?),
+                        Err(_) => return Err(format_err!("ERROR: Mining thread panicked")),
+                    }
+                }
+                Ok(nonce)
+            })?;
 
-        Ok(tree.root())
+            if let Some(nonce) = nonce {
+                self.header.nonce = nonce;
+                self.hash = self.calculate_hash()?;
+                return Ok(true);
+            }
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(false);
+            }
+            self.roll_extra_nonce()?;
+        }
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXS,
-            self.nonce,
+    /// RollExtraNonce increments the extra-nonce of the coinbase, which
+    /// changes the merkle root and so gives new nonces to try
+    fn roll_extra_nonce(&mut self) -> Result<()> {
+        let coinbase = match self.transactions.first_mut() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err(format_err!("ERROR: Block has no coinbase")),
+        };
+        let extra_nonce = match coinbase.extra_nonce() {
+            Some(extra_nonce) => extra_nonce,
+            None => return Err(format_err!("ERROR: Coinbase has no extra-nonce")),
+        };
+        debug!(
+            "nonces exhausted, roll extra-nonce to {}",
+            extra_nonce.wrapping_add(1)
         );
-        let bytes = serialize(&content)?;
-        Ok(bytes)
+        coinbase.set_extra_nonce(extra_nonce.wrapping_add(1))?;
+        self.header.merkle_root = merkle_root(&self.transactions)?;
+        Ok(())
     }
 
-    /// Validate validates block's PoW
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1: Vec<u8> = Vec::new();
-        vec1.resize(TARGET_HEXS, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXS] == String::from_utf8(vec1)?)
+    /// CalculateHash recomputes the hash of the block from its header
+    pub fn calculate_hash(&self) -> Result<String> {
+        self.header.hash()
+    }
+
+    /// CheckMerkleRoot checks that the header commits to the transactions of the block
+    pub fn check_merkle_root(&self) -> Result<bool> {
+        Ok(merkle_root(&self.transactions)? == self.header.merkle_root)
+    }
+}
+
+/// MerkleRoot returns a hash of the transactions in the block
+fn merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let mut hashes = Vec::new();
+    for tx in transactions {
+        hashes.push(tx.hash()?.as_bytes().to_owned());
+    }
+    let tree = CBMT::<Vec<u8>, MergeVu8>::build_merkle_tree(hashes);
+
+    Ok(tree.root())
+}
+
+/// MeetsTarget checks that the hash of data has at least bits leading zero bits
+fn meets_target(data: &[u8], bits: u32) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    let mut hash: [u8; 32] = [0; 32];
+    hasher.result(&mut hash);
+    leading_zero_bits(&hash) >= bits
+}
+
+/// LeadingZeroBits counts the zero bits at the start of a hash
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for b in hash {
+        if *b == 0 {
+            bits += 8;
+        } else {
+            bits += b.leading_zeros();
+            break;
+        }
     }
+    bits
 }
 
 struct MergeVu8 {}
@@ -127,3 +319,79 @@ impl Merge for MergeVu8 {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::INITIAL_SUBSIDY;
+    use crate::script::{Op, Script};
+    use crate::wallets::get_script_address;
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f, 0xff]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x80]), 16);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_parallel_proof_of_work() {
+        let cbtx = Transaction::new_coinbase(
+            get_script_address(&Script(vec![Op::Push(vec![0; 32])])).unwrap(),
+            String::new(),
+            INITIAL_SUBSIDY,
+        )
+        .unwrap();
+        let hashes = AtomicU64::new(0);
+
+        let mut block = Block::new_template(vec![cbtx], String::new(), 0, 12).unwrap();
+        assert!(block
+            .run_parallel_proof_of_work(4, &AtomicBool::new(false), &hashes)
+            .unwrap());
+        assert!(block.header.check_proof_of_work().unwrap());
+        assert_eq!(block.get_hash(), block.calculate_hash().unwrap());
+        assert!(hashes.load(Ordering::Relaxed) > 0);
+
+        let mut block = Block::new_template(block.transactions, String::new(), 0, 64).unwrap();
+        assert!(!block
+            .run_parallel_proof_of_work(4, &AtomicBool::new(true), &hashes)
+            .unwrap());
+        assert!(block.get_hash().is_empty());
+
+        // with 4 nonces per merkle root the extra-nonce has to be rolled
+        let mut block = Block::new_template(block.transactions, String::new(), 0, 12).unwrap();
+        assert!(block
+            .search_nonces(2, 3, &AtomicBool::new(false), &hashes)
+            .unwrap());
+        assert!(block.get_nonce() <= 3);
+        assert!(block.header.check_proof_of_work().unwrap());
+        assert!(block.transactions[0].has_valid_id().unwrap());
+        assert!(block.check_merkle_root().unwrap());
+    }
+
+    #[test]
+    fn test_block_header() {
+        let cbtx = Transaction::new_coinbase(
+            get_script_address(&Script(vec![Op::Push(vec![0; 32])])).unwrap(),
+            String::new(),
+            INITIAL_SUBSIDY,
+        )
+        .unwrap();
+        let block = Block::new_block(vec![cbtx], String::new(), 0, 8).unwrap();
+        let header = block.get_header();
+        assert_eq!(header.version, BLOCK_VERSION);
+        assert_eq!(header.hash().unwrap(), block.get_hash());
+        assert!(header.check_proof_of_work().unwrap());
+        assert!(block.check_merkle_root().unwrap());
+
+        // the nonce ends the serialized header
+        let data = serialize(header).unwrap();
+        assert_eq!(data[data.len() - 8..], header.nonce.to_le_bytes());
+
+        let mut changed = block.clone();
+        changed.transactions[0].set_extra_nonce(1).unwrap();
+        assert!(!changed.check_merkle_root().unwrap());
+        assert_eq!(changed.calculate_hash().unwrap(), block.get_hash());
+    }
+}
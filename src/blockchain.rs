@@ -1,21 +1,232 @@
 //! Blockchain
 
 use super::*;
+use crate::amount::*;
 use crate::block::*;
+use crate::script::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use failure::format_err;
+use failure::{format_err, Fail};
+use serde::{Deserialize, Serialize};
 use sled;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
 
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+/// RETARGET_INTERVAL is the number of blocks between difficulty adjustments
+const RETARGET_INTERVAL: i32 = 10;
+/// TARGET_BLOCK_SPACING is the expected time between two blocks in milliseconds
+const TARGET_BLOCK_SPACING: u128 = 10_000;
+/// MAX_RETARGET_STEP limits how many bits one adjustment may add or remove
+const MAX_RETARGET_STEP: i64 = 2;
+/// MAX_FUTURE_BLOCK_TIME is how far ahead of the local clock a block may be, in milliseconds
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+/// TX_INDEX_TREE maps a txid to the hash of its block and its position in it
+const TX_INDEX_TREE: &str = "txindex";
+/// SPENT_INDEX_TREE maps each output spent in the active chain, as given by
+/// spent_key, to the height of the block spending it
+const SPENT_INDEX_TREE: &str = "spentindex";
+/// TX_INDEX_FLAG is set once the transaction index has been built, to the
+/// version of its format
+const TX_INDEX_FLAG: &str = "TXINDEX";
+/// TX_INDEX_VERSION is the format of the transaction index, which is rebuilt
+/// when it changes
+const TX_INDEX_VERSION: &[u8] = &[2];
+/// HEIGHT_INDEX_TREE maps the height of each block of the active chain to its hash
+const HEIGHT_INDEX_TREE: &str = "heights";
+/// HEADERS_TREE maps the hash of each stored block, and of each header
+/// received ahead of its block, to the header and its height
+const HEADERS_TREE: &str = "headers";
+/// WORK_TREE maps the hash of each stored block and header to the cumulative
+/// work of the chain it ends
+const WORK_TREE: &str = "work";
+/// INVALID_TREE keeps the hashes of the blocks found to break the consensus
+/// rules, and of the headers found to descend from them
+const INVALID_TREE: &str = "invalid";
+/// MAX_BLOCK_SIZE bounds the serialized size of a block
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// MAX_HEADERS is the largest number of headers sent in answer to getheaders
+pub const MAX_HEADERS: usize = 2000;
+/// PARAMS_KEY keeps the ChainParams the chain was created with
+const PARAMS_KEY: &str = "PARAMS";
+/// VERSION_KEY keeps the format of the blocks db
+const VERSION_KEY: &str = "VERSION";
+/// BLOCKS_VERSION is the format of the blocks db, which cannot be read by a
+/// node of another format
+const BLOCKS_VERSION: &[u8] = b"1";
+/// INITIAL_SUBSIDY is the value created by the coinbase of the first blocks
+pub const INITIAL_SUBSIDY: Amount = 10 * COIN;
+/// HALVING_INTERVAL is the number of blocks after which the subsidy halves,
+/// unless the chain was created with another interval
+pub const HALVING_INTERVAL: i32 = 210_000;
+/// MAX_SUPPLY bounds the value ever created by the subsidy
+pub const MAX_SUPPLY: Amount = 21_000_000 * COIN;
+/// COINBASE_MATURITY is the number of confirmations a coinbase output needs
+/// before it is spent, unless the chain was created with another number
+pub const COINBASE_MATURITY: i32 = 100;
+
+/// BlockError is the reason a block is rejected by ValidateBlock
+#[derive(Debug)]
+pub enum BlockError {
+    TooLarge(usize),
+    BadHash,
+    BadMerkleRoot,
+    BadProofOfWork,
+    BadDifficulty(u32, u32),
+    UnknownParent(String),
+    MissingParent(String),
+    UnexpectedGenesis,
+    BadHeight(i32, i32),
+    BadTimestamp(u128),
+    FutureTimestamp(u128),
+    InvalidChain(String),
+    BadCoinbase(String),
+    MissingInput(String, String, i32),
+    DoubleSpend(String, String, i32),
+    ImmatureSpend(String, String),
+    BadTransaction(String, String),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::TooLarge(size) => write!(
+                f,
+                "block size {} exceeds the limit of {} bytes",
+                size, MAX_BLOCK_SIZE
+            ),
+            BlockError::BadHash => write!(f, "block hash does not match its content"),
+            BlockError::BadMerkleRoot => {
+                write!(f, "block header does not commit to its transactions")
+            }
+            BlockError::BadProofOfWork => write!(f, "block hash does not meet its target"),
+            BlockError::BadDifficulty(bits, expected) => {
+                write!(
+                    f,
+                    "block has difficulty {} bits, expected {}",
+                    bits, expected
+                )
+            }
+            BlockError::UnknownParent(hash) => write!(f, "previous block {} is unknown", hash),
+            BlockError::MissingParent(hash) => {
+                write!(f, "previous block {} is only known by its header", hash)
+            }
+            BlockError::UnexpectedGenesis => {
+                write!(f, "genesis block received for an existing chain")
+            }
+            BlockError::BadHeight(height, parent) => write!(
+                f,
+                "block height {} does not follow parent height {}",
+                height, parent
+            ),
+            BlockError::BadTimestamp(time) => write!(f, "block timestamp {} is out of range", time),
+            BlockError::FutureTimestamp(time) => {
+                write!(f, "block timestamp {} is too far in the future", time)
+            }
+            BlockError::InvalidChain(hash) => {
+                write!(f, "block {} is or descends from an invalid block", hash)
+            }
+            BlockError::BadCoinbase(reason) => write!(f, "bad coinbase: {}", reason),
+            BlockError::MissingInput(id, txid, vout) => {
+                write!(
+                    f,
+                    "transaction {} spends missing output {}:{}",
+                    id, txid, vout
+                )
+            }
+            BlockError::DoubleSpend(id, txid, vout) => {
+                write!(
+                    f,
+                    "transaction {} double spends output {}:{}",
+                    id, txid, vout
+                )
+            }
+            BlockError::ImmatureSpend(id, txid) => {
+                write!(
+                    f,
+                    "transaction {} spends coinbase {} before it matured",
+                    id, txid
+                )
+            }
+            BlockError::BadTransaction(id, reason) => {
+                write!(f, "transaction {} is invalid: {}", id, reason)
+            }
+        }
+    }
+}
+
+impl Fail for BlockError {}
+
+impl BlockError {
+    /// IsMissingData tells whether the block could not be checked for lack of
+    /// its parent or of the outputs it spends, rather than breaking a rule
+    pub fn is_missing_data(&self) -> bool {
+        matches!(
+            self,
+            BlockError::UnknownParent(_)
+                | BlockError::MissingParent(_)
+                | BlockError::MissingInput(..)
+        )
+    }
+
+    /// BreaksConsensus tells whether the block breaks a rule for good, so that
+    /// it and its descendants are never to be accepted again
+    pub fn breaks_consensus(&self) -> bool {
+        !self.is_missing_data() && !matches!(self, BlockError::FutureTimestamp(_))
+    }
+}
+
+/// ChainUpdate lists the blocks that left and joined the active chain in AddBlock
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    /// disconnected blocks, from the old tip backwards
+    pub disconnected: Vec<Block>,
+    /// connected blocks, from the fork point forwards to the new tip
+    pub connected: Vec<Block>,
+}
+
+/// PrevTXs maps the id of a transaction to it and the height of its block
+type PrevTXs = HashMap<String, (Transaction, i32)>;
+
+/// ChainParams are the consensus rules a chain is created with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ChainParams {
+    /// HalvingInterval is the number of blocks after which the subsidy halves
+    pub halving_interval: i32,
+    /// CoinbaseMaturity is the number of confirmations a coinbase output needs
+    /// before it is spent
+    pub coinbase_maturity: i32,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            halving_interval: HALVING_INTERVAL,
+            coinbase_maturity: COINBASE_MATURITY,
+        }
+    }
+}
+
+impl ChainParams {
+    /// IsMature checks whether the outputs of a coinbase of the block at height
+    /// may be spent by a transaction of the block at spend_height
+    pub fn is_mature(&self, height: i32, spend_height: i32) -> bool {
+        spend_height - height >= self.coinbase_maturity
+    }
+}
 
 /// Blockchain implements interactions with a DB
 #[derive(Debug)]
 pub struct Blockchain {
     pub tip: String,
     pub db: sled::Db,
+    pub data_dir: PathBuf,
+    pub params: ChainParams,
 }
 
 /// BlockchainIterator is used to iterate over blockchain blocks
@@ -24,12 +235,28 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+/// HeightIterator iterates over a range of heights of the active chain
+pub struct HeightIterator<'a> {
+    next: Option<i32>,
+    end: i32,
+    bc: &'a Blockchain,
+}
+
 impl Blockchain {
-    /// NewBlockchain creates a new Blockchain db
-    pub fn new() -> Result<Blockchain> {
-        info!("open blockchain");
+    /// NewBlockchain opens the Blockchain db kept in data_dir
+    pub fn new(data_dir: &str) -> Result<Blockchain> {
+        info!("open blockchain in {}", data_dir);
 
-        let db = sled::open("data/blocks")?;
+        let data_dir = PathBuf::from(data_dir);
+        let db = open_db(&data_dir.join("blocks"))?;
+        if db.is_empty() {
+            db.insert(VERSION_KEY, BLOCKS_VERSION)?;
+        } else if db.get(VERSION_KEY)?.as_deref() != Some(BLOCKS_VERSION) {
+            return Err(format_err!(
+                "ERROR: The blocks db in {} is of another format, recreate it with createblockchain or sync it again in a new data dir",
+                data_dir.display()
+            ));
+        }
         let hash = match db.get("LAST")? {
             Some(l) => l.to_vec(),
             None => Vec::new(),
@@ -40,50 +267,125 @@ impl Blockchain {
         } else {
             String::from_utf8(hash.to_vec())?
         };
-        Ok(Blockchain { tip: lasthash, db })
+        let params = match db.get(PARAMS_KEY)? {
+            Some(params) => deserialize(&params)?,
+            None => ChainParams::default(),
+        };
+        let bc = Blockchain {
+            tip: lasthash,
+            db,
+            data_dir,
+            params,
+        };
+        if !bc.tip.is_empty() && bc.db.open_tree(HEIGHT_INDEX_TREE)?.is_empty() {
+            info!("build height index");
+            bc.reindex_heights()?;
+        }
+        if bc.has_tx_index()? && bc.db.get(TX_INDEX_FLAG)?.as_deref() != Some(TX_INDEX_VERSION) {
+            info!("rebuild the transaction index of an older format");
+            bc.reindex_transactions()?;
+        }
+        Ok(bc)
     }
 
-    /// CreateBlockchain creates a new blockchain DB
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
-        info!("Creating new blockchain");
+    /// CreateBlockchain creates a new blockchain DB in data_dir
+    pub fn create_blockchain(address: String, data_dir: &str) -> Result<Blockchain> {
+        info!("Creating new blockchain in {}", data_dir);
 
-        std::fs::remove_dir_all("data/blocks").ok();
-        let db = sled::open("data/blocks")?;
+        let data_dir = PathBuf::from(data_dir);
+        remove_db(&data_dir.join("blocks"))?;
+        let db = open_db(&data_dir.join("blocks"))?;
+        db.insert(VERSION_KEY, BLOCKS_VERSION)?;
         debug!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let cbtx = Transaction::new_coinbase(
+            address,
+            String::from(GENESIS_COINBASE_DATA),
+            INITIAL_SUBSIDY,
+        )?;
         let genesis: Block = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
+        let mut bc = Blockchain {
+            tip: String::new(),
             db,
+            data_dir,
+            params: ChainParams::default(),
         };
-        bc.db.flush()?;
+        bc.store_block(&genesis)?;
+        bc.update_indexes(&ChainUpdate {
+            disconnected: Vec::new(),
+            connected: vec![genesis.clone()],
+        })?;
+        bc.set_tip(&genesis.get_hash())?;
         Ok(bc)
     }
 
+    /// SetParams changes the consensus rules of a chain holding only its
+    /// genesis block
+    pub fn set_params(&mut self, params: ChainParams) -> Result<()> {
+        if params.halving_interval <= 0 {
+            return Err(format_err!("ERROR: Halving interval must be positive"));
+        }
+        if params.coinbase_maturity <= 0 {
+            return Err(format_err!("ERROR: Coinbase maturity must be positive"));
+        }
+        if self.get_best_height()? > 0 {
+            return Err(format_err!(
+                "ERROR: Chain parameters are only set on a new chain"
+            ));
+        }
+        self.db.insert(PARAMS_KEY, serialize(&params)?)?;
+        self.db.flush()?;
+        self.params = params;
+        Ok(())
+    }
+
+    /// GetBlockSubsidy returns the value the coinbase of the block at height
+    /// may create, besides the fees of the block
+    pub fn get_block_subsidy(&self, height: i32) -> Amount {
+        block_subsidy(height, self.params.halving_interval)
+    }
+
+    /// GetBlockReward returns what the coinbase of the block following the tip
+    /// may pay with the fees of its transactions
+    pub fn get_block_reward(&self, fees: Amount) -> Result<Amount> {
+        let height = self.get_best_height()? + 1;
+        checked_add(self.get_block_subsidy(height), fees)
+    }
+
+    /// NewBlockTemplate returns a block with the transactions on top of the
+    /// tip, whose proof-of-work is still to be done
+    pub fn new_block_template(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let (_, height) = self.header_of(&self.tip)?;
+        Block::new_template(
+            transactions,
+            self.tip.clone(),
+            height + 1,
+            self.get_next_bits(&self.tip)?,
+        )
+    }
+
     /// MineBlock mines a new block with the provided transactions
+    ///
+    /// The block is validated as a whole, so a transaction may spend the
+    /// outputs of one before it in the block
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
-        for tx in &transactions {
-            if !self.verify_transacton(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
-            }
-        }
-
         let lasthash = self.db.get("LAST")?.unwrap();
+        let lastblock = self.get_block(&String::from_utf8(lasthash.to_vec())?)?;
 
         let newblock = Block::new_block(
             transactions,
-            String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
+            lastblock.get_hash(),
+            lastblock.get_height() + 1,
+            self.get_next_bits(&lastblock.get_hash())?,
         )?;
-        self.db.insert(newblock.get_hash(), serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
-        self.db.flush()?;
-
-        self.tip = newblock.get_hash();
+        self.validate_block(&newblock)?;
+        self.store_block(&newblock)?;
+        self.update_indexes(&ChainUpdate {
+            disconnected: Vec::new(),
+            connected: vec![newblock.clone()],
+        })?;
+        self.set_tip(&newblock.get_hash())?;
         Ok(newblock)
     }
 
@@ -95,6 +397,39 @@ impl Blockchain {
         }
     }
 
+    /// IterHeights returns the blocks of the active chain from height from to
+    /// height to, both included, walking backwards when from is above to
+    pub fn iter_heights(&self, from: i32, to: i32) -> HeightIterator<'_> {
+        HeightIterator {
+            next: Some(from),
+            end: to,
+            bc: self,
+        }
+    }
+
+    /// GetBlockByHeight returns the block of the active chain at height
+    pub fn get_block_by_height(&self, height: i32) -> Result<Option<Block>> {
+        match self.get_hash_at_height(height)? {
+            Some(hash) => Ok(Some(self.get_block(&hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// GetHashAtHeight returns the hash of the block at height in the active chain
+    fn get_hash_at_height(&self, height: i32) -> Result<Option<String>> {
+        if height < 0 {
+            return Ok(None);
+        }
+        match self
+            .db
+            .open_tree(HEIGHT_INDEX_TREE)?
+            .get((height as u32).to_be_bytes())?
+        {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
     /// FindUTXO finds and returns all unspent transaction outputs
     pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
         let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
@@ -109,19 +444,15 @@ impl Blockchain {
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs {
+                            outputs: HashMap::new(),
+                            height: block.get_height(),
+                            is_coinbase: tx.is_coinbase(),
+                        })
+                        .outputs
+                        .insert(index as i32, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -143,17 +474,118 @@ impl Blockchain {
     }
 
     /// FindTransaction finds a transaction by its ID
+    ///
+    /// The transaction index is used when it is enabled, otherwise the chain
+    /// is walked from the tip
     pub fn find_transacton(&self, id: &str) -> Result<Transaction> {
+        Ok(self.find_transacton_and_height(id)?.0)
+    }
+
+    /// FindTransactionAndHeight finds a transaction by its ID, together with
+    /// the height of its block
+    pub fn find_transacton_and_height(&self, id: &str) -> Result<(Transaction, i32)> {
+        if self.has_tx_index()? {
+            let entry = match self.db.open_tree(TX_INDEX_TREE)?.get(id)? {
+                Some(entry) => entry,
+                None => return Err(format_err!("Transaction is not found")),
+            };
+            let (block_hash, pos): (String, u32) = deserialize(&entry)?;
+            let block = self.get_block(&block_hash)?;
+            return match block.get_transaction().get(pos as usize) {
+                Some(tx) if tx.id == id => Ok((tx.clone(), block.get_height())),
+                _ => Err(format_err!("Transaction index is corrupted, run reindextx")),
+            };
+        }
+
         for b in self.iter() {
             for tx in b.get_transaction() {
                 if tx.id == id {
-                    return Ok(tx.clone());
+                    return Ok((tx.clone(), b.get_height()));
                 }
             }
         }
         Err(format_err!("Transaction is not found"))
     }
 
+    /// FindPrevTXsAt returns the transactions with the given ids on the chain
+    /// ending at block_hash and the height of their block, together with those
+    /// of their outputs already spent on that chain
+    ///
+    /// When the transaction index is enabled, only the blocks of a side chain
+    /// are walked, and the transactions of the active chain below the fork are
+    /// looked up in the index. Otherwise the whole chain is walked
+    fn find_prev_TXs_at(
+        &self,
+        block_hash: &str,
+        mut txids: HashSet<String>,
+    ) -> Result<(PrevTXs, HashSet<(String, i32)>)> {
+        let mut prev_TXs = HashMap::new();
+        let mut spent = HashSet::new();
+        let use_index = self.has_tx_index()?;
+        let mut fork_height = -1;
+        let iter = BlockchainIterator {
+            current_hash: block_hash.to_string(),
+            bc: self,
+        };
+
+        for block in iter {
+            if txids.is_empty() {
+                break;
+            }
+            if use_index
+                && self.get_hash_at_height(block.get_height())?.as_deref()
+                    == Some(block.get_hash().as_str())
+            {
+                fork_height = block.get_height();
+                break;
+            }
+            for tx in block.get_transaction() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        if txids.contains(&vin.txid) {
+                            spent.insert((vin.txid.clone(), vin.vout));
+                        }
+                    }
+                }
+            }
+            for tx in block.get_transaction() {
+                if txids.remove(&tx.id) {
+                    prev_TXs.insert(tx.id.clone(), (tx.clone(), block.get_height()));
+                }
+            }
+        }
+        if !use_index || txids.is_empty() {
+            return Ok((prev_TXs, spent));
+        }
+
+        let index = self.db.open_tree(TX_INDEX_TREE)?;
+        let spent_index = self.db.open_tree(SPENT_INDEX_TREE)?;
+        for txid in txids {
+            let entry = match index.get(&txid)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let (block_hash, pos): (String, u32) = deserialize(&entry)?;
+            let block = self.get_block(&block_hash)?;
+            if block.get_height() > fork_height {
+                continue;
+            }
+            let tx = match block.get_transaction().get(pos as usize) {
+                Some(tx) if tx.id == txid => tx.clone(),
+                _ => return Err(format_err!("Transaction index is corrupted, run reindextx")),
+            };
+            for vout in 0..tx.vout.len() as i32 {
+                if let Some(height) = spent_index.get(spent_key(&txid, vout))? {
+                    if u32::from_be_bytes(height.as_ref().try_into()?) as i32 <= fork_height {
+                        spent.insert((txid.clone(), vout));
+                    }
+                }
+            }
+            prev_TXs.insert(txid, (tx, block.get_height()));
+        }
+        Ok((prev_TXs, spent))
+    }
+
     fn get_prev_TXs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_TXs = HashMap::new();
         for vin in &tx.vin {
@@ -170,37 +602,644 @@ impl Blockchain {
         Ok(())
     }
 
-    /// VerifyTransaction verifies transaction input signatures
+    /// SignMultisigTransaction adds the signature of the key to the inputs
+    /// spending outputs of the multisig redeem_script
+    pub fn sign_multisig_transacton(
+        &self,
+        tx: &mut Transaction,
+        private_key: &[u8],
+        redeem_script: &Script,
+    ) -> Result<usize> {
+        let prev_TXs = self.get_prev_TXs(tx)?;
+        tx.sign_multisig(private_key, redeem_script, prev_TXs)
+    }
+
+    /// GetFee returns the fee paid by a transaction spending outputs of the chain
+    pub fn get_fee(&self, tx: &Transaction) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        let prev_TXs = self.get_prev_TXs(tx)?;
+        tx.fee(&prev_TXs)
+    }
+
+    /// VerifyTransaction verifies that the transaction may be included in the
+    /// next block, spending no immature coinbase, and runs the scripts of its inputs
     pub fn verify_transacton(&self, tx: &Transaction) -> Result<bool> {
         if tx.is_coinbase() {
             return Ok(true);
         }
-        let prev_TXs = self.get_prev_TXs(tx)?;
+        let height = self.get_best_height()? + 1;
+        if !tx.is_final(height) {
+            return Ok(false);
+        }
+        let mut prev_TXs = HashMap::new();
+        for vin in &tx.vin {
+            let (prev_TX, prev_height) = self.find_transacton_and_height(&vin.txid)?;
+            if prev_TX.is_coinbase() && !self.params.is_mature(prev_height, height) {
+                debug!("{} spends coinbase {} before it matured", tx.id, prev_TX.id);
+                return Ok(false);
+            }
+            prev_TXs.insert(prev_TX.id.clone(), prev_TX);
+        }
         tx.verify(prev_TXs)
     }
 
-    /// AddBlock saves the block into the blockchain
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
+    /// GetNextBits returns the difficulty required of the block following prev
+    ///
+    /// Every RETARGET_INTERVAL blocks the difficulty is adjusted so that blocks
+    /// are found about every TARGET_BLOCK_SPACING milliseconds
+    pub fn get_next_bits(&self, prev_hash: &str) -> Result<u32> {
+        let (prev, prev_height) = self.header_of(prev_hash)?;
+        let height = prev_height + 1;
+        if height % RETARGET_INTERVAL != 0 {
+            return Ok(prev.bits);
+        }
+
+        let mut first = prev.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            first = self.header_of(&first.prev_block_hash)?.0;
+        }
+        let actual_timespan = prev.timestamp.saturating_sub(first.timestamp);
+        let expected_timespan = TARGET_BLOCK_SPACING * (RETARGET_INTERVAL - 1) as u128;
+        let bits = calculate_next_bits(prev.bits, actual_timespan, expected_timespan);
+        info!(
+            "retarget at height {}: timespan {}ms, expected {}ms, bits {} -> {}",
+            height, actual_timespan, expected_timespan, prev.bits, bits
+        );
+        Ok(bits)
+    }
+
+    /// ValidateBlock checks a block against the consensus rules before it is stored
+    ///
+    /// The transactions are checked against the outputs of the chain ending at
+    /// the block's parent, so the parent must already be known
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        let size = serialize(block)?.len();
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockError::TooLarge(size).into());
+        }
+        if block.calculate_hash()? != block.get_hash() {
+            return Err(BlockError::BadHash.into());
+        }
+        if !block.check_merkle_root()? {
+            return Err(BlockError::BadMerkleRoot.into());
+        }
+        if self.is_invalid(&block.get_hash())? {
+            return Err(BlockError::InvalidChain(block.get_hash()).into());
+        }
+        self.validate_header(block.get_header(), block.get_height())?;
+        // the inputs are looked up in the blocks before it
+        let prev_hash = block.get_prev_hash();
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash)? {
+            return Err(BlockError::MissingParent(prev_hash).into());
+        }
+        self.validate_transactions(block)
+    }
+
+    /// ValidateHeader checks the header of a block at height against the
+    /// consensus rules, which needs only the headers of the chain before it
+    pub fn validate_header(&self, header: &BlockHeader, height: i32) -> Result<()> {
+        if !header.check_proof_of_work()? {
+            return Err(BlockError::BadProofOfWork.into());
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::FutureTimestamp(header.timestamp).into());
+        }
+        if self.is_invalid(&header.prev_block_hash)? {
+            return Err(BlockError::InvalidChain(header.prev_block_hash.clone()).into());
+        }
+
+        let expected_bits = if header.prev_block_hash.is_empty() {
+            if !self.tip.is_empty() {
+                return Err(BlockError::UnexpectedGenesis.into());
+            }
+            if height != 0 {
+                return Err(BlockError::BadHeight(height, -1).into());
+            }
+            INITIAL_BITS
+        } else {
+            let (parent, parent_height) = match self.get_header(&header.prev_block_hash)? {
+                Some(parent) => parent,
+                None => {
+                    return Err(BlockError::UnknownParent(header.prev_block_hash.clone()).into())
+                }
+            };
+            if height != parent_height + 1 {
+                return Err(BlockError::BadHeight(height, parent_height).into());
+            }
+            if header.timestamp < parent.timestamp {
+                return Err(BlockError::BadTimestamp(header.timestamp).into());
+            }
+            self.get_next_bits(&header.prev_block_hash)?
+        };
+        if header.bits != expected_bits {
+            return Err(BlockError::BadDifficulty(header.bits, expected_bits).into());
+        }
+        Ok(())
+    }
+
+    /// ValidateTransactions checks the coinbase and every input of the block's transactions
+    fn validate_transactions(&self, block: &Block) -> Result<()> {
+        let txs = block.get_transaction();
+        if txs.is_empty() || !txs[0].is_coinbase() {
+            return Err(
+                BlockError::BadCoinbase("first transaction is not a coinbase".into()).into(),
+            );
+        }
+        if txs.iter().skip(1).any(|tx| tx.is_coinbase()) {
+            return Err(BlockError::BadCoinbase("more than one coinbase".into()).into());
+        }
+        let coinbase_value = match txs[0].output_value() {
+            Ok(value) => value,
+            Err(e) => return Err(BlockError::BadCoinbase(e.to_string()).into()),
+        };
+
+        let block_txids: HashSet<String> = txs.iter().map(|tx| tx.id.clone()).collect();
+        let mut needed = HashSet::new();
+        for tx in txs.iter().skip(1) {
+            for vin in &tx.vin {
+                if !block_txids.contains(&vin.txid) {
+                    needed.insert(vin.txid.clone());
+                }
+            }
+        }
+        let (mut prev_TXs, spent) = self.find_prev_TXs_at(&block.get_prev_hash(), needed)?;
+
+        let mut spent_in_block = HashSet::new();
+        let mut fees: Amount = 0;
+        for tx in txs {
+            if !tx.has_valid_id()? {
+                return Err(
+                    BlockError::BadTransaction(tx.id.clone(), "id does not match".into()).into(),
+                );
+            }
+            if !tx.is_final(block.get_height()) {
+                return Err(BlockError::BadTransaction(
+                    tx.id.clone(),
+                    format!("locked until height {}", tx.lock_time),
+                )
+                .into());
+            }
+            if !tx.is_coinbase() {
+                let mut tx_prev_TXs = HashMap::new();
+                for vin in &tx.vin {
+                    let outpoint = (vin.txid.clone(), vin.vout);
+                    if !spent_in_block.insert(outpoint.clone()) {
+                        return Err(BlockError::DoubleSpend(
+                            tx.id.clone(),
+                            vin.txid.clone(),
+                            vin.vout,
+                        )
+                        .into());
+                    }
+                    let (prev_TX, prev_height) = match prev_TXs.get(&vin.txid) {
+                        Some((prev_TX, prev_height))
+                            if vin.vout >= 0
+                                && (vin.vout as usize) < prev_TX.vout.len()
+                                && !spent.contains(&outpoint) =>
+                        {
+                            (prev_TX, *prev_height)
+                        }
+                        _ => {
+                            return Err(BlockError::MissingInput(
+                                tx.id.clone(),
+                                vin.txid.clone(),
+                                vin.vout,
+                            )
+                            .into())
+                        }
+                    };
+                    if prev_TX.is_coinbase()
+                        && !self.params.is_mature(prev_height, block.get_height())
+                    {
+                        return Err(
+                            BlockError::ImmatureSpend(tx.id.clone(), prev_TX.id.clone()).into()
+                        );
+                    }
+                    tx_prev_TXs.insert(vin.txid.clone(), prev_TX.clone());
+                }
+                match tx.fee(&tx_prev_TXs) {
+                    Ok(fee) => fees = checked_add(fees, fee)?,
+                    Err(e) => {
+                        return Err(BlockError::BadTransaction(tx.id.clone(), e.to_string()).into())
+                    }
+                }
+                if !tx.verify(tx_prev_TXs)? {
+                    return Err(BlockError::BadTransaction(
+                        tx.id.clone(),
+                        "script verification failed".into(),
+                    )
+                    .into());
+                }
+            }
+            prev_TXs.insert(tx.id.clone(), (tx.clone(), block.get_height()));
+        }
+
+        let reward = checked_add(self.get_block_subsidy(block.get_height()), fees)?;
+        if coinbase_value > reward {
+            return Err(BlockError::BadCoinbase(format!(
+                "pays {} for a subsidy and fees of {}",
+                coinbase_value, reward
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// AddBlock validates the block and saves it into the blockchain
+    ///
+    /// A block extending a side chain is kept, and once that chain has more
+    /// cumulative work than the active one the chain is reorganized onto it
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate> {
+        if self.db.get(block.get_hash())?.is_some() {
+            return Ok(ChainUpdate::default());
+        }
+        self.validate_block(&block)?;
+        let work = self.store_block(&block)?;
+
+        if !self.tip.is_empty() && work <= self.get_chain_work(&self.tip)? {
+            info!("store side chain block {}", block.get_hash());
+            return Ok(ChainUpdate::default());
+        }
+
+        let update = self.find_chain_update(&self.tip, block)?;
+        self.update_indexes(&update)?;
+        if !update.disconnected.is_empty() {
+            info!(
+                "reorganize chain: disconnect {} blocks, connect {} blocks",
+                update.disconnected.len(),
+                update.connected.len()
+            );
+        }
+        let new_tip = update.connected.last().unwrap().get_hash();
+        self.set_tip(&new_tip)?;
+        Ok(update)
+    }
+
+    /// ChainUpdateSince returns how the active chain changed since its tip was
+    /// old_tip, from the genesis block when old_tip is not a stored block
+    pub fn chain_update_since(&self, old_tip: &str) -> Result<ChainUpdate> {
+        if self.tip.is_empty() {
+            return Ok(ChainUpdate::default());
+        }
+        let old_tip = match self.db.get(old_tip)? {
+            Some(_) => old_tip,
+            None => "",
+        };
+        self.find_chain_update(old_tip, self.get_block(&self.tip)?)
+    }
+
+    /// FindChainUpdate walks back from old_tip and from new_tip to their fork point
+    fn find_chain_update(&self, old_tip: &str, new_tip: Block) -> Result<ChainUpdate> {
+        let mut update = ChainUpdate::default();
+        let mut new = new_tip;
+        let mut old = if old_tip.is_empty() {
+            None
+        } else {
+            Some(self.get_block(old_tip)?)
+        };
+
+        loop {
+            match &old {
+                Some(o) if o.get_hash() == new.get_hash() => break,
+                Some(o) if o.get_height() > new.get_height() => {
+                    let parent = self.get_parent(o)?;
+                    update.disconnected.push(old.take().unwrap());
+                    old = parent;
+                }
+                _ => {
+                    let parent = self.get_parent(&new)?;
+                    update.connected.push(new);
+                    new = match parent {
+                        Some(p) => p,
+                        None => break,
+                    };
+                }
+            }
+        }
+
+        update.connected.reverse();
+        Ok(update)
+    }
+
+    /// HasTxIndex tells whether the transaction index is maintained
+    pub fn has_tx_index(&self) -> Result<bool> {
+        Ok(self.db.get(TX_INDEX_FLAG)?.is_some())
+    }
+
+    /// ReindexTransactions rebuilds the transaction index from the active chain
+    /// and keeps it up to date from then on
+    pub fn reindex_transactions(&self) -> Result<usize> {
+        let index = self.db.open_tree(TX_INDEX_TREE)?;
+        let spent_index = self.db.open_tree(SPENT_INDEX_TREE)?;
+        index.clear()?;
+        spent_index.clear()?;
+        let mut count = 0;
+        for block in self.iter() {
+            let height = (block.get_height() as u32).to_be_bytes();
+            for (pos, tx) in block.get_transaction().iter().enumerate() {
+                index.insert(
+                    tx.id.as_bytes(),
+                    serialize(&(block.get_hash(), pos as u32))?,
+                )?;
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent_index.insert(spent_key(&vin.txid, vin.vout), &height)?;
+                    }
+                }
+                count += 1;
+            }
+        }
+        self.db.insert(TX_INDEX_FLAG, TX_INDEX_VERSION)?;
+        self.db.flush()?;
+        Ok(count)
+    }
+
+    /// ReindexHeights rebuilds the height index from the active chain
+    fn reindex_heights(&self) -> Result<()> {
+        let index = self.db.open_tree(HEIGHT_INDEX_TREE)?;
+        index.clear()?;
+        for block in self.iter() {
+            index.insert(
+                (block.get_height() as u32).to_be_bytes(),
+                block.get_hash().as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// UpdateIndexes updates the indexes of the active chain when it changes
+    fn update_indexes(&self, update: &ChainUpdate) -> Result<()> {
+        self.update_height_index(update)?;
+        self.update_tx_index(update)
+    }
+
+    fn update_height_index(&self, update: &ChainUpdate) -> Result<()> {
+        let index = self.db.open_tree(HEIGHT_INDEX_TREE)?;
+        for block in &update.disconnected {
+            index.remove((block.get_height() as u32).to_be_bytes())?;
+        }
+        for block in &update.connected {
+            index.insert(
+                (block.get_height() as u32).to_be_bytes(),
+                block.get_hash().as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// UpdateTxIndex removes the transactions of disconnected blocks from the
+    /// transaction index and adds those of connected blocks
+    fn update_tx_index(&self, update: &ChainUpdate) -> Result<()> {
+        if !self.has_tx_index()? {
             return Ok(());
         }
-        self.db.insert(block.get_hash(), data)?;
+        let index = self.db.open_tree(TX_INDEX_TREE)?;
+        let spent_index = self.db.open_tree(SPENT_INDEX_TREE)?;
+        for block in &update.disconnected {
+            for tx in block.get_transaction() {
+                index.remove(tx.id.as_bytes())?;
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent_index.remove(spent_key(&vin.txid, vin.vout))?;
+                    }
+                }
+            }
+        }
+        for block in &update.connected {
+            let height = (block.get_height() as u32).to_be_bytes();
+            for (pos, tx) in block.get_transaction().iter().enumerate() {
+                index.insert(
+                    tx.id.as_bytes(),
+                    serialize(&(block.get_hash(), pos as u32))?,
+                )?;
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent_index.insert(spent_key(&vin.txid, vin.vout), &height)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.tip = block.get_hash();
-            self.db.flush()?;
+    fn get_parent(&self, block: &Block) -> Result<Option<Block>> {
+        if block.get_prev_hash().is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.get_block(&block.get_prev_hash())?))
+        }
+    }
+
+    /// StoreBlock saves a block with the cumulative work of the chain it ends
+    fn store_block(&self, block: &Block) -> Result<u128> {
+        let work = self.store_header(&block.get_hash(), block.get_header(), block.get_height())?;
+        self.db.insert(block.get_hash(), serialize(block)?)?;
+        Ok(work)
+    }
+
+    /// StoreHeader saves a header at height with the cumulative work of the chain it ends
+    fn store_header(&self, block_hash: &str, header: &BlockHeader, height: i32) -> Result<u128> {
+        let parent_work = if header.prev_block_hash.is_empty() {
+            0
+        } else {
+            self.get_chain_work(&header.prev_block_hash)?
+        };
+        let work = parent_work + block_work(header.bits);
+        self.db
+            .open_tree(HEADERS_TREE)?
+            .insert(block_hash, serialize(&(header, height))?)?;
+        self.db
+            .open_tree(WORK_TREE)?
+            .insert(block_hash, serialize(&work)?)?;
+        Ok(work)
+    }
+
+    /// AddHeader validates a header received ahead of its block and stores it
+    ///
+    /// It returns the hash and height of the header, and the cumulative work of
+    /// the header chain it ends, so that the chain with the most work is
+    /// downloaded before any of its blocks is seen
+    ///
+    /// A header that is or descends from an invalid block is refused, and
+    /// remembered as invalid if it was not yet
+    pub fn add_header(&self, header: &BlockHeader) -> Result<(String, i32, u128)> {
+        let block_hash = header.hash()?;
+        if self.is_invalid(&block_hash)? || self.is_invalid(&header.prev_block_hash)? {
+            self.mark_invalid(&block_hash)?;
+            return Err(BlockError::InvalidChain(block_hash).into());
+        }
+        if let Some((_, height)) = self.get_header(&block_hash)? {
+            return Ok((
+                block_hash.clone(),
+                height,
+                self.get_chain_work(&block_hash)?,
+            ));
+        }
+        let height = if header.prev_block_hash.is_empty() {
+            0
+        } else {
+            match self.get_header(&header.prev_block_hash)? {
+                Some((_, parent_height)) => parent_height + 1,
+                None => {
+                    return Err(BlockError::UnknownParent(header.prev_block_hash.clone()).into())
+                }
+            }
+        };
+        self.validate_header(header, height)?;
+        let work = self.store_header(&block_hash, header, height)?;
+        Ok((block_hash, height, work))
+    }
+
+    /// MarkInvalid remembers that the block block_hash breaks the consensus
+    /// rules, so that neither it nor its descendants are accepted again
+    pub fn mark_invalid(&self, block_hash: &str) -> Result<()> {
+        self.db.open_tree(INVALID_TREE)?.insert(block_hash, &[])?;
+        Ok(())
+    }
+
+    /// IsInvalid tells whether the block or header block_hash is invalid or
+    /// descends from an invalid block
+    ///
+    /// The stored headers are walked back until the active chain, which holds
+    /// no invalid block, and the ones found to descend from an invalid block
+    /// are marked invalid themselves
+    pub fn is_invalid(&self, block_hash: &str) -> Result<bool> {
+        let invalid = self.db.open_tree(INVALID_TREE)?;
+        if invalid.is_empty() {
+            return Ok(false);
         }
+        let mut descendants = Vec::new();
+        let mut hash = block_hash.to_string();
+        while !hash.is_empty() {
+            if invalid.contains_key(&hash)? {
+                for descendant in descendants {
+                    invalid.insert(descendant, &[])?;
+                }
+                return Ok(true);
+            }
+            let (header, height) = match self.get_header(&hash)? {
+                Some(header) => header,
+                None => return Ok(false),
+            };
+            if self.get_hash_at_height(height)?.as_ref() == Some(&hash) {
+                return Ok(false);
+            }
+            descendants.push(hash);
+            hash = header.prev_block_hash;
+        }
+        Ok(false)
+    }
+
+    /// GetBlockLocator returns hashes of the header chain ending at block_hash,
+    /// the latest ten of them and then every other, fourth, eighth... back to
+    /// the genesis block, for a peer to find where its chain forks from it
+    ///
+    /// Only the headers ahead of the active chain, ten at most, are walked.
+    /// The rest are read from the height index of the active chain, from where
+    /// the header chain joins it, or from its tip when that is further back
+    pub fn get_block_locator(&self, block_hash: &str) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        let mut hash = block_hash.to_string();
+        let mut height = None;
+        while !hash.is_empty() && locator.len() < 10 {
+            let (header, h) = self.header_of(&hash)?;
+            if self.get_hash_at_height(h)?.as_ref() == Some(&hash) {
+                height = Some(h);
+                break;
+            }
+            locator.push(hash);
+            hash = header.prev_block_hash;
+        }
+        if hash.is_empty() || self.tip.is_empty() {
+            return Ok(locator);
+        }
+
+        let mut height = match height {
+            Some(height) => height,
+            None => self.get_best_height()?,
+        };
+        let mut step = 1;
+        loop {
+            if let Some(hash) = self.get_hash_at_height(height)? {
+                locator.push(hash);
+            }
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = (height - step).max(0);
+        }
+        Ok(locator)
+    }
+
+    /// GetHeadersAfter returns up to max headers of the active chain following
+    /// the first hash of locator on it, from the genesis block when there is none
+    pub fn get_headers_after(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut height = 0;
+        for hash in locator {
+            if let Some((_, h)) = self.get_header(hash)? {
+                if self.get_hash_at_height(h)?.as_ref() == Some(hash) {
+                    height = h + 1;
+                    break;
+                }
+            }
+        }
+
+        let mut headers = Vec::new();
+        while headers.len() < max {
+            match self.get_hash_at_height(height)? {
+                Some(hash) => headers.push(self.header_of(&hash)?.0),
+                None => break,
+            }
+            height += 1;
+        }
+        Ok(headers)
+    }
+
+    fn set_tip(&mut self, block_hash: &str) -> Result<()> {
+        self.db.insert("LAST", block_hash.as_bytes())?;
+        self.db.flush()?;
+        self.tip = block_hash.to_string();
         Ok(())
     }
 
+    /// GetChainWork returns the cumulative work of the chain ending at block_hash
+    pub fn get_chain_work(&self, block_hash: &str) -> Result<u128> {
+        match self.db.open_tree(WORK_TREE)?.get(block_hash)? {
+            Some(work) => Ok(deserialize(&work)?),
+            None => Err(format_err!("ERROR: Block {} is not stored", block_hash)),
+        }
+    }
+
+    /// GetHeader finds the header of a stored block by its hash, and returns
+    /// it with the height of the block
+    pub fn get_header(&self, block_hash: &str) -> Result<Option<(BlockHeader, i32)>> {
+        match self.db.open_tree(HEADERS_TREE)?.get(block_hash)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn header_of(&self, block_hash: &str) -> Result<(BlockHeader, i32)> {
+        match self.get_header(block_hash)? {
+            Some(header) => Ok(header),
+            None => Err(format_err!("ERROR: Block {} is not found", block_hash)),
+        }
+    }
+
     // GetBlock finds a block by its hash and returns it
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
-        let data = self.db.get(block_hash)?.unwrap();
-        let block = deserialize(&data.to_vec())?;
-        Ok(block)
+        match self.db.get(block_hash)? {
+            Some(data) => Ok(deserialize(&data)?),
+            None => Err(format_err!("ERROR: Block {} is not stored", block_hash)),
+        }
     }
 
     /// GetBestHeight returns the height of the latest block
@@ -210,19 +1249,83 @@ impl Blockchain {
         } else {
             return Ok(-1);
         };
-        let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = deserialize(&last_data.to_vec())?;
-        Ok(last_block.get_height())
+        Ok(self.header_of(&String::from_utf8(lasthash.to_vec())?)?.1)
     }
 
-    /// GetBlockHashes returns a list of hashes of all the blocks in the chain
-    pub fn get_block_hashs(&self) -> Vec<String> {
-        let mut list = Vec::new();
-        for b in self.iter() {
-            list.push(b.get_hash());
+    /// HasBlock tells whether the block itself, not only its header, is stored
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.get(block_hash)?.is_some())
+    }
+}
+
+/// SpentKey is the key of the output vout of the transaction txid in the spent index
+fn spent_key(txid: &str, vout: i32) -> String {
+    format!("{}:{}", txid, vout)
+}
+
+/// BlockWork is the expected number of hashes needed to find a block with the given bits
+fn block_work(bits: u32) -> u128 {
+    1 << bits
+}
+
+/// CalculateNextBits adjusts the difficulty by the ratio of the expected to the
+/// actual timespan of the last interval
+///
+/// One bit doubles the work needed for a block, so the change is the rounded
+/// base-2 logarithm of that ratio, limited to MAX_RETARGET_STEP bits. It is
+/// computed on integers, comparing the squared timespans to powers of two,
+/// so that every node finds the same bits
+fn calculate_next_bits(bits: u32, actual_timespan: u128, expected_timespan: u128) -> u32 {
+    // beyond a ratio of 8 the step is limited anyway, and the squares fit
+    let expected = expected_timespan.max(1);
+    let actual = actual_timespan.clamp((expected / 8).max(1), expected * 8);
+    let (expected2, actual2) = (expected * expected, actual * actual);
+
+    // the rounded logarithm is step when 2^(2 step - 1) <= ratio^2 < 2^(2 step + 1)
+    let mut step: i64 = 0;
+    if expected2 >= actual2 {
+        while step < MAX_RETARGET_STEP && expected2 >= actual2 << (2 * step + 1) {
+            step += 1;
+        }
+    } else {
+        while step > -MAX_RETARGET_STEP && expected2 << (1 - 2 * step) < actual2 {
+            step -= 1;
+        }
+    }
+    (bits as i64 + step).clamp(MIN_BITS as i64, MAX_BITS as i64) as u32
+}
+
+/// BlockSubsidy halves INITIAL_SUBSIDY every halving_interval blocks, and
+/// stops once the blocks below height created MAX_SUPPLY
+fn block_subsidy(height: i32, halving_interval: i32) -> Amount {
+    let created = supply_before(height, halving_interval);
+    scheduled_subsidy(height, halving_interval).min(MAX_SUPPLY - created)
+}
+
+/// ScheduledSubsidy is the subsidy at height, regardless of MAX_SUPPLY
+fn scheduled_subsidy(height: i32, halving_interval: i32) -> Amount {
+    let halvings = height / halving_interval;
+    if halvings >= Amount::BITS as i32 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+/// SupplyBefore is the value created by the subsidy of the blocks below height
+fn supply_before(height: i32, halving_interval: i32) -> Amount {
+    let mut supply: Amount = 0;
+    let mut start = 0;
+    while start < height && supply < MAX_SUPPLY {
+        let subsidy = scheduled_subsidy(start, halving_interval);
+        if subsidy == 0 {
+            break;
         }
-        list
+        let end = start.saturating_add(halving_interval).min(height);
+        supply = supply.saturating_add(subsidy.saturating_mul((end - start) as Amount));
+        start = end;
     }
+    supply.min(MAX_SUPPLY)
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
@@ -245,3 +1348,424 @@ impl<'a> Iterator for BlockchainIterator<'a> {
         None
     }
 }
+
+impl<'a> Iterator for HeightIterator<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let height = self.next?;
+        self.next = match height.cmp(&self.end) {
+            Ordering::Less => Some(height + 1),
+            Ordering::Greater => Some(height - 1),
+            Ordering::Equal => None,
+        };
+        match self.bc.get_block_by_height(height) {
+            Ok(Some(block)) => Some(block),
+            _ => {
+                self.next = None;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wallets::*;
+
+    #[test]
+    fn test_validate_block() {
+        let data_dir = "data/test_validate_block";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        bc.validate_block(&genesis).unwrap_err();
+
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let block = Block::new_block(vec![cbtx], bc.tip.clone(), 1, MIN_BITS).unwrap();
+        let err = bc.validate_block(&block).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::BadDifficulty(bits, expected)) => {
+                assert_eq!(*bits, MIN_BITS);
+                assert_eq!(*expected, INITIAL_BITS);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        let data = "x".repeat(MAX_BLOCK_SIZE);
+        let cbtx = Transaction::new_coinbase(address, data, INITIAL_SUBSIDY).unwrap();
+        let block = Block::new_block(vec![cbtx], bc.tip.clone(), 1, INITIAL_BITS).unwrap();
+        let err = bc.validate_block(&block).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::TooLarge(size)) => assert!(*size > MAX_BLOCK_SIZE),
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0, HALVING_INTERVAL), INITIAL_SUBSIDY);
+        assert_eq!(
+            block_subsidy(HALVING_INTERVAL - 1, HALVING_INTERVAL),
+            INITIAL_SUBSIDY
+        );
+        assert_eq!(
+            block_subsidy(HALVING_INTERVAL, HALVING_INTERVAL),
+            INITIAL_SUBSIDY / 2
+        );
+        assert_eq!(block_subsidy(5, 2), INITIAL_SUBSIDY / 4);
+        assert_eq!(block_subsidy(64 * 2, 2), 0);
+        assert_eq!(supply_before(4, 2), INITIAL_SUBSIDY * 3);
+        assert!(supply_before(i32::MAX, HALVING_INTERVAL) <= MAX_SUPPLY);
+
+        // without halvings the subsidy stops at MAX_SUPPLY
+        let last = (MAX_SUPPLY / INITIAL_SUBSIDY) as i32;
+        assert_eq!(block_subsidy(last - 1, i32::MAX), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(last, i32::MAX), 0);
+        assert_eq!(supply_before(i32::MAX, i32::MAX), MAX_SUPPLY);
+    }
+
+    #[test]
+    fn test_coinbase_value() {
+        let data_dir = "data/test_coinbase_value";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let params = ChainParams {
+            halving_interval: 2,
+            ..Default::default()
+        };
+        bc.set_params(params).unwrap();
+        drop(bc);
+        let mut bc = Blockchain::new(data_dir).unwrap();
+        assert_eq!(bc.params, params);
+
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY + 1).unwrap();
+        let err = bc.mine_block(vec![cbtx]).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::BadCoinbase(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+        bc.set_params(ChainParams::default()).unwrap_err();
+
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap_err();
+        let reward = bc.get_block_reward(0).unwrap();
+        assert_eq!(reward, INITIAL_SUBSIDY / 2);
+        let cbtx = Transaction::new_coinbase(address, String::new(), reward).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+    }
+
+    #[test]
+    fn test_calculate_next_bits() {
+        assert_eq!(calculate_next_bits(16, 90_000, 90_000), 16);
+        assert_eq!(calculate_next_bits(16, 45_000, 90_000), 17);
+        assert_eq!(calculate_next_bits(16, 180_000, 90_000), 15);
+        assert_eq!(calculate_next_bits(16, 1, 90_000), 18);
+        assert_eq!(calculate_next_bits(16, 0, 90_000), 18);
+        assert_eq!(calculate_next_bits(16, 9_000_000, 90_000), 14);
+        assert_eq!(calculate_next_bits(16, u128::MAX, 90_000), 14);
+        assert_eq!(calculate_next_bits(16, 22_500, 90_000), 18);
+        // a ratio of sqrt(2) rounds to the nearest bit
+        assert_eq!(calculate_next_bits(16, 63_639, 90_000), 17);
+        assert_eq!(calculate_next_bits(16, 63_640, 90_000), 16);
+        assert_eq!(calculate_next_bits(16, 127_279, 90_000), 16);
+        assert_eq!(calculate_next_bits(16, 127_280, 90_000), 15);
+        // the bits stay between MIN_BITS and MAX_BITS
+        assert_eq!(calculate_next_bits(MIN_BITS, 9_000_000, 90_000), MIN_BITS);
+        assert_eq!(
+            calculate_next_bits(MIN_BITS + 1, 9_000_000, 90_000),
+            MIN_BITS
+        );
+        assert_eq!(calculate_next_bits(MIN_BITS, 45_000, 90_000), MIN_BITS + 1);
+        assert_eq!(calculate_next_bits(MAX_BITS, 1, 90_000), MAX_BITS);
+        assert_eq!(calculate_next_bits(MAX_BITS - 1, 1, 90_000), MAX_BITS);
+        assert_eq!(calculate_next_bits(MAX_BITS, 180_000, 90_000), MAX_BITS - 1);
+    }
+
+    #[test]
+    fn test_tx_index() {
+        let data_dir = "data/test_tx_index";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let genesis = bc.tip.clone();
+        assert!(!bc.has_tx_index().unwrap());
+        assert_eq!(bc.reindex_transactions().unwrap(), 1);
+        assert!(bc.has_tx_index().unwrap());
+
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let a1 = bc.mine_block(vec![cbtx.clone()]).unwrap();
+        assert_eq!(bc.find_transacton(&cbtx.id).unwrap().id, cbtx.id);
+
+        // a longer side chain disconnects a1 and its transactions
+        let b1_cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let b1 = Block::new_block(vec![b1_cbtx.clone()], genesis, 1, INITIAL_BITS).unwrap();
+        bc.add_block(b1.clone()).unwrap();
+        bc.find_transacton(&b1_cbtx.id).unwrap_err();
+        let cbtx2 = Transaction::new_coinbase(address, String::new(), INITIAL_SUBSIDY).unwrap();
+        let b2 = Block::new_block(vec![cbtx2], b1.get_hash(), 2, INITIAL_BITS).unwrap();
+        bc.add_block(b2).unwrap();
+        assert_ne!(bc.tip, a1.get_hash());
+        bc.find_transacton(&cbtx.id).unwrap_err();
+        assert_eq!(bc.find_transacton(&b1_cbtx.id).unwrap().id, b1_cbtx.id);
+        assert_eq!(bc.reindex_transactions().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_validate_with_tx_index() {
+        let data_dir = "data/test_validate_with_tx_index";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let wallet = ws.get_wallet(&address).unwrap().clone();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        bc.reindex_transactions().unwrap();
+        let coinbase = |bc: &Blockchain| {
+            Transaction::new_coinbase(
+                address.clone(),
+                String::new(),
+                bc.get_block_reward(0).unwrap(),
+            )
+            .unwrap()
+        };
+        let a1 = bc.mine_block(vec![coinbase(&bc)]).unwrap();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis.get_transaction()[0].id.clone(),
+                vout: 0,
+                script_sig: Script::default(),
+            }],
+            vout: vec![TXOutput::new(COIN, address.clone()).unwrap()],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        bc.sign_transacton(&mut tx, &wallet.secret_key).unwrap();
+        let a2 = bc.mine_block(vec![coinbase(&bc), tx.clone()]).unwrap();
+
+        // the output spent in a2 is found in the spent index
+        let a3 = Block::new_block(
+            vec![coinbase(&bc), tx.clone()],
+            a2.get_hash(),
+            3,
+            INITIAL_BITS,
+        )
+        .unwrap();
+        let err = bc.validate_block(&a3).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::MissingInput(..)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // a side chain forking below a2 may spend it, but only once
+        let b2 = Block::new_block(
+            vec![coinbase(&bc), tx.clone()],
+            a1.get_hash(),
+            2,
+            INITIAL_BITS,
+        )
+        .unwrap();
+        bc.add_block(b2.clone()).unwrap();
+        assert_eq!(bc.tip, a2.get_hash());
+        let b3 = Block::new_block(vec![coinbase(&bc), tx], b2.get_hash(), 3, INITIAL_BITS).unwrap();
+        let err = bc.validate_block(&b3).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::MissingInput(..)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_recreate_blockchain() {
+        let data_dir = "data/test_recreate_blockchain";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+        assert_eq!(
+            Blockchain::new(data_dir)
+                .unwrap()
+                .get_best_height()
+                .unwrap(),
+            1
+        );
+
+        // a db of another format is refused
+        bc.db.insert(VERSION_KEY, &b"0"[..]).unwrap();
+        Blockchain::new(data_dir).unwrap_err();
+
+        // the blocks db shared with bc is replaced, not reused
+        let bc = Blockchain::create_blockchain(address, data_dir).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 0);
+        assert_eq!(
+            Blockchain::new(data_dir)
+                .unwrap()
+                .get_best_height()
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let data_dir = "data/test_block_locator";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let mut hashes = vec![bc.tip.clone()];
+        for _ in 0..14 {
+            let cbtx =
+                Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            hashes.push(bc.mine_block(vec![cbtx]).unwrap().get_hash());
+        }
+        let heights = |locator: Vec<String>| -> Vec<usize> {
+            locator
+                .iter()
+                .map(|hash| hashes.iter().position(|h| h == hash).unwrap())
+                .collect()
+        };
+        let expected = vec![14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 3, 0];
+        assert_eq!(heights(bc.get_block_locator(&bc.tip).unwrap()), expected);
+        assert_eq!(
+            heights(bc.get_block_locator(&hashes[4]).unwrap()),
+            vec![4, 3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_height_index() {
+        let data_dir = "data/test_height_index";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let genesis = bc.tip.clone();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let a1 = bc.mine_block(vec![cbtx]).unwrap();
+        let cbtx =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let a2 = bc.mine_block(vec![cbtx]).unwrap();
+
+        let hashes =
+            |from, to| -> Vec<String> { bc.iter_heights(from, to).map(|b| b.get_hash()).collect() };
+        assert_eq!(
+            hashes(0, 2),
+            vec![genesis.clone(), a1.get_hash(), a2.get_hash()]
+        );
+        assert_eq!(hashes(2, 1), vec![a2.get_hash(), a1.get_hash()]);
+        assert_eq!(hashes(1, 5), vec![a1.get_hash(), a2.get_hash()]);
+        assert!(bc.get_block_by_height(3).unwrap().is_none());
+
+        // a longer side chain replaces heights 1 and 2 and adds height 3
+        let mut prev = genesis;
+        for height in 1..4 {
+            let cbtx =
+                Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            let block = Block::new_block(vec![cbtx], prev, height, INITIAL_BITS).unwrap();
+            prev = block.get_hash();
+            bc.add_block(block).unwrap();
+        }
+        assert_eq!(
+            bc.get_block_by_height(3).unwrap().unwrap().get_hash(),
+            bc.tip
+        );
+        let heights: Vec<i32> = bc.iter_heights(3, 0).map(|b| b.get_height()).collect();
+        assert_eq!(heights, vec![3, 2, 1, 0]);
+        assert!(bc.iter_heights(0, 3).all(|b| b.get_hash() != a1.get_hash()));
+    }
+
+    #[test]
+    fn test_headers_first() {
+        let data_dir = "data/test_headers_first";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let address = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(address.clone(), data_dir).unwrap();
+        let genesis = bc.tip.clone();
+        let mut blocks = vec![bc.get_block(&genesis).unwrap()];
+        for _ in 0..3 {
+            let cbtx =
+                Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            blocks.push(bc.mine_block(vec![cbtx]).unwrap());
+        }
+        let locator = bc.get_block_locator(&bc.tip).unwrap();
+        assert_eq!(locator.len(), 4);
+        assert_eq!(locator[0], bc.tip);
+        assert_eq!(locator[3], genesis);
+        let after_a1 = bc
+            .get_headers_after(&["unknown".to_string(), blocks[1].get_hash()], MAX_HEADERS)
+            .unwrap();
+        assert_eq!(after_a1.len(), 2);
+        assert_eq!(after_a1[0].prev_block_hash, blocks[1].get_hash());
+
+        // a node without blocks gets every header, and then the blocks
+        let node_dir = "data/test_headers_first_node";
+        std::fs::remove_dir_all(node_dir).ok();
+        let mut node = Blockchain::new(node_dir).unwrap();
+        let headers = bc
+            .get_headers_after(&node.get_block_locator(&node.tip).unwrap(), MAX_HEADERS)
+            .unwrap();
+        assert_eq!(headers.len(), 4);
+        let err = node.add_header(&headers[1]).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::UnknownParent(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+        let mut best = (String::new(), -1, 0);
+        for header in &headers {
+            best = node.add_header(header).unwrap();
+        }
+        assert_eq!(
+            best,
+            (bc.tip.clone(), 3, bc.get_chain_work(&bc.tip).unwrap())
+        );
+        assert!(!node.has_block(&bc.tip).unwrap());
+        assert_eq!(node.get_block_locator(&best.0).unwrap(), locator);
+
+        // a block whose parent is only known by its header is not stored
+        node.add_block(blocks[0].clone()).unwrap();
+        node.get_block(&blocks[1].get_hash()).unwrap_err();
+        let err = node.add_block(blocks[2].clone()).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::MissingParent(hash)) => assert_eq!(*hash, blocks[1].get_hash()),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(!node.has_block(&blocks[2].get_hash()).unwrap());
+        assert_eq!(node.tip, blocks[0].get_hash());
+
+        for block in blocks {
+            node.add_block(block).unwrap();
+        }
+        assert_eq!(node.tip, bc.tip);
+        assert!(node
+            .get_headers_after(&locator, MAX_HEADERS)
+            .unwrap()
+            .is_empty());
+    }
+}
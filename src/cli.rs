@@ -1,14 +1,33 @@
 //! cli process
 
 use super::*;
+use crate::amount::*;
 use crate::blockchain::*;
+use crate::coinselect::*;
+use crate::psbt::*;
+use crate::script::*;
 use crate::server::*;
 use crate::transaction::*;
 use crate::utxoset::*;
 use crate::wallets::*;
-use bitcoincash_addr::Address;
+use bitcoincash_addr::{Address, HashType};
 use clap::{App, Arg};
+use failure::format_err;
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
 use std::process::exit;
+use std::time::Duration;
+
+/// DEFAULT_DATA_DIR is where the databases are kept when --datadir is not given
+const DEFAULT_DATA_DIR: &str = "data";
+/// DEFAULT_GAP_LIMIT is how many unused addresses restorewallet looks past
+const DEFAULT_GAP_LIMIT: u32 = 20;
+const COINSELECT_USAGE: &str =
+    "--coinselect=[strategy] 'Outputs to spend: largest, oldest or bnb (exact match, the default)'";
+const DUST_USAGE: &str =
+    "--dust=[amount] 'Smallest output to create in coins, smaller change is left to the miner'";
+/// UNLOCK_TIMEOUT is how long a command keeps an encrypted wallet unlocked
+const UNLOCK_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct Cli {}
 
@@ -23,20 +42,126 @@ impl Cli {
             .version("0.1")
             .author("yunwei37. 1067852565@qq.com")
             .about("reimplement blockchain_go in rust: a simple blockchain for learning")
-            .subcommand(App::new("printchain").about("print all the chain blocks"))
+            .arg(
+                Arg::from_usage(
+                    "--datadir=[dir] 'Directory of the node databases, data by default'",
+                )
+                .global(true),
+            )
+            .subcommand(
+                App::new("printchain")
+                    .about("print all the chain blocks")
+                    .arg(Arg::from_usage(
+                        "--from=[height] 'first height to print, 0 by default'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--to=[height] 'last height to print, the best height by default'",
+                    )),
+            )
             .subcommand(App::new("createwallet").about("create a wallet"))
             .subcommand(App::new("listaddresses").about("list all addresses"))
+            .subcommand(
+                App::new("listtransactions")
+                    .about("list the transactions of the wallet, the latest last")
+                    .arg(Arg::from_usage(
+                        "[address] 'Only list the transactions of this address'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "-c --count=[n] 'Number of transactions to list, 10 by default'",
+                    )),
+            )
+            .subcommand(
+                App::new("restorewallet")
+                    .about("recreate the addresses of a mnemonic read from stdin and find their coins")
+                    .arg(Arg::from_usage(
+                        "--gap=[n] 'Number of unused addresses to look past, 20 by default'",
+                    )),
+            )
+            .subcommand(
+                App::new("encryptwallet")
+                    .about("encrypt the keys of the wallet with a passphrase read from stdin"),
+            )
+            .subcommand(
+                App::new("createmultisig")
+                    .about("create a multisig address spendable by nrequired of the keys")
+                    .arg(Arg::from_usage(
+                        "<nrequired> 'Number of signatures needed to spend'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "<keys>... 'Public keys in hex, or addresses of this wallet'",
+                    )),
+            )
             .subcommand(App::new("reindex").about("reindex UTXO"))
+            .subcommand(
+                App::new("createpsbt")
+                    .about("write an unsigned payment to a PSBT file, to be signed elsewhere")
+                    .arg(Arg::from_usage("<from> 'Source address'"))
+                    .arg(Arg::from_usage("<to> 'Destination address'"))
+                    .arg(Arg::from_usage("<amount> 'Amount to send in coins'"))
+                    .arg(Arg::from_usage("<file> 'PSBT file to write'"))
+                    .arg(Arg::from_usage(
+                        "-f --fee=[fee] 'Fee paid to the miner in coins, 0 by default'",
+                    ))
+                    .arg(Arg::from_usage(COINSELECT_USAGE))
+                    .arg(Arg::from_usage(DUST_USAGE)),
+            )
+            .subcommand(
+                App::new("signpsbt")
+                    .about("add the signatures of the wallet to a PSBT file")
+                    .arg(Arg::from_usage("<file> 'PSBT file to sign'"))
+                    .arg(Arg::from_usage(
+                        "--signer=[address]... 'Wallet signing the PSBT, every wallet by default'",
+                    )),
+            )
+            .subcommand(
+                App::new("combinepsbt")
+                    .about("add the signatures of other PSBT files to a PSBT file")
+                    .arg(Arg::from_usage("<file> 'PSBT file to update'"))
+                    .arg(Arg::from_usage("<others>... 'PSBT files signed by others'")),
+            )
+            .subcommand(
+                App::new("finalizepsbt")
+                    .about("print the signed transaction of a PSBT file in hex")
+                    .arg(Arg::from_usage("<file> 'PSBT file to finalize'"))
+                    .arg(Arg::from_usage(
+                        "-s --send 'send the transaction to the network'",
+                    )),
+            )
+            .subcommand(
+                App::new("reindextx")
+                    .about("build the transaction index and keep it up to date from now on"),
+            )
             .subcommand(
                 App::new("startnode")
                     .about("start the node server")
-                    .arg(Arg::from_usage("<port> 'the port server bind to locally'")),
+                    .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
+                    .arg(Arg::from_usage(
+                        "--rpcport=[port] 'also serve JSON-RPC requests on this port'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--rpcuser=[user] 'user of the JSON-RPC requests, a cookie file is written to the data dir without it'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--rpcpassword=[password] 'password of the JSON-RPC requests'",
+                    )),
             )
             .subcommand(
                 App::new("startminer")
                     .about("start the minner server")
                     .arg(Arg::from_usage("<port> 'the port server bind to locally'"))
-                    .arg(Arg::from_usage("<address> 'wallet address'")),
+                    .arg(Arg::from_usage("<address> 'wallet address'"))
+                    .arg(Arg::from_usage(
+                        "--rpcport=[port] 'also serve JSON-RPC requests on this port'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--rpcuser=[user] 'user of the JSON-RPC requests, a cookie file is written to the data dir without it'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--rpcpassword=[password] 'password of the JSON-RPC requests'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--threads=[n] 'threads searching the nonce, one per CPU by default'",
+                    )),
             )
             .subcommand(
                 App::new("getbalance")
@@ -45,38 +170,179 @@ impl Cli {
                         "<address> 'The address to get balance for'",
                     )),
             )
-            .subcommand(App::new("createblockchain").about("create blockchain").arg(
-                Arg::from_usage("<address> 'The address to send genesis block reward to'"),
-            ))
+            .subcommand(
+                App::new("createblockchain")
+                    .about("create blockchain")
+                    .arg(Arg::from_usage(
+                        "<address> 'The address to send genesis block reward to'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--halving=[blocks] 'Blocks after which the block subsidy halves, 210000 by default'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--maturity=[blocks] 'Confirmations a coinbase needs before it is spent, 100 by default'",
+                    )),
+            )
             .subcommand(
                 App::new("send")
                     .about("send in the blockchain")
                     .arg(Arg::from_usage("<from> 'Source wallet address'"))
                     .arg(Arg::from_usage("<to> 'Destination wallet address'"))
-                    .arg(Arg::from_usage("<amount> 'Amount to send'"))
+                    .arg(Arg::from_usage("<amount> 'Amount to send in coins'"))
                     .arg(Arg::from_usage(
                         "-m --mine 'the from address mine immediately'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "-f --fee=[fee] 'Fee paid to the miner in coins, 0 by default'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--locktime=[height] 'Lock the payment until the block at height'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--hashlock=[hash] 'Lock the payment to the preimage of this hex sha256 hash'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--signer=[address]... 'Wallet signing a payment from a multisig address, every wallet holding one of its keys by default'",
+                    ))
+                    .arg(Arg::from_usage(COINSELECT_USAGE))
+                    .arg(Arg::from_usage(DUST_USAGE))
+                    .arg(Arg::from_usage(
+                        "--newchange 'Send the change to a new address of the wallet'",
+                    )),
+            )
+            .subcommand(
+                App::new("sendmany")
+                    .about("pay several addresses in one transaction")
+                    .arg(Arg::from_usage("<from> 'Source wallet address'"))
+                    .arg(Arg::from_usage(
+                        "[recipients]... 'Payments as address:amount in coins'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--file=[path] 'Read the payments from a CSV file of address,amount lines or a JSON file'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "-m --mine 'the from address mine immediately'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "-f --fee=[fee] 'Fee paid to the miner in coins, 0 by default'",
+                    ))
+                    .arg(Arg::from_usage(
+                        "--signer=[address]... 'Wallet signing a payment from a multisig address, every wallet holding one of its keys by default'",
+                    ))
+                    .arg(Arg::from_usage(COINSELECT_USAGE))
+                    .arg(Arg::from_usage(DUST_USAGE))
+                    .arg(Arg::from_usage(
+                        "--newchange 'Send the change to a new address of the wallet'",
                     )),
             )
             .get_matches();
 
+        let data_dir = match matches.subcommand() {
+            (_, Some(sub)) if sub.is_present("datadir") => sub.value_of("datadir"),
+            _ => matches.value_of("datadir"),
+        }
+        .unwrap_or(DEFAULT_DATA_DIR)
+        .to_string();
+        let data_dir = data_dir.as_str();
+
         if let Some(ref matches) = matches.subcommand_matches("getbalance") {
             if let Some(address) = matches.value_of("address") {
-                let balance = cmd_get_balance(address)?;
-                println!("Balance: {}\n", balance);
+                let balance = cmd_get_balance(data_dir, address)?;
+                println!("Balance: {}", format_amount(balance.spendable));
+                println!("Immature: {}\n", format_amount(balance.immature));
             }
         } else if let Some(_) = matches.subcommand_matches("createwallet") {
-            println!("address: {}", cmd_create_wallet()?);
-        } else if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+            println!("address: {}", cmd_create_wallet(data_dir)?);
+        } else if let Some(ref matches) = matches.subcommand_matches("printchain") {
+            let from = match matches.value_of("from") {
+                Some(from) => Some(from.parse()?),
+                None => None,
+            };
+            let to = match matches.value_of("to") {
+                Some(to) => Some(to.parse()?),
+                None => None,
+            };
+            cmd_print_chain(data_dir, from, to)?;
         } else if let Some(_) = matches.subcommand_matches("reindex") {
-            let count = cmd_reindex()?;
+            let count = cmd_reindex(data_dir)?;
             println!("Done! There are {} transactions in the UTXO set.", count);
+        } else if let Some(_) = matches.subcommand_matches("reindextx") {
+            let count = cmd_reindex_tx(data_dir)?;
+            println!(
+                "Done! There are {} transactions in the transaction index.",
+                count
+            );
+        } else if let Some(ref matches) = matches.subcommand_matches("createmultisig") {
+            let nrequired: usize = match matches.value_of("nrequired") {
+                Some(nrequired) => nrequired.parse()?,
+                None => {
+                    println!("nrequired not supply!: usage\n{}", matches.usage());
+                    exit(1)
+                }
+            };
+            let keys: Vec<&str> = matches.values_of("keys").unwrap().collect();
+            println!(
+                "address: {}",
+                cmd_create_multisig(data_dir, nrequired, &keys)?
+            );
+        } else if let Some(ref matches) = matches.subcommand_matches("createpsbt") {
+            let from = matches.value_of("from").unwrap();
+            let to = matches.value_of("to").unwrap();
+            let amount = parse_amount(matches.value_of("amount").unwrap())?;
+            let fee = match matches.value_of("fee") {
+                Some(fee) => parse_amount(fee)?,
+                None => 0,
+            };
+            let file = matches.value_of("file").unwrap();
+            let coin_control = coin_control_from(matches)?;
+            cmd_create_psbt(data_dir, from, to, amount, fee, &coin_control, file)?;
+        } else if let Some(ref matches) = matches.subcommand_matches("signpsbt") {
+            let signers: Vec<&str> = match matches.values_of("signer") {
+                Some(signers) => signers.collect(),
+                None => Vec::new(),
+            };
+            let (signed, fee) =
+                cmd_sign_psbt(data_dir, matches.value_of("file").unwrap(), &signers)?;
+            println!("added {} signatures, fee {}", signed, format_amount(fee));
+        } else if let Some(ref matches) = matches.subcommand_matches("combinepsbt") {
+            let others: Vec<&str> = matches.values_of("others").unwrap().collect();
+            cmd_combine_psbt(matches.value_of("file").unwrap(), &others)?;
+        } else if let Some(ref matches) = matches.subcommand_matches("finalizepsbt") {
+            let file = matches.value_of("file").unwrap();
+            cmd_finalize_psbt(data_dir, file, matches.is_present("send"))?;
+        } else if let Some(ref matches) = matches.subcommand_matches("listtransactions") {
+            let count: usize = match matches.value_of("count") {
+                Some(count) => count.parse()?,
+                None => 10,
+            };
+            cmd_list_transactions(data_dir, matches.value_of("address"), count)?;
+        } else if let Some(ref matches) = matches.subcommand_matches("restorewallet") {
+            let gap: u32 = match matches.value_of("gap") {
+                Some(gap) => gap.parse()?,
+                None => DEFAULT_GAP_LIMIT,
+            };
+            let mnemonic = read_passphrase("Enter the mnemonic: ")?;
+            let found = cmd_restore_wallet(data_dir, &mnemonic, gap)?;
+            println!("Done! {} addresses of the mnemonic were used.", found);
+        } else if let Some(_) = matches.subcommand_matches("encryptwallet") {
+            let passphrase = read_passphrase("Enter the new wallet passphrase: ")?;
+            if read_passphrase("Repeat the passphrase: ")? != passphrase {
+                return Err(format_err!("ERROR: passphrases do not match"));
+            }
+            Wallets::new(data_dir)?.encrypt(&passphrase, KeyParams::default())?;
+            println!("wallet encrypted, keep the passphrase safe: the keys are lost without it");
         } else if let Some(_) = matches.subcommand_matches("listaddresses") {
-            cmd_list_address()?;
+            cmd_list_address(data_dir)?;
         } else if let Some(ref matches) = matches.subcommand_matches("createblockchain") {
+            let mut params = ChainParams::default();
+            if let Some(interval) = matches.value_of("halving") {
+                params.halving_interval = interval.parse()?;
+            }
+            if let Some(maturity) = matches.value_of("maturity") {
+                params.coinbase_maturity = maturity.parse()?;
+            }
             if let Some(address) = matches.value_of("address") {
-                cmd_create_blockchain(address)?;
+                cmd_create_blockchain(data_dir, address, Some(params))?;
             }
         } else if let Some(ref matches) = matches.subcommand_matches("send") {
             let from = if let Some(address) = matches.value_of("from") {
@@ -91,23 +357,73 @@ impl Cli {
                 println!("to not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
-            let amount: i32 = if let Some(amount) = matches.value_of("amount") {
-                amount.parse()?
+            let amount = if let Some(amount) = matches.value_of("amount") {
+                parse_amount(amount)?
             } else {
                 println!("amount in send not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
-            if matches.is_present("mine") {
-                cmd_send(from, to, amount, true)?;
-            } else {
-                cmd_send(from, to, amount, false)?;
+            let fee = match matches.value_of("fee") {
+                Some(fee) => parse_amount(fee)?,
+                None => 0,
+            };
+            let lock_height = match matches.value_of("locktime") {
+                Some(height) => Some(height.parse()?),
+                None => None,
+            };
+            let to = locking_script(to, lock_height, matches.value_of("hashlock"))?;
+            let signers: Vec<&str> = match matches.values_of("signer") {
+                Some(signers) => signers.collect(),
+                None => Vec::new(),
+            };
+            let opts = SendOptions {
+                fee,
+                signers: &signers,
+                coin_control: coin_control_from(matches)?,
+                fresh_change: matches.is_present("newchange"),
+                mine_now: matches.is_present("mine"),
+            };
+            let outputs = vec![TXOutput {
+                value: amount,
+                script_pubkey: to,
+            }];
+            cmd_send(data_dir, from, outputs, &opts)?;
+        } else if let Some(ref matches) = matches.subcommand_matches("sendmany") {
+            let from = matches.value_of("from").unwrap();
+            let mut recipients = match matches.value_of("file") {
+                Some(file) => read_recipients(file)?,
+                None => Vec::new(),
+            };
+            if let Some(values) = matches.values_of("recipients") {
+                for value in values {
+                    recipients.push(parse_recipient(value)?);
+                }
             }
+            let fee = match matches.value_of("fee") {
+                Some(fee) => parse_amount(fee)?,
+                None => 0,
+            };
+            let signers: Vec<&str> = match matches.values_of("signer") {
+                Some(signers) => signers.collect(),
+                None => Vec::new(),
+            };
+            let opts = SendOptions {
+                fee,
+                signers: &signers,
+                coin_control: coin_control_from(matches)?,
+                fresh_change: matches.is_present("newchange"),
+                mine_now: matches.is_present("mine"),
+            };
+            cmd_send(data_dir, from, recipient_outputs(&recipients)?, &opts)?;
         } else if let Some(ref matches) = matches.subcommand_matches("startnode") {
             if let Some(port) = matches.value_of("port") {
                 println!("Start node...");
-                let bc = Blockchain::new()?;
-                let utxo_set = UTXOSet { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
+                let bc = Blockchain::new(data_dir)?;
+                let utxo_set = UTXOSet::new(bc)?;
+                let mut server = Server::new(port, "", utxo_set)?;
+                if let Some(rpc_port) = matches.value_of("rpcport") {
+                    server.enable_rpc(rpc_port, rpc_credentials(matches)?)?;
+                }
                 server.start_server()?;
             }
         } else if let Some(ref matches) = matches.subcommand_matches("startminer") {
@@ -123,10 +439,22 @@ impl Cli {
                 println!("port not supply!: usage\n{}", matches.usage());
                 exit(1)
             };
+            if Address::decode(address).is_err() {
+                return Err(format_err!("ERROR: Invalid address {}", address));
+            }
             println!("Start miner node...");
-            let bc = Blockchain::new()?;
-            let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let bc = Blockchain::new(data_dir)?;
+            let utxo_set = UTXOSet::new(bc)?;
+            let mut server = Server::new(port, address, utxo_set)?;
+            if let Some(rpc_port) = matches.value_of("rpcport") {
+                server.enable_rpc(rpc_port, rpc_credentials(matches)?)?;
+            }
+            if let Some(threads) = matches.value_of("threads") {
+                match threads.parse() {
+                    Ok(threads) if threads > 0 => server.set_mining_threads(threads),
+                    _ => return Err(format_err!("ERROR: --threads must be a positive number")),
+                }
+            }
             server.start_server()?;
         }
 
@@ -134,14 +462,183 @@ impl Cli {
     }
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet { blockchain: bc };
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
-    if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
+/// LockingScript returns the script locking a payment to the address, until
+/// lock_height or to the preimage of hash_lock when they are given
+fn locking_script(to: &str, lock_height: Option<i32>, hash_lock: Option<&str>) -> Result<Script> {
+    let address = match Address::decode(to) {
+        Ok(address) => address,
+        Err(_) => return Err(format_err!("ERROR: Invalid address {}", to)),
+    };
+    let pub_key_hash = address.body;
+    match (lock_height, hash_lock) {
+        (None, None) if address.hash_type == HashType::Script => {
+            Ok(Script::pay_to_script_hash(&pub_key_hash))
+        }
+        (None, None) => Ok(Script::pay_to_pub_key_hash(&pub_key_hash)),
+        _ if address.hash_type == HashType::Script => Err(format_err!(
+            "ERROR: only payments to a key address can be locked"
+        )),
+        (Some(height), None) => Ok(Script::time_lock(height, &pub_key_hash)),
+        (None, Some(hash)) => {
+            let hash = hex::decode(hash)?;
+            if hash.len() != 32 {
+                return Err(format_err!("ERROR: hash lock must be a sha256 hash"));
+            }
+            Ok(Script::hash_lock(&hash, &pub_key_hash))
+        }
+        (Some(_), Some(_)) => Err(format_err!(
+            "ERROR: a payment is either time locked or hash locked"
+        )),
+    }
+}
+
+/// RpcCredentials returns the user:password given with --rpcuser and
+/// --rpcpassword, if any
+fn rpc_credentials(matches: &clap::ArgMatches) -> Result<Option<String>> {
+    match (matches.value_of("rpcuser"), matches.value_of("rpcpassword")) {
+        (None, None) => Ok(None),
+        (Some(user), Some(password)) if !user.is_empty() && !password.is_empty() => {
+            if user.contains(':') {
+                return Err(format_err!("ERROR: --rpcuser cannot contain ':'"));
+            }
+            Ok(Some(format!("{}:{}", user, password)))
+        }
+        _ => Err(format_err!(
+            "ERROR: --rpcuser and --rpcpassword must be given together"
+        )),
+    }
+}
+
+/// ReadPassphrase prompts on stderr and reads a line of stdin
+fn read_passphrase(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut passphrase = String::new();
+    io::stdin().lock().read_line(&mut passphrase)?;
+    Ok(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// OpenWallets opens the wallets of data_dir for a command needing the secret
+/// keys, asking for the passphrase when they are encrypted
+fn open_wallets(data_dir: &str) -> Result<Wallets> {
+    let mut wallets = Wallets::new(data_dir)?;
+    if wallets.is_encrypted() {
+        let passphrase = read_passphrase("Enter the wallet passphrase: ")?;
+        wallets.unlock(&passphrase, UNLOCK_TIMEOUT)?;
+    }
+    Ok(wallets)
+}
+
+/// SendOptions are how send builds, signs and relays a payment
+#[derive(Default)]
+struct SendOptions<'a> {
+    /// Fee is paid to the miner
+    fee: Amount,
+    /// Signers sign a payment from a multisig address
+    signers: &'a [&'a str],
+    coin_control: CoinControl,
+    /// FreshChange sends the change to a new address of the wallet
+    fresh_change: bool,
+    /// MineNow mines the payment in a block of this node instead of relaying it
+    mine_now: bool,
+}
+
+/// CoinControlFrom returns the coin selection given by the --coinselect and
+/// --dust options of matches
+fn coin_control_from(matches: &clap::ArgMatches) -> Result<CoinControl> {
+    let mut coin_control = CoinControl::default();
+    if let Some(name) = matches.value_of("coinselect") {
+        coin_control.selector = coin_selector_by_name(name)?;
+    }
+    if let Some(dust) = matches.value_of("dust") {
+        coin_control.dust_threshold = parse_amount(dust)?;
+    }
+    Ok(coin_control)
+}
+
+/// cmd_send pays the outputs from an address of the wallet
+///
+/// A payment from a multisig address is signed by each of the signers, or by
+/// every wallet holding one of its keys when there are none
+fn cmd_send(data_dir: &str, from: &str, outputs: Vec<TXOutput>, opts: &SendOptions) -> Result<()> {
+    let bc = Blockchain::new(data_dir)?;
+    let mut utxo_set = UTXOSet::new(bc)?;
+    let mut wallets = open_wallets(data_dir)?;
+    let fee = opts.fee;
+    let change_address = if opts.fresh_change {
+        if wallets.get_redeem_script(from).is_some() {
+            return Err(format_err!(
+                "ERROR: the change of a multisig address goes back to it"
+            ));
+        }
+        let address = wallets.create_wallet()?;
+        wallets.save_all()?;
+        address
+    } else {
+        from.to_string()
+    };
+    let change = locking_script(&change_address, None, None)?;
+
+    let tx = if let Some(redeem_script) = wallets.get_redeem_script(from) {
+        let script_hash = redeem_script.hash()?;
+        let mut tx = Transaction::new_unsigned(
+            &script_hash,
+            change,
+            outputs,
+            fee,
+            &opts.coin_control,
+            &utxo_set,
+        )?;
+
+        let (m, pub_keys) = redeem_script.get_multisig().unwrap();
+        let mut signers: Vec<&Wallet> = opts
+            .signers
+            .iter()
+            .map(|signer| {
+                wallets
+                    .get_wallet(signer)
+                    .ok_or_else(|| format_err!("ERROR: {} is not in the wallet", signer))
+            })
+            .collect::<Result<_>>()?;
+        if signers.is_empty() {
+            for address in wallets.get_all_addresses() {
+                if let Some(wallet) = wallets.get_wallet(&address) {
+                    if pub_keys.contains(&wallet.public_key) {
+                        signers.push(wallet);
+                    }
+                }
+            }
+        }
+        for wallet in signers {
+            utxo_set.blockchain.sign_multisig_transacton(
+                &mut tx,
+                &wallet.secret_key,
+                redeem_script,
+            )?;
+        }
+        if !utxo_set.blockchain.verify_transacton(&tx)? {
+            return Err(format_err!(
+                "ERROR: {} of the {} keys must sign",
+                m,
+                pub_keys.len()
+            ));
+        }
+        tx
+    } else {
+        let wallet = match wallets.get_wallet(from) {
+            Some(wallet) => wallet,
+            None => return Err(format_err!("ERROR: {} is not in the wallet", from)),
+        };
+        Transaction::new_to_outputs(wallet, outputs, change, fee, &opts.coin_control, &utxo_set)?
+    };
+    if opts.fresh_change {
+        println!("change address: {}", change_address);
+    }
+    if opts.mine_now {
+        // change below the dust threshold was added to the fee
+        let fee = utxo_set.blockchain.get_fee(&tx)?;
+        let reward = utxo_set.blockchain.get_block_reward(fee)?;
+        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"), reward)?;
         let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
 
         utxo_set.update(&new_block)?;
@@ -153,57 +650,333 @@ fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_create_wallet() -> Result<String> {
-    let mut ws = Wallets::new()?;
-    let address = ws.create_wallet();
+/// parse_recipient parses a payment given as address:amount
+fn parse_recipient(value: &str) -> Result<(String, Amount)> {
+    let mut parts = value.rsplitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(amount), Some(address)) => Ok((address.trim().to_string(), parse_amount(amount)?)),
+        _ => Err(format_err!("ERROR: {} is not address:amount", value)),
+    }
+}
+
+/// read_recipients reads the payments of a file, either JSON, as an object of
+/// amounts by address or an array of {"address", "amount"} objects, or CSV
+/// lines of address,amount
+fn read_recipients(file: &str) -> Result<Vec<(String, Amount)>> {
+    let text = std::fs::read_to_string(file)?;
+    if text.trim_start().starts_with(['{', '[']) {
+        let value: serde_json::Value = serde_json::from_str(&text)?;
+        let entries: Vec<(&str, &serde_json::Value)> = match &value {
+            serde_json::Value::Object(amounts) => amounts
+                .iter()
+                .map(|(address, amount)| (address.as_str(), amount))
+                .collect(),
+            serde_json::Value::Array(payments) => payments
+                .iter()
+                .map(|payment| {
+                    (
+                        payment["address"].as_str().unwrap_or_default(),
+                        &payment["amount"],
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut recipients = Vec::new();
+        for (address, amount) in entries {
+            let amount = match amount {
+                serde_json::Value::Number(amount) => parse_amount(&amount.to_string())?,
+                serde_json::Value::String(amount) => parse_amount(amount)?,
+                _ => return Err(format_err!("ERROR: {} has a payment without amount", file)),
+            };
+            if address.is_empty() {
+                return Err(format_err!("ERROR: {} has a payment without address", file));
+            }
+            recipients.push((address.to_string(), amount));
+        }
+        return Ok(recipients);
+    }
+
+    let mut recipients = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "address,amount" {
+            continue;
+        }
+        let mut parts = line.splitn(2, ',');
+        match (parts.next(), parts.next()) {
+            (Some(address), Some(amount)) => {
+                recipients.push((address.trim().to_string(), parse_amount(amount)?))
+            }
+            _ => return Err(format_err!("ERROR: {} is not address,amount", line)),
+        }
+    }
+    Ok(recipients)
+}
+
+/// recipient_outputs returns the outputs paying the recipients, each address once
+fn recipient_outputs(recipients: &[(String, Amount)]) -> Result<Vec<TXOutput>> {
+    if recipients.is_empty() {
+        return Err(format_err!("ERROR: no payments to send"));
+    }
+    let mut addresses = HashSet::new();
+    let mut outputs = Vec::new();
+    for (address, amount) in recipients {
+        if !addresses.insert(address) {
+            return Err(format_err!("ERROR: {} is paid more than once", address));
+        }
+        outputs.push(TXOutput {
+            value: *amount,
+            script_pubkey: locking_script(address, None, None)?,
+        });
+    }
+    Ok(outputs)
+}
+
+/// cmd_create_wallet creates the next address of the wallet, and prints the
+/// mnemonic backing up every address when it is created along the first one
+fn cmd_create_wallet(data_dir: &str) -> Result<String> {
+    let mut ws = open_wallets(data_dir)?;
+    let new_seed = !ws.has_seed();
+    let address = ws.create_wallet()?;
     ws.save_all()?;
+    if new_seed {
+        println!(
+            "mnemonic: {}\nwrite the mnemonic down, restorewallet recreates the addresses of the wallet from it",
+            ws.get_mnemonic().unwrap_or_default()
+        );
+    }
+    let non_hd = ws.non_hd_key_count();
+    if non_hd > 0 {
+        println!(
+            "warning: {} keys of the wallet were created before the mnemonic and are not derived from it, back up the wallet db to keep them",
+            non_hd
+        );
+    }
     Ok(address)
 }
 
-fn cmd_reindex() -> Result<i32> {
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
+/// cmd_restore_wallet recreates the addresses of a mnemonic, up to gap
+/// addresses after the last one paid in the chain
+fn cmd_restore_wallet(data_dir: &str, mnemonic: &str, gap: u32) -> Result<usize> {
+    let bc = Blockchain::new(data_dir)?;
+    let mut used = HashSet::new();
+    for block in bc.iter() {
+        for tx in block.get_transaction() {
+            for out in &tx.vout {
+                if let Some(pub_key_hash) = out.script_pubkey.get_pub_key_hash() {
+                    used.insert(pub_key_hash.to_vec());
+                }
+            }
+        }
+    }
+
+    let mut ws = open_wallets(data_dir)?;
+    let found = ws.restore(mnemonic, gap, |pub_key_hash| used.contains(pub_key_hash))?;
+    ws.save_all()?;
+    let utxo_set = UTXOSet::new(bc)?;
+    for address in ws.get_all_addresses() {
+        let pub_key_hash = Address::decode(&address).unwrap().body;
+        let balance = utxo_set.get_balance(&pub_key_hash)?;
+        println!(
+            "{} balance: {}",
+            address,
+            format_amount(checked_add(balance.spendable, balance.immature)?)
+        );
+    }
+    Ok(found)
+}
+
+fn cmd_create_multisig(data_dir: &str, nrequired: usize, keys: &[&str]) -> Result<String> {
+    let mut ws = Wallets::new(data_dir)?;
+    let mut pub_keys = Vec::new();
+    for key in keys {
+        match ws.get_public_key(key) {
+            Some(pub_key) => pub_keys.push(pub_key.clone()),
+            None => pub_keys.push(hex::decode(key)?),
+        }
+    }
+    let address = ws.add_multisig(nrequired, &pub_keys)?;
+    ws.save_all()?;
+    Ok(address)
+}
+
+fn read_psbt(file: &str) -> Result<Psbt> {
+    Psbt::from_hex(&std::fs::read_to_string(file)?)
+}
+
+fn write_psbt(file: &str, psbt: &Psbt) -> Result<()> {
+    std::fs::write(file, psbt.to_hex()? + "\n")?;
+    Ok(())
+}
+
+fn cmd_create_psbt(
+    data_dir: &str,
+    from: &str,
+    to: &str,
+    amount: Amount,
+    fee: Amount,
+    coin_control: &CoinControl,
+    file: &str,
+) -> Result<()> {
+    let from_hash = match Address::decode(from) {
+        Ok(address) => address.body,
+        Err(_) => return Err(format_err!("ERROR: Invalid address {}", from)),
+    };
+    let change = locking_script(from, None, None)?;
+    let outputs = vec![TXOutput {
+        value: amount,
+        script_pubkey: locking_script(to, None, None)?,
+    }];
+
+    let bc = Blockchain::new(data_dir)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    let tx = Transaction::new_unsigned(&from_hash, change, outputs, fee, coin_control, &utxo_set)?;
+    let mut psbt = Psbt::new(tx, &utxo_set.blockchain)?;
+    if let Some(redeem_script) = Wallets::new(data_dir)?.get_redeem_script(from) {
+        psbt.add_redeem_script(redeem_script)?;
+    }
+    write_psbt(file, &psbt)
+}
+
+fn cmd_sign_psbt(data_dir: &str, file: &str, signers: &[&str]) -> Result<(usize, Amount)> {
+    let mut psbt = read_psbt(file)?;
+    let ws = open_wallets(data_dir)?;
+    for address in ws.get_multisig_addresses() {
+        psbt.add_redeem_script(ws.get_redeem_script(&address).unwrap())?;
+    }
+
+    let signers: Vec<String> = if signers.is_empty() {
+        ws.get_all_addresses()
+    } else {
+        signers.iter().map(|signer| signer.to_string()).collect()
+    };
+    let mut signed = 0;
+    for signer in signers {
+        match ws.get_wallet(&signer) {
+            Some(wallet) => signed += psbt.sign(wallet)?,
+            None => return Err(format_err!("ERROR: {} is not in the wallet", signer)),
+        }
+    }
+    write_psbt(file, &psbt)?;
+    Ok((signed, psbt.fee()?))
+}
+
+fn cmd_combine_psbt(file: &str, others: &[&str]) -> Result<()> {
+    let mut psbt = read_psbt(file)?;
+    for other in others {
+        psbt.combine(&read_psbt(other)?)?;
+    }
+    write_psbt(file, &psbt)
+}
+
+fn cmd_finalize_psbt(data_dir: &str, file: &str, send: bool) -> Result<()> {
+    let tx = read_psbt(file)?.finalize()?;
+    println!("{}", hex::encode(bincode::serialize(&tx)?));
+    if send {
+        let bc = Blockchain::new(data_dir)?;
+        let utxo_set = UTXOSet::new(bc)?;
+        Server::send_transaction(&tx, utxo_set)?;
+    }
+    Ok(())
+}
+
+fn cmd_reindex(data_dir: &str) -> Result<i32> {
+    let bc = Blockchain::new(data_dir)?;
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     utxo_set.count_transactions()
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+fn cmd_reindex_tx(data_dir: &str) -> Result<usize> {
+    let bc = Blockchain::new(data_dir)?;
+    bc.reindex_transactions()
+}
+
+fn cmd_create_blockchain(data_dir: &str, address: &str, params: Option<ChainParams>) -> Result<()> {
     let address = String::from(address);
-    let bc = Blockchain::create_blockchain(address)?;
+    let mut bc = Blockchain::create_blockchain(address, data_dir)?;
+    if let Some(params) = params {
+        bc.set_params(params)?;
+    }
 
-    let utxo_set = UTXOSet { blockchain: bc };
+    let utxo_set = UTXOSet::new(bc)?;
     utxo_set.reindex()?;
     println!("create blockchain");
     Ok(())
 }
 
-fn cmd_get_balance(address: &str) -> Result<i32> {
+fn cmd_get_balance(data_dir: &str, address: &str) -> Result<Balance> {
     let pub_key_hash = Address::decode(address).unwrap().body;
-    let bc = Blockchain::new()?;
-    let utxo_set = UTXOSet { blockchain: bc };
-    let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
+    let bc = Blockchain::new(data_dir)?;
+    let utxo_set = UTXOSet::new(bc)?;
+    utxo_set.get_balance(&pub_key_hash)
+}
 
-    let mut balance = 0;
-    for out in utxos.outputs {
-        balance += out.value;
+fn cmd_print_chain(data_dir: &str, from: Option<i32>, to: Option<i32>) -> Result<()> {
+    let bc = Blockchain::new(data_dir)?;
+    if from.is_none() && to.is_none() {
+        for b in bc.iter() {
+            println!("{:#?}", b);
+        }
+        return Ok(());
     }
-    Ok(balance)
-}
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = Blockchain::new()?;
-    for b in bc.iter() {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(bc.get_best_height()?);
+    for b in bc.iter_heights(from, to) {
         println!("{:#?}", b);
     }
     Ok(())
 }
 
-fn cmd_list_address() -> Result<()> {
-    let ws = Wallets::new()?;
+/// cmd_list_transactions prints the count latest transactions of the wallet,
+/// with the amount received by address when it is given
+fn cmd_list_transactions(data_dir: &str, address: Option<&str>, count: usize) -> Result<()> {
+    let bc = Blockchain::new(data_dir)?;
+    let mut ws = Wallets::new(data_dir)?;
+    ws.sync(&bc)?;
+    let best_height = bc.get_best_height()?;
+    let history = ws.get_history(address);
+    for wtx in &history[history.len().saturating_sub(count)..] {
+        let amount = match address {
+            Some(address) => wtx.ledger[address],
+            None => wtx.amount,
+        };
+        let kind = if wtx.is_coinbase {
+            "mined"
+        } else if amount < 0 {
+            "sent"
+        } else {
+            "received"
+        };
+        println!(
+            "{} {}: {} height: {} confirmations: {} time: {}",
+            wtx.txid,
+            kind,
+            format_signed_amount(amount),
+            wtx.height,
+            wtx.confirmations(best_height),
+            wtx.timestamp
+        );
+        for (to, value) in &wtx.counterparties {
+            println!("    pays {}: {}", to, format_amount(*value));
+        }
+    }
+    Ok(())
+}
+
+fn cmd_list_address(data_dir: &str) -> Result<()> {
+    let ws = Wallets::new(data_dir)?;
     let addresses = ws.get_all_addresses();
     println!("addresses: ");
     for ad in addresses {
-        println!("{}", ad);
+        let pub_key = ws.get_public_key(&ad).unwrap();
+        println!("{} pubkey: {}", ad, hex::encode(pub_key));
+    }
+    for ad in ws.get_multisig_addresses() {
+        let (m, pub_keys) = ws.get_redeem_script(&ad).unwrap().get_multisig().unwrap();
+        println!("{} multisig: {} of {}", ad, m, pub_keys.len());
     }
     Ok(())
 }
@@ -212,28 +985,143 @@ fn cmd_list_address() -> Result<()> {
 mod test {
     use super::*;
 
+    /// TEST_PARAMS let the tests spend a coinbase in the next block
+    const TEST_PARAMS: ChainParams = ChainParams {
+        halving_interval: HALVING_INTERVAL,
+        coinbase_maturity: 1,
+    };
+
+    fn pay(address: &str, coins: Amount) -> Vec<TXOutput> {
+        vec![TXOutput {
+            value: coins * COIN,
+            script_pubkey: locking_script(address, None, None).unwrap(),
+        }]
+    }
+
+    fn mined<'a>(fee: Amount, signers: &'a [&'a str]) -> SendOptions<'a> {
+        SendOptions {
+            fee: fee * COIN,
+            signers,
+            mine_now: true,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_locally() {
-        let addr1 = cmd_create_wallet().unwrap();
-        let addr2 = cmd_create_wallet().unwrap();
-        cmd_create_blockchain(&addr1).unwrap();
+        let data_dir = "data/test_locally";
+        std::fs::remove_dir_all(data_dir).ok();
+        let addr1 = cmd_create_wallet(data_dir).unwrap();
+        let addr2 = cmd_create_wallet(data_dir).unwrap();
+        cmd_create_blockchain(data_dir, &addr1, Some(TEST_PARAMS)).unwrap();
 
-        let b1 = cmd_get_balance(&addr1).unwrap();
-        let b2 = cmd_get_balance(&addr2).unwrap();
-        assert_eq!(b1, 10);
+        let b1 = cmd_get_balance(data_dir, &addr1).unwrap().spendable;
+        let b2 = cmd_get_balance(data_dir, &addr2).unwrap().spendable;
+        assert_eq!(b1, 10 * COIN);
         assert_eq!(b2, 0);
 
-        cmd_send(&addr1, &addr2, 5, true).unwrap();
+        cmd_send(data_dir, &addr1, pay(&addr2, 5), &mined(0, &[])).unwrap();
+
+        let b1 = cmd_get_balance(data_dir, &addr1).unwrap().spendable;
+        let b2 = cmd_get_balance(data_dir, &addr2).unwrap().spendable;
+        assert_eq!(b1, 15 * COIN);
+        assert_eq!(b2, 5 * COIN);
+
+        cmd_send(data_dir, &addr2, pay(&addr1, 15), &mined(0, &[])).unwrap_err();
+        let b1 = cmd_get_balance(data_dir, &addr1).unwrap().spendable;
+        let b2 = cmd_get_balance(data_dir, &addr2).unwrap().spendable;
+        assert_eq!(b1, 15 * COIN);
+        assert_eq!(b2, 5 * COIN);
+
+        cmd_send(data_dir, &addr2, pay(&addr1, 5), &mined(1, &[])).unwrap_err();
+        cmd_send(data_dir, &addr2, pay(&addr1, 3), &mined(2, &[])).unwrap();
+        let b1 = cmd_get_balance(data_dir, &addr1).unwrap().spendable;
+        let b2 = cmd_get_balance(data_dir, &addr2).unwrap().spendable;
+        assert_eq!(b1, 18 * COIN);
+        assert_eq!(b2, 12 * COIN);
+
+        let mut opts = mined(0, &[]);
+        opts.coin_control.dust_threshold = 4 * COIN;
+        cmd_send(data_dir, &addr2, pay(&addr1, 3), &opts).unwrap_err();
+    }
+
+    #[test]
+    fn test_send_many() {
+        let data_dir = "data/test_send_many";
+        std::fs::remove_dir_all(data_dir).ok();
+        let addr1 = cmd_create_wallet(data_dir).unwrap();
+        let addr2 = cmd_create_wallet(data_dir).unwrap();
+        let addr3 = cmd_create_wallet(data_dir).unwrap();
+        cmd_create_blockchain(data_dir, &addr1, Some(TEST_PARAMS)).unwrap();
+
+        let csv = format!("{}/payments.csv", data_dir);
+        std::fs::write(&csv, format!("address,amount\n# rent\n{},3\n\n", addr2)).unwrap();
+        let mut recipients = read_recipients(&csv).unwrap();
+        recipients.push(parse_recipient(&format!("{}:4", addr3)).unwrap());
+        let outputs = recipient_outputs(&recipients).unwrap();
+        cmd_send(data_dir, &addr1, outputs, &mined(1, &[])).unwrap();
+        assert_eq!(
+            cmd_get_balance(data_dir, &addr1).unwrap().spendable,
+            13 * COIN
+        );
+        assert_eq!(
+            cmd_get_balance(data_dir, &addr2).unwrap().spendable,
+            3 * COIN
+        );
+        assert_eq!(
+            cmd_get_balance(data_dir, &addr3).unwrap().spendable,
+            4 * COIN
+        );
+        let block = Blockchain::new(data_dir).unwrap().iter().next().unwrap();
+        assert_eq!(block.get_transaction()[1].vout.len(), 3);
+
+        let json = format!("{}/payments.json", data_dir);
+        std::fs::write(
+            &json,
+            format!(r#"[{{"address": "{}", "amount": 0.5}}]"#, addr3),
+        )
+        .unwrap();
+        assert_eq!(
+            read_recipients(&json).unwrap(),
+            vec![(addr3.clone(), COIN / 2)]
+        );
+        recipients.push((addr3, 2));
+        recipient_outputs(&recipients).unwrap_err();
+        recipient_outputs(&[]).unwrap_err();
+    }
+
+    #[test]
+    fn test_multisig() {
+        let data_dir = "data/test_multisig";
+        std::fs::remove_dir_all(data_dir).ok();
+        let addr1 = cmd_create_wallet(data_dir).unwrap();
+        let addr2 = cmd_create_wallet(data_dir).unwrap();
+        let addr3 = cmd_create_wallet(data_dir).unwrap();
+        cmd_create_blockchain(data_dir, &addr1, Some(TEST_PARAMS)).unwrap();
+        let multisig = cmd_create_multisig(data_dir, 2, &[&addr1, &addr2, &addr3]).unwrap();
 
-        let b1 = cmd_get_balance(&addr1).unwrap();
-        let b2 = cmd_get_balance(&addr2).unwrap();
-        assert_eq!(b1, 15);
-        assert_eq!(b2, 5);
+        cmd_send(data_dir, &addr1, pay(&multisig, 6), &mined(0, &[])).unwrap();
+        assert_eq!(
+            cmd_get_balance(data_dir, &multisig).unwrap().spendable,
+            6 * COIN
+        );
 
-        cmd_send(&addr2, &addr1, 15, true).unwrap_err();
-        let b1 = cmd_get_balance(&addr1).unwrap();
-        let b2 = cmd_get_balance(&addr2).unwrap();
-        assert_eq!(b1, 15);
-        assert_eq!(b2, 5);
+        cmd_send(data_dir, &multisig, pay(&addr3, 4), &mined(0, &[&addr1])).unwrap_err();
+        cmd_send(
+            data_dir,
+            &multisig,
+            pay(&addr3, 4),
+            &mined(0, &[&addr2, &addr3]),
+        )
+        .unwrap();
+        // the block reward of the send goes to the multisig address
+        assert_eq!(
+            cmd_get_balance(data_dir, &multisig).unwrap().spendable,
+            12 * COIN
+        );
+        assert_eq!(
+            cmd_get_balance(data_dir, &addr3).unwrap().spendable,
+            4 * COIN
+        );
     }
 }
@@ -0,0 +1,224 @@
+//! coin selection
+//!
+//! A `CoinSelector` picks which unspent outputs of the wallet a transaction
+//! spends. Largest-first spends few outputs, oldest-first consolidates old
+//! coins, and branch-and-bound looks for a set of outputs matching the payment
+//! closely enough that no change output is needed.
+
+use super::*;
+use crate::amount::*;
+use failure::format_err;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+
+/// DUST_THRESHOLD is the smallest output worth creating, smaller change is
+/// left to the miner
+pub const DUST_THRESHOLD: Amount = 546;
+/// BNB_MAX_TRIES bounds the branch-and-bound search
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Utxo is an unspent output the wallet can spend
+#[derive(Debug, Clone, PartialEq)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: i32,
+    pub value: Amount,
+    /// Height is the height of the block of the transaction
+    pub height: i32,
+}
+
+/// CoinSelector selects the outputs spent by a transaction
+pub trait CoinSelector {
+    /// Select returns outputs of utxos worth at least target, or None when
+    /// they are not worth enough
+    ///
+    /// Change below cost_of_change is not worth an output of its own
+    fn select(&self, utxos: &[Utxo], target: Amount, cost_of_change: Amount) -> Option<Vec<Utxo>>;
+}
+
+/// LargestFirst spends the largest outputs first
+pub struct LargestFirst;
+
+/// OldestFirst spends the outputs of the oldest blocks first
+pub struct OldestFirst;
+
+/// BranchAndBound looks for outputs worth the target up to the cost of change,
+/// and spends the largest outputs first when there are none
+pub struct BranchAndBound;
+
+/// CoinControl is how a transaction spends the outputs of the wallet
+pub struct CoinControl {
+    pub selector: Box<dyn CoinSelector>,
+    /// DustThreshold is the smallest output the transaction may create
+    pub dust_threshold: Amount,
+    /// Exclude lists the outputs, by txid and index, not to spend, such as
+    /// those already spent by transactions of the mempool
+    pub exclude: HashSet<(String, i32)>,
+}
+
+impl Default for CoinControl {
+    fn default() -> Self {
+        CoinControl {
+            selector: Box::new(BranchAndBound),
+            dust_threshold: DUST_THRESHOLD,
+            exclude: HashSet::new(),
+        }
+    }
+}
+
+/// CoinSelectorByName returns the selector named largest, oldest or bnb
+pub fn coin_selector_by_name(name: &str) -> Result<Box<dyn CoinSelector>> {
+    match name {
+        "largest" => Ok(Box::new(LargestFirst)),
+        "oldest" => Ok(Box::new(OldestFirst)),
+        "bnb" => Ok(Box::new(BranchAndBound)),
+        _ => Err(format_err!(
+            "ERROR: unknown coin selection {}, use largest, oldest or bnb",
+            name
+        )),
+    }
+}
+
+/// SelectInOrder takes outputs in order until they are worth target
+fn select_in_order(utxos: Vec<Utxo>, target: Amount) -> Option<Vec<Utxo>> {
+    let mut selected = Vec::new();
+    let mut accumulated = 0;
+    for utxo in utxos {
+        if accumulated >= target {
+            break;
+        }
+        accumulated += utxo.value;
+        selected.push(utxo);
+    }
+    if accumulated >= target {
+        Some(selected)
+    } else {
+        None
+    }
+}
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, utxos: &[Utxo], target: Amount, _: Amount) -> Option<Vec<Utxo>> {
+        let mut utxos = utxos.to_vec();
+        utxos.sort_by_key(|u| Reverse(u.value));
+        select_in_order(utxos, target)
+    }
+}
+
+impl CoinSelector for OldestFirst {
+    fn select(&self, utxos: &[Utxo], target: Amount, _: Amount) -> Option<Vec<Utxo>> {
+        let mut utxos = utxos.to_vec();
+        utxos.sort_by(|a, b| a.height.cmp(&b.height).then(b.value.cmp(&a.value)));
+        select_in_order(utxos, target)
+    }
+}
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, utxos: &[Utxo], target: Amount, cost_of_change: Amount) -> Option<Vec<Utxo>> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|u| Reverse(u.value));
+        match branch_and_bound(&sorted, target, cost_of_change) {
+            Some(selected) => Some(selected),
+            None => select_in_order(sorted, target),
+        }
+    }
+}
+
+/// BranchAndBound searches the outputs, sorted from the largest, for those
+/// worth at least target and less than target + cost_of_change, with the least excess
+fn branch_and_bound(sorted: &[Utxo], target: Amount, cost_of_change: Amount) -> Option<Vec<Utxo>> {
+    // remaining[i] is the value of the outputs from i on
+    let mut remaining = vec![0; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        remaining[i] = remaining[i + 1] + sorted[i].value;
+    }
+
+    let mut best: Option<(Amount, Vec<bool>)> = None;
+    let mut included = vec![false; sorted.len()];
+    let mut value = 0;
+    let mut depth = 0;
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let backtrack = if value >= target.saturating_add(cost_of_change.max(1))
+            || value + remaining[depth] < target
+        {
+            true
+        } else if value >= target {
+            let excess = value - target;
+            if best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                best = Some((excess, included.clone()));
+            }
+            true
+        } else {
+            depth == sorted.len()
+        };
+
+        if backtrack {
+            // undo the last inclusion and try without it
+            while depth > 0 && !included[depth - 1] {
+                depth -= 1;
+            }
+            if depth == 0 || tries >= BNB_MAX_TRIES || best.as_ref().is_some_and(|b| b.0 == 0) {
+                break;
+            }
+            included[depth - 1] = false;
+            value -= sorted[depth - 1].value;
+        } else {
+            included[depth] = true;
+            value += sorted[depth].value;
+            depth += 1;
+            continue;
+        }
+    }
+
+    best.map(|(_, included)| {
+        sorted
+            .iter()
+            .zip(included)
+            .filter(|(_, included)| *included)
+            .map(|(utxo, _)| utxo.clone())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utxos(values: &[(Amount, i32)]) -> Vec<Utxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, (value, height))| Utxo {
+                txid: format!("{}", i),
+                vout: 0,
+                value: *value,
+                height: *height,
+            })
+            .collect()
+    }
+
+    fn values(selected: Option<Vec<Utxo>>) -> Vec<Amount> {
+        let mut values: Vec<Amount> = selected.unwrap().iter().map(|u| u.value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_coin_selection() {
+        let utxos = utxos(&[(5, 3), (1, 1), (8, 2), (3, 4), (2, 5)]);
+
+        assert_eq!(values(LargestFirst.select(&utxos, 10, 1)), vec![5, 8]);
+        assert_eq!(values(OldestFirst.select(&utxos, 10, 1)), vec![1, 5, 8]);
+        assert_eq!(values(BranchAndBound.select(&utxos, 10, 1)), vec![2, 8]);
+        assert_eq!(values(BranchAndBound.select(&utxos, 12, 1)), vec![1, 3, 8]);
+        // nothing within the cost of change, largest first
+        assert_eq!(values(BranchAndBound.select(&utxos[..3], 7, 1)), vec![8]);
+        assert_eq!(values(BranchAndBound.select(&utxos[..3], 4, 2)), vec![5]);
+
+        assert!(LargestFirst.select(&utxos, 20, 1).is_none());
+        assert!(BranchAndBound.select(&utxos, 20, 1).is_none());
+        assert!(coin_selector_by_name("random").is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! block download
+//!
+//! A node first syncs the headers of the chain with the most work, and then
+//! downloads the blocks it is missing on that chain. Blocks are requested from
+//! every peer known to have them, a few at a time per peer. A request left
+//! unanswered is sent again to another peer, and a block arriving before its
+//! parent waits for it, since blocks are connected in the order of the chain.
+
+use crate::block::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// MAX_BLOCKS_IN_FLIGHT is how many blocks are requested at once from one peer
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// DOWNLOAD_WINDOW bounds the blocks requested and waiting for their parent
+const DOWNLOAD_WINDOW: usize = 256;
+/// BLOCK_DOWNLOAD_TIMEOUT is how long a peer has to send a requested block
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// MAX_DOWNLOAD_ATTEMPTS is how many times a block is requested before the
+/// download is given up
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+struct Request {
+    peer: String,
+    height: i32,
+    sent: Instant,
+}
+
+/// BlockDownload schedules the requests of the blocks of the best header chain
+pub struct BlockDownload {
+    /// best_header is the hash, height and cumulative work of the header chain
+    /// with the most work
+    best_header: (String, i32, u128),
+    /// peers maps each peer to the height of its best chain
+    peers: HashMap<String, i32>,
+    queue: VecDeque<(String, i32)>,
+    queued: HashSet<String>,
+    in_flight: HashMap<String, Request>,
+    /// failures counts the requests of a block that timed out, with the peer
+    /// of the last one
+    failures: HashMap<String, (u32, String)>,
+    /// orphans holds the blocks received before their parent, by hash
+    orphans: HashMap<String, Block>,
+}
+
+impl BlockDownload {
+    /// NewBlockDownload creates a download with nothing to fetch, whose best
+    /// header is the tip of the active chain
+    pub fn new(tip: String, height: i32, work: u128) -> BlockDownload {
+        BlockDownload {
+            best_header: (tip, height, work),
+            peers: HashMap::new(),
+            queue: VecDeque::new(),
+            queued: HashSet::new(),
+            in_flight: HashMap::new(),
+            failures: HashMap::new(),
+            orphans: HashMap::new(),
+        }
+    }
+
+    /// BestHeader returns the hash, height and work of the best header chain
+    pub fn best_header(&self) -> (String, i32, u128) {
+        self.best_header.clone()
+    }
+
+    /// UpdateBestHeader makes a header the best one if its chain has more work
+    pub fn update_best_header(&mut self, hash: String, height: i32, work: u128) {
+        if work > self.best_header.2 {
+            self.best_header = (hash, height, work);
+        }
+    }
+
+    /// ResetBestHeader drops the download and makes the tip the best header
+    /// again, once a block of the best header chain turned out to be invalid
+    pub fn reset_best_header(&mut self, tip: String, height: i32, work: u128) {
+        self.clear();
+        self.best_header = (tip, height, work);
+    }
+
+    /// SetPeerHeight records that peer has the blocks up to height
+    pub fn set_peer_height(&mut self, peer: &str, height: i32) {
+        let best = self.peers.entry(peer.to_string()).or_insert(height);
+        *best = (*best).max(height);
+    }
+
+    /// RemovePeer forgets a peer and queues again the blocks requested from it
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.remove(peer);
+        let hashes: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer == peer)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        self.requeue(hashes);
+    }
+
+    /// IsTracked tells whether a block is queued, requested or waiting for its parent
+    pub fn is_tracked(&self, hash: &str) -> bool {
+        self.queued.contains(hash)
+            || self.in_flight.contains_key(hash)
+            || self.orphans.contains_key(hash)
+    }
+
+    /// Enqueue adds blocks to fetch, given with their height in the order of the chain
+    pub fn enqueue(&mut self, blocks: Vec<(String, i32)>) {
+        for (hash, height) in blocks {
+            if !self.is_tracked(&hash) {
+                self.queued.insert(hash.clone());
+                self.queue.push_back((hash, height));
+            }
+        }
+    }
+
+    /// Schedule assigns queued blocks to the peers that have them, the least
+    /// busy first, and returns the requests to send as peer and block hash
+    ///
+    /// A block whose request timed out goes to another peer when there is one
+    pub fn schedule(&mut self, now: Instant) -> Vec<(String, String)> {
+        let mut load: HashMap<String, usize> = HashMap::new();
+        for request in self.in_flight.values() {
+            *load.entry(request.peer.clone()).or_default() += 1;
+        }
+
+        let mut requests = Vec::new();
+        while self.in_flight.len() + self.orphans.len() < DOWNLOAD_WINDOW {
+            let (hash, height) = match self.queue.front() {
+                Some((hash, height)) => (hash.clone(), *height),
+                None => break,
+            };
+            let failed_peer = self.failures.get(&hash).map(|(_, peer)| peer);
+            let peer = self
+                .peers
+                .iter()
+                .filter(|(peer, best)| {
+                    **best >= height && load.get(*peer).copied().unwrap_or(0) < MAX_BLOCKS_IN_FLIGHT
+                })
+                .min_by_key(|(peer, _)| {
+                    (
+                        Some(*peer) == failed_peer,
+                        load.get(*peer).copied().unwrap_or(0),
+                        (*peer).clone(),
+                    )
+                })
+                .map(|(peer, _)| peer.clone());
+            let peer = match peer {
+                Some(peer) => peer,
+                None => break,
+            };
+
+            self.queue.pop_front();
+            self.queued.remove(&hash);
+            *load.entry(peer.clone()).or_default() += 1;
+            self.in_flight.insert(
+                hash.clone(),
+                Request {
+                    peer: peer.clone(),
+                    height,
+                    sent: now,
+                },
+            );
+            requests.push((peer, hash));
+        }
+        requests
+    }
+
+    /// Expire queues again the blocks requested longer than
+    /// BLOCK_DOWNLOAD_TIMEOUT ago, and returns how many there were
+    ///
+    /// The download is given up once a block timed out MAX_DOWNLOAD_ATTEMPTS
+    /// times, and starts again with the next headers received
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let expired: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| now.duration_since(request.sent) >= BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in &expired {
+            let request = &self.in_flight[hash];
+            warn!(
+                "block {} at height {} requested from {} timed out",
+                hash, request.height, request.peer
+            );
+            let failure = self
+                .failures
+                .entry(hash.clone())
+                .or_insert((0, String::new()));
+            failure.0 += 1;
+            failure.1 = request.peer.clone();
+            if failure.0 >= MAX_DOWNLOAD_ATTEMPTS {
+                warn!("give up the download of block {}", hash);
+                self.clear();
+                return expired.len();
+            }
+        }
+        self.requeue(expired.clone());
+        expired.len()
+    }
+
+    /// BlockReceived marks a block as received, and tells whether it was to be
+    /// downloaded, requested or queued again after its request timed out
+    pub fn block_received(&mut self, hash: &str) -> bool {
+        self.failures.remove(hash);
+        if self.queued.remove(hash) {
+            self.queue.retain(|(queued, _)| queued != hash);
+            return true;
+        }
+        self.in_flight.remove(hash).is_some()
+    }
+
+    /// AddOrphan keeps a block to download until its parent is connected
+    pub fn add_orphan(&mut self, block: Block) {
+        self.orphans.insert(block.get_hash(), block);
+    }
+
+    /// TakeOrphan returns the block waiting for the block parent_hash, if any
+    pub fn take_orphan(&mut self, parent_hash: &str) -> Option<Block> {
+        let hash = self
+            .orphans
+            .values()
+            .find(|block| block.get_prev_hash() == parent_hash)?
+            .get_hash();
+        self.orphans.remove(&hash)
+    }
+
+    /// InFlight returns the number of blocks requested and not received yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Pending returns the number of blocks queued or waiting for their parent
+    pub fn pending(&self) -> usize {
+        self.queue.len() + self.orphans.len()
+    }
+
+    /// Clear drops the blocks queued, requested and waiting for their parent
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.queued.clear();
+        self.in_flight.clear();
+        self.failures.clear();
+        self.orphans.clear();
+    }
+
+    /// Requeue puts requested blocks back at the front of the queue, lowest first
+    fn requeue(&mut self, hashes: Vec<String>) {
+        let mut blocks: Vec<(String, i32)> = hashes
+            .into_iter()
+            .filter_map(|hash| {
+                let request = self.in_flight.remove(&hash)?;
+                Some((hash, request.height))
+            })
+            .collect();
+        blocks.sort_by_key(|(_, height)| -height);
+        for (hash, height) in blocks {
+            self.queued.insert(hash.clone());
+            self.queue.push_front((hash, height));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blocks(from: i32, to: i32) -> Vec<(String, i32)> {
+        (from..=to).map(|h| (format!("b{}", h), h)).collect()
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut download = BlockDownload::new(String::new(), -1, 0);
+        download.enqueue(blocks(0, 39));
+        download.enqueue(blocks(30, 40));
+        assert_eq!(download.pending(), 41);
+
+        // nothing is requested before a peer is known to have the blocks
+        let now = Instant::now();
+        assert!(download.schedule(now).is_empty());
+        download.set_peer_height("p1", 40);
+        download.set_peer_height("p2", 10);
+        let requests = download.schedule(now);
+        assert_eq!(requests[0], ("p1".to_string(), "b0".to_string()));
+        assert_eq!(requests[1], ("p2".to_string(), "b1".to_string()));
+        // p2 only has the blocks up to height 10, and p1 is then full
+        let to_p2 = requests.iter().filter(|(peer, _)| peer == "p2").count();
+        assert_eq!(to_p2, 5);
+        assert_eq!(requests.len(), MAX_BLOCKS_IN_FLIGHT + 5);
+        assert!(download.is_tracked("b5"));
+        assert!(download.is_tracked("b40"));
+
+        assert!(download.block_received("b0"));
+        assert!(!download.block_received("b0"));
+        assert_eq!(download.in_flight(), MAX_BLOCKS_IN_FLIGHT + 4);
+        assert_eq!(download.schedule(now).len(), 1);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut download = BlockDownload::new(String::new(), -1, 0);
+        download.enqueue(blocks(0, 1));
+        download.set_peer_height("p1", 1);
+        download.set_peer_height("p2", 1);
+        let start = Instant::now();
+        let requests = download.schedule(start);
+        assert_eq!(requests[0], ("p1".to_string(), "b0".to_string()));
+        assert_eq!(requests[1], ("p2".to_string(), "b1".to_string()));
+
+        assert_eq!(download.expire(start), 0);
+        let later = start + BLOCK_DOWNLOAD_TIMEOUT;
+        assert_eq!(download.expire(later), 2);
+        assert_eq!(download.in_flight(), 0);
+        // a block arriving after its request timed out is not requested again
+        assert!(download.block_received("b1"));
+        assert!(!download.is_tracked("b1"));
+        assert_eq!(download.pending(), 1);
+        download.enqueue(blocks(1, 1));
+        // each block goes to the peer that did not time out on it
+        let requests = download.schedule(later);
+        assert_eq!(requests[0], ("p2".to_string(), "b0".to_string()));
+        assert_eq!(requests[1], ("p1".to_string(), "b1".to_string()));
+
+        download.remove_peer("p2");
+        assert_eq!(download.in_flight(), 1);
+        assert_eq!(
+            download.schedule(later)[0],
+            ("p1".to_string(), "b0".to_string())
+        );
+
+        let mut time = later;
+        for _ in 1..MAX_DOWNLOAD_ATTEMPTS {
+            time += BLOCK_DOWNLOAD_TIMEOUT;
+            download.expire(time);
+            download.schedule(time);
+        }
+        assert_eq!(download.pending() + download.in_flight(), 0);
+    }
+}
@@ -1,8 +1,17 @@
 #![allow(non_snake_case)]
 
+mod amount;
 mod block;
 mod blockchain;
 mod cli;
+mod coinselect;
+mod download;
+mod mempool;
+mod miner;
+mod protocol;
+mod psbt;
+mod rpc;
+mod script;
 mod server;
 mod transaction;
 mod utxoset;
@@ -13,8 +22,44 @@ extern crate log;
 
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
+/// OpenDB returns the sled db at path, opened once per process and shared by
+/// all its users
+///
+/// sled locks the files of a db until its last handle is dropped, which its
+/// background flusher may delay, so reopening a db just dropped may fail
+pub fn open_db(path: &Path) -> Result<sled::Db> {
+    let mut dbs = open_dbs().lock().unwrap();
+    if let Some(db) = dbs.get(path) {
+        return Ok(db.clone());
+    }
+    let db = sled::open(path)?;
+    dbs.insert(path.to_path_buf(), db.clone());
+    Ok(db)
+}
+
+/// RemoveDB deletes the files under path, after forgetting the dbs opened
+/// there so that opening them again creates new ones
+pub fn remove_db(path: &Path) -> Result<()> {
+    open_dbs()
+        .lock()
+        .unwrap()
+        .retain(|db_path, _| !db_path.starts_with(path));
+    if path.exists() {
+        std::fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+fn open_dbs() -> &'static Mutex<HashMap<PathBuf, sled::Db>> {
+    static DBS: OnceLock<Mutex<HashMap<PathBuf, sled::Db>>> = OnceLock::new();
+    DBS.get_or_init(Default::default)
+}
+
 use crate::cli::Cli;
 use env_logger::Env;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
 fn main() {
     env_logger::from_env(Env::default().default_filter_or("warning")).init();
@@ -0,0 +1,531 @@
+//! memory pool of unconfirmed transactions
+//!
+//! A transaction enters the `Mempool` only once its inputs are found unspent,
+//! in the UTXO set or in the outputs of the transactions already in the pool,
+//! and its scripts verify. The pool remembers which transaction spends each
+//! outpoint, so a second transaction spending the same output is rejected.
+
+use super::*;
+use crate::amount::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::transaction::*;
+use crate::utxoset::*;
+use bincode::serialize;
+use failure::Fail;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// MAX_MEMPOOL_SIZE bounds the serialized size of the transactions in the pool
+pub const MAX_MEMPOOL_SIZE: usize = 5_000_000;
+/// BLOCK_RESERVED_SIZE is the room a block template keeps for its header and
+/// coinbase out of MAX_BLOCK_SIZE
+pub const BLOCK_RESERVED_SIZE: usize = 1_000;
+
+/// MempoolError is the reason a transaction is not admitted to the Mempool
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyKnown(String),
+    Coinbase(String),
+    NotFinal(String, i32),
+    MissingInput(String, String, i32),
+    Conflict(String, String),
+    ImmatureSpend(String, String),
+    BadTransaction(String, String),
+    Full(String),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown(id) => write!(f, "transaction {} is already known", id),
+            MempoolError::Coinbase(id) => write!(f, "transaction {} is a coinbase", id),
+            MempoolError::NotFinal(id, lock_time) => {
+                write!(f, "transaction {} is locked until height {}", id, lock_time)
+            }
+            MempoolError::MissingInput(id, txid, vout) => {
+                write!(
+                    f,
+                    "transaction {} spends missing output {}:{}",
+                    id, txid, vout
+                )
+            }
+            MempoolError::Conflict(id, other) => {
+                write!(f, "transaction {} spends an output of {}", id, other)
+            }
+            MempoolError::ImmatureSpend(id, txid) => {
+                write!(
+                    f,
+                    "transaction {} spends coinbase {} before it matured",
+                    id, txid
+                )
+            }
+            MempoolError::BadTransaction(id, reason) => {
+                write!(f, "transaction {} is invalid: {}", id, reason)
+            }
+            MempoolError::Full(id) => {
+                write!(f, "mempool is full, transaction {} pays too little", id)
+            }
+        }
+    }
+}
+
+impl Fail for MempoolError {}
+
+/// MempoolEntry is a transaction of the pool with what it pays to be mined
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    /// Size is the size of the serialized transaction
+    pub size: usize,
+    /// Sequence orders the entries as they were added, parents first
+    sequence: u64,
+}
+
+impl MempoolEntry {
+    /// CmpFeeRate compares the fee per byte of two entries
+    fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        (self.fee as u128 * other.size as u128).cmp(&(other.fee as u128 * self.size as u128))
+    }
+}
+
+/// Mempool keeps the valid transactions waiting for a block
+pub struct Mempool {
+    entries: HashMap<String, MempoolEntry>,
+    /// spent maps each outpoint spent in the pool to the transaction spending it
+    spent: HashMap<(String, i32), String>,
+    size: usize,
+    max_size: usize,
+    next_sequence: u64,
+}
+
+impl Mempool {
+    /// NewMempool creates an empty pool holding up to max_size bytes of transactions
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            size: 0,
+            max_size,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Size returns the serialized size of the transactions in the pool
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    /// SpentOutpoints returns the outputs, by txid and index, spent by the
+    /// transactions of the pool
+    pub fn spent_outpoints(&self) -> HashSet<(String, i32)> {
+        self.spent.keys().cloned().collect()
+    }
+
+    /// Add validates the transaction against the UTXO set and the pool, and
+    /// admits it
+    ///
+    /// When the pool grows above its size limit, the transactions paying the
+    /// lowest fee rate are evicted together with those spending their outputs
+    pub fn add(&mut self, tx: Transaction, utxo: &UTXOSet) -> Result<()> {
+        if self.entries.contains_key(&tx.id) {
+            return Err(MempoolError::AlreadyKnown(tx.id).into());
+        }
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase(tx.id).into());
+        }
+        if !tx.has_valid_id()? {
+            return Err(MempoolError::BadTransaction(tx.id, "id does not match".into()).into());
+        }
+        let height = utxo.blockchain.get_best_height()? + 1;
+        if !tx.is_final(height) {
+            return Err(MempoolError::NotFinal(tx.id, tx.lock_time).into());
+        }
+
+        let mut prev_TXs = HashMap::new();
+        let mut outpoints = HashSet::new();
+        for vin in &tx.vin {
+            let outpoint = (vin.txid.clone(), vin.vout);
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(MempoolError::BadTransaction(
+                    tx.id.clone(),
+                    "spends an output twice".into(),
+                )
+                .into());
+            }
+            if let Some(other) = self.spent.get(&outpoint) {
+                return Err(MempoolError::Conflict(tx.id.clone(), other.clone()).into());
+            }
+            let missing = || MempoolError::MissingInput(tx.id.clone(), vin.txid.clone(), vin.vout);
+
+            if let Some(parent) = self.entries.get(&vin.txid) {
+                if vin.vout < 0 || vin.vout as usize >= parent.tx.vout.len() {
+                    return Err(missing().into());
+                }
+                prev_TXs.insert(vin.txid.clone(), parent.tx.clone());
+                continue;
+            }
+            let outs = match utxo.get_outputs(&vin.txid)? {
+                Some(outs) if outs.outputs.contains_key(&vin.vout) => outs,
+                _ => return Err(missing().into()),
+            };
+            if outs.is_coinbase && !utxo.blockchain.params.is_mature(outs.height, height) {
+                return Err(MempoolError::ImmatureSpend(tx.id.clone(), vin.txid.clone()).into());
+            }
+            if !prev_TXs.contains_key(&vin.txid) {
+                let prev_TX = utxo.blockchain.find_transacton(&vin.txid)?;
+                prev_TXs.insert(vin.txid.clone(), prev_TX);
+            }
+        }
+
+        let fee = match tx.fee(&prev_TXs) {
+            Ok(fee) => fee,
+            Err(e) => return Err(MempoolError::BadTransaction(tx.id, e.to_string()).into()),
+        };
+        if !tx.verify(prev_TXs)? {
+            return Err(
+                MempoolError::BadTransaction(tx.id, "script verification failed".into()).into(),
+            );
+        }
+
+        let txid = tx.id.clone();
+        let size = serialize(&tx)?.len();
+        for vin in &tx.vin {
+            self.spent
+                .insert((vin.txid.clone(), vin.vout), txid.clone());
+        }
+        self.size += size;
+        self.entries.insert(
+            txid.clone(),
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                sequence: self.next_sequence,
+            },
+        );
+        self.next_sequence += 1;
+
+        self.trim();
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::Full(txid).into());
+        }
+        Ok(())
+    }
+
+    /// Trim evicts the transactions of the lowest fee rate, the newest first,
+    /// until the pool fits its size limit
+    fn trim(&mut self) {
+        while self.size > self.max_size {
+            let lowest = self
+                .entries
+                .values()
+                .min_by(|a, b| a.cmp_fee_rate(b).then(b.sequence.cmp(&a.sequence)))
+                .map(|entry| entry.tx.id.clone());
+            match lowest {
+                Some(txid) => {
+                    for tx in self.remove_with_descendants(&txid) {
+                        info!("evict transaction {} from the mempool", tx.id);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// RemoveEntry removes a transaction, leaving the transactions spending its outputs
+    fn remove_entry(&mut self, txid: &str) -> Option<Transaction> {
+        let entry = self.entries.remove(txid)?;
+        for vin in &entry.tx.vin {
+            self.spent.remove(&(vin.txid.clone(), vin.vout));
+        }
+        self.size -= entry.size;
+        Some(entry.tx)
+    }
+
+    /// RemoveWithDescendants removes a transaction and those spending its
+    /// outputs, directly or not, and returns them
+    pub fn remove_with_descendants(&mut self, txid: &str) -> Vec<Transaction> {
+        let mut removed = Vec::new();
+        let mut pending = vec![txid.to_string()];
+        while let Some(txid) = pending.pop() {
+            if let Some(tx) = self.remove_entry(&txid) {
+                for vout in 0..tx.vout.len() {
+                    if let Some(child) = self.spent.get(&(txid.clone(), vout as i32)) {
+                        pending.push(child.clone());
+                    }
+                }
+                removed.push(tx);
+            }
+        }
+        removed
+    }
+
+    /// RemoveForBlock removes the transactions mined in the block, and those
+    /// spending an output the block spends
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.get_transaction() {
+            if tx.is_coinbase() || self.remove_entry(&tx.id).is_some() {
+                continue;
+            }
+            for vin in &tx.vin {
+                if let Some(other) = self.spent.get(&(vin.txid.clone(), vin.vout)).cloned() {
+                    for removed in self.remove_with_descendants(&other) {
+                        info!(
+                            "remove transaction {} conflicting with block {}",
+                            removed.id,
+                            block.get_hash()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// ApplyChainUpdate moves the pool along a change of the active chain
+    ///
+    /// On a reorganization the transactions of the disconnected blocks are
+    /// added back, and the whole pool is validated again against the new chain
+    pub fn apply_chain_update(&mut self, update: &ChainUpdate, utxo: &UTXOSet) {
+        if update.disconnected.is_empty() {
+            for block in &update.connected {
+                self.remove_for_block(block);
+            }
+            return;
+        }
+
+        let mut txs: Vec<Transaction> = update
+            .disconnected
+            .iter()
+            .rev()
+            .flat_map(|block| block.get_transaction().iter().skip(1).cloned())
+            .collect();
+        let mut entries: Vec<MempoolEntry> = self.entries.drain().map(|(_, e)| e).collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        txs.extend(entries.into_iter().map(|entry| entry.tx));
+        self.spent.clear();
+        self.size = 0;
+
+        for tx in txs {
+            let txid = tx.id.clone();
+            if let Err(e) = self.add(tx, utxo) {
+                debug!("drop transaction {} after reorganization: {}", txid, e);
+            }
+        }
+    }
+
+    /// BlockTransactions returns transactions of the pool worth at most
+    /// max_size bytes, every transaction after those whose outputs it spends,
+    /// and the fees they pay
+    ///
+    /// The transactions are taken in one pass by the fee rate of their
+    /// ancestor package, the transaction with its unconfirmed ancestors, so
+    /// that a child paying a high fee brings its parents in. A package not
+    /// fitting in the room left is skipped for the smaller ones after it
+    pub fn block_transactions(&self, max_size: usize) -> Result<(Vec<Transaction>, Amount)> {
+        // parents enter the pool before their children
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        let mut ancestors: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for entry in &entries {
+            let mut set = HashSet::new();
+            for vin in &entry.tx.vin {
+                if let Some(parent) = self.entries.get(&vin.txid) {
+                    set.insert(parent.tx.id.as_str());
+                    set.extend(&ancestors[parent.tx.id.as_str()]);
+                }
+            }
+            ancestors.insert(entry.tx.id.as_str(), set);
+        }
+
+        // the fee and size of each package, with the fee rate ordering
+        let mut packages: Vec<(&MempoolEntry, u128, usize)> = entries
+            .iter()
+            .map(|entry| {
+                let package = &ancestors[entry.tx.id.as_str()];
+                let fee = package
+                    .iter()
+                    .map(|txid| self.entries[*txid].fee as u128)
+                    .sum::<u128>()
+                    + entry.fee as u128;
+                let size = package
+                    .iter()
+                    .map(|txid| self.entries[*txid].size)
+                    .sum::<usize>()
+                    + entry.size;
+                (*entry, fee, size)
+            })
+            .collect();
+        packages.sort_by(|(a, a_fee, a_size), (b, b_fee, b_size)| {
+            (b_fee * *a_size as u128)
+                .cmp(&(a_fee * *b_size as u128))
+                .then(a.sequence.cmp(&b.sequence))
+        });
+
+        let mut included = HashSet::new();
+        let mut txs = Vec::new();
+        let mut fees = 0;
+        let mut size = 0;
+        for (entry, _, _) in packages {
+            if included.contains(entry.tx.id.as_str()) {
+                continue;
+            }
+            let mut package: Vec<&MempoolEntry> = ancestors[entry.tx.id.as_str()]
+                .iter()
+                .filter(|txid| !included.contains(*txid))
+                .map(|txid| &self.entries[*txid])
+                .collect();
+            package.push(entry);
+            let package_size: usize = package.iter().map(|entry| entry.size).sum();
+            if size + package_size > max_size {
+                continue;
+            }
+            package.sort_by_key(|entry| entry.sequence);
+            for entry in package {
+                included.insert(entry.tx.id.as_str());
+                txs.push(entry.tx.clone());
+                fees = checked_add(fees, entry.fee)?;
+            }
+            size += package_size;
+        }
+        Ok((txs, fees))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::coinselect::*;
+    use crate::wallets::*;
+
+    #[test]
+    fn test_mempool() {
+        let data_dir = "data/test_mempool";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addr1 = ws.create_wallet().unwrap();
+        let addr2 = ws.create_wallet().unwrap();
+        let wallet1 = ws.get_wallet(&addr1).unwrap().clone();
+        let wallet2 = ws.get_wallet(&addr2).unwrap().clone();
+        let mut bc = Blockchain::create_blockchain(addr1.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let genesis = bc.tip.clone();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+        let mut mempool = Mempool::new(MAX_MEMPOOL_SIZE);
+
+        let tx1 = Transaction::new_UTXO(
+            &wallet1,
+            &addr2,
+            6 * COIN,
+            COIN / 10,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        mempool.add(tx1.clone(), &utxo_set).unwrap();
+        let err = mempool.add(tx1.clone(), &utxo_set).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::AlreadyKnown(_))
+        ));
+        let double = Transaction::new_UTXO(
+            &wallet1,
+            &addr1,
+            COIN,
+            0,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let err = mempool.add(double, &utxo_set).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::Conflict(_, other)) if *other == tx1.id
+        ));
+
+        // a child spending the unconfirmed output of tx1, paying a higher fee rate
+        let mut tx2 = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: tx1.id.clone(),
+                vout: 0,
+                script_sig: Default::default(),
+            }],
+            vout: vec![TXOutput::new(5 * COIN, addr1.clone()).unwrap()],
+            lock_time: 0,
+        };
+        tx2.id = tx2.hash().unwrap();
+        let mut prev_TXs = HashMap::new();
+        prev_TXs.insert(tx1.id.clone(), tx1.clone());
+        tx2.sign(&wallet2.secret_key, prev_TXs).unwrap();
+        mempool.add(tx2.clone(), &utxo_set).unwrap();
+
+        let (txs, fees) = mempool.block_transactions(MAX_BLOCK_SIZE).unwrap();
+        let txids: Vec<&str> = txs.iter().map(|tx| tx.id.as_str()).collect();
+        assert_eq!(txids, vec![tx1.id.as_str(), tx2.id.as_str()]);
+        assert_eq!(fees, COIN / 10 + COIN);
+        // the package of tx2 does not fit, tx1 alone does
+        let (txs, fees) = mempool.block_transactions(mempool.size() - 1).unwrap();
+        let txids: Vec<&str> = txs.iter().map(|tx| tx.id.as_str()).collect();
+        assert_eq!(txids, vec![tx1.id.as_str()]);
+        assert_eq!(fees, COIN / 10);
+
+        // evicting tx1 takes its child along
+        let mut small = Mempool::new(mempool.size() - 1);
+        small.add(tx1.clone(), &utxo_set).unwrap();
+        let err = small.add(tx2.clone(), &utxo_set).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MempoolError>(),
+            Some(MempoolError::Full(_))
+        ));
+        assert!(small.is_empty());
+        assert_eq!(small.size(), 0);
+
+        // mining tx1 leaves tx2, and disconnecting the block adds tx1 back
+        let mut utxo_set = utxo_set;
+        let cbtx =
+            Transaction::new_coinbase(addr1.clone(), String::new(), INITIAL_SUBSIDY + COIN / 10)
+                .unwrap();
+        let a1 = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx1.clone()])
+            .unwrap();
+        utxo_set.update(&a1).unwrap();
+        mempool.remove_for_block(&a1);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&tx2.id).is_some());
+
+        let mut tip = genesis;
+        for height in 1..3 {
+            let cbtx =
+                Transaction::new_coinbase(addr2.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            let block = Block::new_block(vec![cbtx], tip, height, INITIAL_BITS).unwrap();
+            tip = block.get_hash();
+            let update = utxo_set.blockchain.add_block(block).unwrap();
+            utxo_set.apply_chain_update(&update).unwrap();
+            mempool.apply_chain_update(&update, &utxo_set);
+        }
+        let (txs, _) = mempool.block_transactions(MAX_BLOCK_SIZE).unwrap();
+        let txids: Vec<&str> = txs.iter().map(|tx| tx.id.as_str()).collect();
+        assert_eq!(txids, vec![tx1.id.as_str(), tx2.id.as_str()]);
+    }
+}
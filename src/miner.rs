@@ -0,0 +1,140 @@
+//! background miner
+//!
+//! The miner runs in a thread of its own. It waits for work, asks the node for
+//! a block template, and searches its nonce on several worker threads without
+//! holding any lock of the node. The node wakes the miner when its mempool
+//! gains transactions, and cancels the search when the tip changes, so that
+//! a stale block is not mined on.
+
+use super::*;
+use crate::block::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+/// MinerHandle is how the node drives the miner and reads its hash rate
+#[derive(Clone, Default)]
+pub struct MinerHandle {
+    state: Arc<MinerState>,
+}
+
+#[derive(Default)]
+struct MinerState {
+    /// work tells whether something changed since the miner last looked
+    work: Mutex<bool>,
+    wakeup: Condvar,
+    cancel: AtomicBool,
+    hashes: AtomicU64,
+    hashrate: AtomicU64,
+    blocks: AtomicU64,
+}
+
+impl MinerHandle {
+    /// Notify wakes the miner to look for a new block template
+    pub fn notify(&self) {
+        *self.state.work.lock().unwrap() = true;
+        self.state.wakeup.notify_all();
+    }
+
+    /// Cancel aborts the nonce search of the current template and wakes the miner
+    pub fn cancel(&self) {
+        self.state.cancel.store(true, Ordering::Relaxed);
+        self.notify();
+    }
+
+    /// Hashrate returns the hashes per second of the last nonce search
+    pub fn hashrate(&self) -> u64 {
+        self.state.hashrate.load(Ordering::Relaxed)
+    }
+
+    /// BlocksMined returns the number of blocks found by the miner
+    pub fn blocks_mined(&self) -> u64 {
+        self.state.blocks.load(Ordering::Relaxed)
+    }
+
+    fn wait_for_work(&self) {
+        let mut work = self.state.work.lock().unwrap();
+        while !*work {
+            work = self.state.wakeup.wait(work).unwrap();
+        }
+        *work = false;
+    }
+}
+
+/// DefaultThreads returns the number of workers used when none is given
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Run mines blocks each time the miner is woken, until there is nothing left to mine
+///
+/// template returns the block to mine, None when there is nothing to mine, and
+/// submit takes the blocks found
+pub fn run<T, S>(handle: MinerHandle, threads: usize, template: T, submit: S)
+where
+    T: Fn() -> Result<Option<Block>>,
+    S: Fn(Block) -> Result<()>,
+{
+    info!("start miner with {} threads", threads);
+    let state = &handle.state;
+    loop {
+        handle.wait_for_work();
+        loop {
+            state.cancel.store(false, Ordering::Relaxed);
+            let mut block = match template() {
+                Ok(Some(block)) => block,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("cannot make a block template: {}", e);
+                    break;
+                }
+            };
+
+            state.hashes.store(0, Ordering::Relaxed);
+            let start = Instant::now();
+            let found =
+                match block.run_parallel_proof_of_work(threads, &state.cancel, &state.hashes) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        warn!("cannot mine block: {}", e);
+                        break;
+                    }
+                };
+            let elapsed = start.elapsed().as_secs_f64();
+            let hashes = state.hashes.load(Ordering::Relaxed);
+            if elapsed > 0.0 {
+                state
+                    .hashrate
+                    .store((hashes as f64 / elapsed) as u64, Ordering::Relaxed);
+            }
+
+            if !found {
+                info!(
+                    "stop mining block at height {} after {} hashes",
+                    block.get_height(),
+                    hashes
+                );
+                continue;
+            }
+            info!(
+                "mined block {} at height {}: {} hashes in {:.1}s, {} hashes/s",
+                block.get_hash(),
+                block.get_height(),
+                hashes,
+                elapsed,
+                handle.hashrate()
+            );
+            match submit(block) {
+                Ok(()) => {
+                    state.blocks.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    warn!("cannot submit mined block: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
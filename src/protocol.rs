@@ -0,0 +1,261 @@
+//! wire protocol of the p2p network
+//!
+//! Every message is sent as a frame made of a header followed by the payload:
+//!
+//! | field    | size | content                                        |
+//! |----------|------|------------------------------------------------|
+//! | magic    | 4    | `MAGIC`, identifies the network                |
+//! | version  | 1    | `PROTOCOL_VERSION`                             |
+//! | command  | 12   | ASCII command name, padded with zero bytes     |
+//! | length   | 4    | payload length, little endian                  |
+//! | checksum | 4    | first 4 bytes of sha256(sha256(payload))       |
+//!
+//! so that many messages can be sent one after another on the same connection.
+
+use super::*;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::Fail;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+
+/// MAGIC is the start of every frame of this network, distinct from the
+/// magic of Bitcoin networks so that frames of the two are never mistaken
+pub const MAGIC: [u8; 4] = [0xb1, 0x0c, 0xd3, 0x7e];
+/// PROTOCOL_VERSION is the version of the framing and of the message payloads
+pub const PROTOCOL_VERSION: u8 = 7;
+/// CMD_LEN is the size of the command field
+pub const CMD_LEN: usize = 12;
+/// HEADER_LEN is the size of a frame header
+pub const HEADER_LEN: usize = 4 + 1 + CMD_LEN + 4 + 4;
+/// MAX_PAYLOAD_LEN bounds the payload a peer may announce
+pub const MAX_PAYLOAD_LEN: u32 = 32 * 1024 * 1024;
+
+/// ProtocolError is the reason a frame cannot be decoded
+#[derive(Debug)]
+pub enum ProtocolError {
+    Truncated(usize, usize),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    BadCommand,
+    PayloadTooLarge(u32),
+    BadChecksum,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated(len, expected) => {
+                write!(f, "frame truncated: {} bytes of {}", len, expected)
+            }
+            ProtocolError::BadMagic(magic) => write!(f, "bad network magic {:x?}", magic),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ProtocolError::BadCommand => write!(f, "command is not ASCII"),
+            ProtocolError::PayloadTooLarge(len) => {
+                write!(f, "payload of {} bytes is too large", len)
+            }
+            ProtocolError::BadChecksum => write!(f, "payload checksum mismatch"),
+        }
+    }
+}
+
+impl Fail for ProtocolError {}
+
+/// Header is the decoded header of a frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub command: String,
+    pub length: u32,
+    pub checksum: [u8; 4],
+}
+
+/// Checksum returns the first 4 bytes of the double sha256 of the payload
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut hash: [u8; 32] = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result(&mut hash);
+    let mut hasher = Sha256::new();
+    hasher.input(&hash);
+    hasher.result(&mut hash);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// EncodeFrame builds the frame carrying payload for command
+pub fn encode_frame(command: &str, payload: &[u8]) -> Result<Vec<u8>> {
+    if command.len() > CMD_LEN || !command.is_ascii() {
+        return Err(ProtocolError::BadCommand.into());
+    }
+    if payload.len() > MAX_PAYLOAD_LEN as usize {
+        return Err(ProtocolError::PayloadTooLarge(payload.len() as u32).into());
+    }
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(PROTOCOL_VERSION);
+    let mut cmd = [0; CMD_LEN];
+    cmd[..command.len()].copy_from_slice(command.as_bytes());
+    frame.extend_from_slice(&cmd);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// DecodeHeader parses the header at the start of bytes
+pub fn decode_header(bytes: &[u8]) -> Result<Header> {
+    if bytes.len() < HEADER_LEN {
+        return Err(ProtocolError::Truncated(bytes.len(), HEADER_LEN).into());
+    }
+    let mut magic = [0; 4];
+    magic.copy_from_slice(&bytes[..4]);
+    if magic != MAGIC {
+        return Err(ProtocolError::BadMagic(magic).into());
+    }
+    if bytes[4] != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(bytes[4]).into());
+    }
+
+    let cmd_bytes = &bytes[5..5 + CMD_LEN];
+    let cmd_end = cmd_bytes.iter().position(|b| *b == 0).unwrap_or(CMD_LEN);
+    if !cmd_bytes[..cmd_end].is_ascii() || cmd_bytes[cmd_end..].iter().any(|b| *b != 0) {
+        return Err(ProtocolError::BadCommand.into());
+    }
+    let command = String::from_utf8(cmd_bytes[..cmd_end].to_vec())?;
+
+    let mut length = [0; 4];
+    length.copy_from_slice(&bytes[5 + CMD_LEN..9 + CMD_LEN]);
+    let length = u32::from_le_bytes(length);
+    if length > MAX_PAYLOAD_LEN {
+        return Err(ProtocolError::PayloadTooLarge(length).into());
+    }
+    let mut checksum = [0; 4];
+    checksum.copy_from_slice(&bytes[9 + CMD_LEN..HEADER_LEN]);
+
+    Ok(Header {
+        command,
+        length,
+        checksum,
+    })
+}
+
+/// WriteFrame sends one frame on the stream
+pub fn write_frame<W: Write>(stream: &mut W, command: &str, payload: &[u8]) -> Result<()> {
+    let frame = encode_frame(command, payload)?;
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// ReadFrame reads the next frame from the stream
+///
+/// It returns None when the peer closed the connection between two frames
+pub fn read_frame<R: Read>(stream: &mut R) -> Result<Option<(String, Vec<u8>)>> {
+    let mut header = [0; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match stream.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(ProtocolError::Truncated(read, HEADER_LEN).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let header = decode_header(&header)?;
+
+    let mut payload = vec![0; header.length as usize];
+    if let Err(e) = stream.read_exact(&mut payload) {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Err(ProtocolError::Truncated(HEADER_LEN, HEADER_LEN + payload.len()).into());
+        }
+        return Err(e.into());
+    }
+    if checksum(&payload) != header.checksum {
+        return Err(ProtocolError::BadChecksum.into());
+    }
+    Ok(Some((header.command, payload)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = encode_frame("version", b"payload").unwrap();
+        assert_eq!(frame.len(), HEADER_LEN + 7);
+        let header = decode_header(&frame).unwrap();
+        assert_eq!(header.command, "version");
+        assert_eq!(header.length, 7);
+
+        let mut stream = Vec::new();
+        write_frame(&mut stream, "inv", b"first").unwrap();
+        write_frame(&mut stream, "getblocks", b"").unwrap();
+        let mut stream = Cursor::new(stream);
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            Some((String::from("inv"), b"first".to_vec()))
+        );
+        assert_eq!(
+            read_frame(&mut stream).unwrap(),
+            Some((String::from("getblocks"), Vec::new()))
+        );
+        assert_eq!(read_frame(&mut stream).unwrap(), None);
+    }
+
+    fn read_error(bytes: &[u8]) -> failure::Error {
+        read_frame(&mut Cursor::new(bytes)).unwrap_err()
+    }
+
+    #[test]
+    fn test_frame_errors() {
+        let frame = encode_frame("tx", b"payload").unwrap();
+
+        let err = decode_header(&frame[..3]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Truncated(3, HEADER_LEN))
+        ));
+        let err = read_error(&frame[..3]);
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Truncated(3, HEADER_LEN))
+        ));
+        let err = read_error(&frame[..frame.len() - 1]);
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Truncated(_, _))
+        ));
+
+        // a frame of the Bitcoin mainnet
+        let mut bad = frame.clone();
+        bad[..4].copy_from_slice(&[0xf9, 0xbe, 0xb4, 0xd9]);
+        let err = read_error(&bad);
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::BadMagic(_))
+        ));
+
+        let mut bad = frame.clone();
+        bad[4] = PROTOCOL_VERSION + 1;
+        let err = read_error(&bad);
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::UnsupportedVersion(_))
+        ));
+
+        let mut bad = frame.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        let err = read_error(&bad);
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::BadChecksum)
+        ));
+
+        encode_frame("commandistoolong", b"").unwrap_err();
+    }
+}
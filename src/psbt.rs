@@ -0,0 +1,324 @@
+//! partially signed transactions
+//!
+//! A `Psbt` carries an unsigned transaction together with the transactions its
+//! inputs spend, so that it can be signed on a machine without the chain, by
+//! several parties whose signatures are combined before it is finalized into a
+//! transaction ready to be broadcast. The signature hash does not commit to
+//! the values spent, so a signer checks them against the ids of the previous
+//! transactions rather than trusting the outputs it is given.
+
+use super::*;
+use crate::amount::*;
+use crate::blockchain::*;
+use crate::script::*;
+use crate::transaction::*;
+use crate::wallets::*;
+use bincode::{deserialize, serialize};
+use crypto::ed25519;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// PSBT_MAGIC starts the serialization of a Psbt
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// PsbtInput is what a signer needs to know about an input
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    /// PrevTx is the transaction of the output spent by the input, whose id
+    /// commits to the value of the output
+    pub prev_tx: Transaction,
+    /// PrevOut is the output spent by the input
+    pub prev_out: TXOutput,
+    /// RedeemScript is the script hashing to the script hash of prev_out
+    pub redeem_script: Option<Script>,
+    /// PartialSigs are the signatures of the input by public key
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Psbt is a partially signed transaction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+impl PsbtInput {
+    /// ScriptCode is the script signed for the input
+    fn script_code(&self) -> &Script {
+        match &self.redeem_script {
+            Some(redeem_script) => redeem_script,
+            None => &self.prev_out.script_pubkey,
+        }
+    }
+
+    /// FinalScriptSig builds the script_sig from the partial signatures
+    fn final_script_sig(&self) -> Result<Script> {
+        let script_code = self.script_code();
+        if script_code.get_pub_key_hash().is_some() {
+            if let Some((pub_key, signature)) = self.partial_sigs.iter().next() {
+                return Ok(Script(vec![
+                    Op::Push(signature.clone()),
+                    Op::Push(pub_key.clone()),
+                ]));
+            }
+            return Err(format_err!("ERROR: input is not signed"));
+        }
+
+        let (m, pub_keys) = match (&self.redeem_script, script_code.get_multisig()) {
+            (Some(_), Some(multisig)) => multisig,
+            _ => return Err(format_err!("ERROR: cannot finalize this kind of script")),
+        };
+        let mut ops: Vec<Op> = pub_keys
+            .iter()
+            .filter_map(|pub_key| self.partial_sigs.get(pub_key))
+            .take(m)
+            .map(|signature| Op::Push(signature.clone()))
+            .collect();
+        if ops.len() < m {
+            return Err(format_err!(
+                "ERROR: input has {} of the {} signatures needed",
+                ops.len(),
+                m
+            ));
+        }
+        ops.push(Op::Push(serialize(script_code)?));
+        Ok(Script(ops))
+    }
+}
+
+impl Psbt {
+    /// NewPsbt creates a Psbt for an unsigned transaction spending outputs of the chain
+    pub fn new(tx: Transaction, bc: &Blockchain) -> Result<Psbt> {
+        let mut inputs = Vec::new();
+        for vin in &tx.vin {
+            let prev_TX = bc.find_transacton(&vin.txid)?;
+            let prev_out = match prev_TX.vout.get(vin.vout as usize) {
+                Some(prev_out) => prev_out.clone(),
+                None => return Err(format_err!("ERROR: Previous output is not found")),
+            };
+            inputs.push(PsbtInput {
+                prev_tx: prev_TX,
+                prev_out,
+                redeem_script: None,
+                partial_sigs: BTreeMap::new(),
+            });
+        }
+        Ok(Psbt { tx, inputs })
+    }
+
+    /// CheckInputs checks that every input comes with the transaction it
+    /// spends, whose id matches both the input and its content, and with the
+    /// very output of it that is spent
+    fn check_inputs(&self) -> Result<()> {
+        if self.inputs.len() != self.tx.vin.len() {
+            return Err(format_err!(
+                "ERROR: PSBT inputs do not match the transaction"
+            ));
+        }
+        for (in_id, (vin, input)) in self.tx.vin.iter().zip(&self.inputs).enumerate() {
+            if input.prev_tx.id != vin.txid || !input.prev_tx.has_valid_id()? {
+                return Err(format_err!(
+                    "ERROR: input {} does not come with the transaction it spends",
+                    in_id
+                ));
+            }
+            if input.prev_tx.vout.get(vin.vout as usize) != Some(&input.prev_out) {
+                return Err(format_err!(
+                    "ERROR: input {} does not come with the output it spends",
+                    in_id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fee returns the value of the inputs not paid to the outputs, as
+    /// committed to by the previous transactions
+    pub fn fee(&self) -> Result<Amount> {
+        self.check_inputs()?;
+        let prev_TXs: HashMap<String, Transaction> = self
+            .inputs
+            .iter()
+            .map(|input| (input.prev_tx.id.clone(), input.prev_tx.clone()))
+            .collect();
+        self.tx.fee(&prev_TXs)
+    }
+
+    /// AddRedeemScript sets the redeem script of the inputs spending outputs
+    /// paying to its hash
+    pub fn add_redeem_script(&mut self, redeem_script: &Script) -> Result<()> {
+        let script_hash = redeem_script.hash()?;
+        for input in &mut self.inputs {
+            if input.prev_out.script_pubkey.get_script_hash() == Some(script_hash.as_slice()) {
+                input.redeem_script = Some(redeem_script.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sign adds the signature of the wallet to the inputs it can sign and
+    /// returns how many it signed
+    ///
+    /// Nothing is signed unless every input comes with the transaction it spends
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<usize> {
+        self.check_inputs()?;
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut signed = 0;
+        for in_id in 0..self.inputs.len() {
+            let script_code = self.inputs[in_id].script_code().clone();
+            let can_sign = match script_code.get_multisig() {
+                Some((_, pub_keys)) => pub_keys.contains(&wallet.public_key),
+                None => script_code.get_pub_key_hash() == Some(pub_key_hash.as_slice()),
+            };
+            if !can_sign {
+                continue;
+            }
+            let hash = self.tx.signature_hash(in_id, &script_code)?;
+            let signature = ed25519::signature(hash.as_bytes(), &wallet.secret_key);
+            self.inputs[in_id]
+                .partial_sigs
+                .insert(wallet.public_key.clone(), signature.to_vec());
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// Combine adds the signatures of another Psbt of the same transaction
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.id != other.tx.id || self.inputs.len() != other.inputs.len() {
+            return Err(format_err!("ERROR: PSBTs are not for the same transaction"));
+        }
+        for (input, other) in self.inputs.iter_mut().zip(&other.inputs) {
+            if input.redeem_script.is_none() {
+                input.redeem_script = other.redeem_script.clone();
+            }
+            for (pub_key, signature) in &other.partial_sigs {
+                input
+                    .partial_sigs
+                    .insert(pub_key.clone(), signature.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Finalize builds the script_sig of every input and returns the signed transaction
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (in_id, input) in self.inputs.iter().enumerate() {
+            tx.vin[in_id].script_sig = input
+                .final_script_sig()
+                .map_err(|e| format_err!("input {}: {}", in_id, e))?;
+        }
+        for (in_id, input) in self.inputs.iter().enumerate() {
+            verify_script(
+                &tx.vin[in_id].script_sig,
+                &input.prev_out.script_pubkey,
+                &tx,
+                in_id,
+            )
+            .map_err(|e| format_err!("input {}: {}", in_id, e))?;
+        }
+        Ok(tx)
+    }
+
+    /// ToHex serializes the Psbt into the text kept in PSBT files
+    pub fn to_hex(&self) -> Result<String> {
+        let mut data = PSBT_MAGIC.to_vec();
+        data.append(&mut serialize(self)?);
+        Ok(hex::encode(data))
+    }
+
+    /// FromHex deserializes a Psbt written by ToHex
+    pub fn from_hex(text: &str) -> Result<Psbt> {
+        let data = hex::decode(text.trim())?;
+        if !data.starts_with(PSBT_MAGIC) {
+            return Err(format_err!("ERROR: not a PSBT"));
+        }
+        let psbt: Psbt = deserialize(&data[PSBT_MAGIC.len()..])?;
+        psbt.check_inputs()?;
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::coinselect::*;
+    use crate::utxoset::*;
+
+    #[test]
+    fn test_multisig_psbt() {
+        let data_dir = "data/test_psbt";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addrs: Vec<String> = (0..3).map(|_| ws.create_wallet().unwrap()).collect();
+        let wallets: Vec<Wallet> = addrs
+            .iter()
+            .map(|addr| ws.get_wallet(addr).unwrap().clone())
+            .collect();
+        let pub_keys: Vec<Vec<u8>> = wallets.iter().map(|w| w.public_key.clone()).collect();
+        let multisig = ws.add_multisig(2, &pub_keys).unwrap();
+        let redeem_script = ws.get_redeem_script(&multisig).unwrap().clone();
+
+        let mut bc = Blockchain::create_blockchain(addrs[0].clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+        let tx = Transaction::new_UTXO(
+            &wallets[0],
+            &multisig,
+            6 * COIN,
+            0,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let cbtx =
+            Transaction::new_coinbase(addrs[0].clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.update(&block).unwrap();
+
+        let script_hash = redeem_script.hash().unwrap();
+        let to = TXOutput::new(4 * COIN, addrs[2].clone()).unwrap();
+        let tx = Transaction::new_unsigned(
+            &script_hash,
+            Script::pay_to_script_hash(&script_hash),
+            vec![to],
+            COIN,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let mut psbt = Psbt::new(tx, &utxo_set.blockchain).unwrap();
+        psbt.add_redeem_script(&redeem_script).unwrap();
+        let text = psbt.to_hex().unwrap();
+
+        let mut psbt1 = Psbt::from_hex(&text).unwrap();
+        assert_eq!(psbt1.fee().unwrap(), COIN);
+        // an output claiming more value than its transaction is not signed
+        let mut forged = psbt1.clone();
+        forged.inputs[0].prev_out.value += COIN;
+        forged.sign(&wallets[0]).unwrap_err();
+        Psbt::from_hex(&forged.to_hex().unwrap()).unwrap_err();
+        forged.inputs[0].prev_tx.vout[forged.tx.vin[0].vout as usize].value += COIN;
+        forged.sign(&wallets[0]).unwrap_err();
+        assert_eq!(psbt1.sign(&wallets[0]).unwrap(), 1);
+        psbt1.finalize().unwrap_err();
+        let mut psbt2 = Psbt::from_hex(&text).unwrap();
+        assert_eq!(psbt2.sign(&wallets[2]).unwrap(), 1);
+
+        psbt1.combine(&psbt2).unwrap();
+        let tx = psbt1.finalize().unwrap();
+        assert!(utxo_set.blockchain.verify_transacton(&tx).unwrap());
+        assert_eq!(utxo_set.blockchain.get_fee(&tx).unwrap(), COIN);
+
+        Psbt::from_hex("00").unwrap_err();
+    }
+}
@@ -0,0 +1,459 @@
+//! JSON-RPC over HTTP
+//!
+//! A small JSON-RPC 2.0 endpoint: every request is an HTTP POST whose body is
+//! `{"jsonrpc": "2.0", "method": ..., "params": [...], "id": ...}`. The methods
+//! themselves are provided by the caller of `serve`.
+//!
+//! Requests must carry the credentials of the node with HTTP Basic
+//! authentication, either the `--rpcuser` and `--rpcpassword` it was started
+//! with, or `__cookie__` and the password written to the cookie file of its
+//! data dir.
+
+use super::*;
+use crate::amount::*;
+use crypto::util::fixed_time_eq;
+use failure::format_err;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// MAX_BODY_LEN bounds the size of a request body
+const MAX_BODY_LEN: usize = 4 * 1024 * 1024;
+/// MAX_HEADERS_LEN bounds the size of the request line and headers together
+const MAX_HEADERS_LEN: usize = 8 * 1024;
+/// MAX_CONNECTIONS bounds the connections served at once, others are refused
+const MAX_CONNECTIONS: usize = 16;
+/// RPC_TIMEOUT is how long a read or a write on a connection may block
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+/// COOKIE_FILE is the file of the data dir holding the credentials of the
+/// node when it is not given an RPC user and password
+pub const COOKIE_FILE: &str = ".cookie";
+/// COOKIE_USER is the user name of the credentials of the cookie file
+const COOKIE_USER: &str = "__cookie__";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// MISC_ERROR is returned when a method fails for any other reason
+pub const MISC_ERROR: i64 = -1;
+/// WALLET_UNLOCK_NEEDED is returned when a method needs the wallet passphrase
+pub const WALLET_UNLOCK_NEEDED: i64 = -13;
+
+/// RpcError is the error object of a JSON-RPC response
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> RpcError {
+        RpcError::new(MISC_ERROR, &e.to_string())
+    }
+}
+
+pub type RpcResult = std::result::Result<Value, RpcError>;
+
+/// HttpRequest is the body of an HTTP request with its Basic credentials
+#[derive(Debug)]
+struct HttpRequest {
+    body: Vec<u8>,
+    credentials: Option<Vec<u8>>,
+}
+
+/// WriteCookie writes new random credentials to the cookie file of data_dir,
+/// and returns them as user:password
+pub fn write_cookie(data_dir: &Path) -> Result<String> {
+    let mut password = [0; 32];
+    rand::OsRng::new()?.fill_bytes(&mut password);
+    let credentials = format!("{}:{}", COOKIE_USER, hex::encode(password));
+    fs::write(data_dir.join(COOKIE_FILE), &credentials)?;
+    Ok(credentials)
+}
+
+/// Serve answers JSON-RPC requests on address, one thread per connection up
+/// to MAX_CONNECTIONS
+///
+/// Requests not authenticated with credentials, given as user:password, are
+/// rejected
+pub fn serve<F>(address: &str, credentials: &str, handler: F) -> Result<()>
+where
+    F: Fn(&str, &[Value]) -> RpcResult + Send + Sync + 'static,
+{
+    if credentials.is_empty() {
+        return Err(format_err!("RPC credentials are empty"));
+    }
+    let listener = TcpListener::bind(address)?;
+    info!("RPC server listen at {}", address);
+    let handler = Arc::new(handler);
+    let credentials = Arc::new(credentials.as_bytes().to_vec());
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+        stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            warn!("too many RPC connections, refuse {}", stream.peer_addr()?);
+            write_http_status(stream, "503 Service Unavailable", "").ok();
+            continue;
+        }
+        let handler = Arc::clone(&handler);
+        let credentials = Arc::clone(&credentials);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &credentials, handler.as_ref()) {
+                warn!("RPC connection failed: {}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection<F>(stream: TcpStream, credentials: &[u8], handler: &F) -> Result<()>
+where
+    F: Fn(&str, &[Value]) -> RpcResult,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_http_request(&mut reader) {
+        Ok(request) => {
+            if !is_authorized(&request, credentials) {
+                warn!("RPC request from {} is not authorized", stream.peer_addr()?);
+                return write_http_status(
+                    stream,
+                    "401 Unauthorized",
+                    "WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n",
+                );
+            }
+            handle_request(&request.body, handler)
+        }
+        Err(e) => error_response(Value::Null, RpcError::new(INVALID_REQUEST, &e.to_string())),
+    };
+    write_http_response(stream, &response)
+}
+
+/// IsAuthorized tells whether a request carries the given credentials
+fn is_authorized(request: &HttpRequest, credentials: &[u8]) -> bool {
+    match &request.credentials {
+        Some(given) => fixed_time_eq(given, credentials),
+        None => false,
+    }
+}
+
+/// ReadHttpRequest reads an HTTP request and returns its body and credentials
+fn read_http_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest> {
+    let mut headers_left = MAX_HEADERS_LEN;
+    let mut line = String::new();
+    read_header_line(reader, &mut line, &mut headers_left)?;
+    if !line.starts_with("POST ") {
+        return Err(format_err!("only POST requests are supported"));
+    }
+
+    let mut content_length = None;
+    let mut credentials = None;
+    loop {
+        line.clear();
+        if read_header_line(reader, &mut line, &mut headers_left)? == 0 {
+            return Err(format_err!("unexpected end of request headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(idx) = header.find(':') {
+            let name = &header[..idx];
+            let value = header[idx + 1..].trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(value.parse::<usize>()?);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                credentials = parse_basic_auth(value);
+            }
+        }
+    }
+
+    let len = content_length.ok_or_else(|| format_err!("missing Content-Length"))?;
+    if len > MAX_BODY_LEN {
+        return Err(format_err!("request body of {} bytes is too large", len));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest { body, credentials })
+}
+
+/// ReadHeaderLine reads a line of the request headers, of which there are
+/// headers_left bytes left to read
+fn read_header_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    headers_left: &mut usize,
+) -> Result<usize> {
+    let len = reader.by_ref().take(*headers_left as u64).read_line(line)?;
+    *headers_left -= len;
+    if *headers_left == 0 && !line.ends_with('\n') {
+        return Err(format_err!(
+            "request headers are larger than {} bytes",
+            MAX_HEADERS_LEN
+        ));
+    }
+    Ok(len)
+}
+
+/// ParseBasicAuth returns the credentials of the value of a Basic
+/// Authorization header
+fn parse_basic_auth(value: &str) -> Option<Vec<u8>> {
+    let (scheme, encoded) = value.split_at(value.find(' ')?);
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    decode_base64(encoded.trim())
+}
+
+/// DecodeBase64 decodes padded standard base64
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = encoded.iter().rev().take_while(|c| **c == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    for chunk in encoded.chunks(4) {
+        let mut group = 0u32;
+        for c in chunk {
+            let value = match c {
+                b'=' => 0,
+                _ => ALPHABET.iter().position(|a| a == c)? as u32,
+            };
+            group = group << 6 | value;
+        }
+        decoded.extend_from_slice(&group.to_be_bytes()[1..]);
+    }
+    let unpadded = &encoded[..encoded.len() - padding];
+    if unpadded.contains(&b'=') {
+        return None;
+    }
+    decoded.truncate(decoded.len() - padding);
+    Some(decoded)
+}
+
+/// WriteHttpStatus writes an HTTP response without a body, with the extra
+/// headers given as lines ending with CRLF
+fn write_http_status(mut stream: TcpStream, status: &str, headers: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, headers
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn write_http_response(mut stream: TcpStream, response: &Value) -> Result<()> {
+    let body = response.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// HandleRequest parses a JSON-RPC request, calls the handler and builds the response
+pub fn handle_request<F>(body: &[u8], handler: &F) -> Value
+where
+    F: Fn(&str, &[Value]) -> RpcResult,
+{
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error_response(Value::Null, RpcError::new(PARSE_ERROR, &e.to_string())),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error_response(id, RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+    let params = match request.get("params") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => {
+            return error_response(id, RpcError::new(INVALID_PARAMS, "params must be an array"))
+        }
+    };
+
+    info!("RPC request: {} {:?}", method, params);
+    match handler(method, &params) {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(e) => error_response(id, e),
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": {"code": e.code, "message": e.message},
+        "id": id,
+    })
+}
+
+/// ParamStr returns the string parameter at index i
+pub fn param_str(params: &[Value], i: usize) -> std::result::Result<&str, RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, &format!("param {} must be a string", i)))
+}
+
+/// ParamI64 returns the integer parameter at index i
+pub fn param_i64(params: &[Value], i: usize) -> std::result::Result<i64, RpcError> {
+    params
+        .get(i)
+        .and_then(Value::as_i64)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, &format!("param {} must be an integer", i)))
+}
+
+/// AsAmount returns the amount of a JSON integer number of units, or of a
+/// string of decimal coins as the CLI takes them
+pub fn as_amount(value: &Value) -> Option<Amount> {
+    match value {
+        Value::String(coins) => parse_amount(coins).ok(),
+        _ => value.as_u64().filter(|value| money_range(*value)),
+    }
+}
+
+/// ParamAmount returns the amount parameter at index i, in units
+pub fn param_amount(params: &[Value], i: usize) -> std::result::Result<Amount, RpcError> {
+    params.get(i).and_then(as_amount).ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            &format!(
+                "param {} must be an amount in units or a string of coins",
+                i
+            ),
+        )
+    })
+}
+
+/// ParamAmountOr returns the optional amount parameter at index i, or default
+pub fn param_amount_or(
+    params: &[Value],
+    i: usize,
+    default: Amount,
+) -> std::result::Result<Amount, RpcError> {
+    match params.get(i) {
+        None | Some(Value::Null) => Ok(default),
+        Some(_) => param_amount(params, i),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn handler(method: &str, params: &[Value]) -> RpcResult {
+        match method {
+            "echo" => Ok(json!(param_str(params, 0)?)),
+            "fail" => Err(format_err!("failed").into()),
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+        }
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let res = handle_request(
+            br#"{"jsonrpc":"2.0","method":"echo","params":["hi"],"id":1}"#,
+            &handler,
+        );
+        assert_eq!(res, json!({"jsonrpc": "2.0", "result": "hi", "id": 1}));
+
+        let res = handle_request(br#"{"method":"echo","params":[1],"id":2}"#, &handler);
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+        assert_eq!(res["id"], 2);
+
+        let res = handle_request(br#"{"method":"fail","id":3}"#, &handler);
+        assert_eq!(res["error"]["code"], MISC_ERROR);
+        assert_eq!(res["error"]["message"], "failed");
+
+        let res = handle_request(br#"{"method":"nope","id":4}"#, &handler);
+        assert_eq!(res["error"]["code"], METHOD_NOT_FOUND);
+
+        let res = handle_request(b"{not json", &handler);
+        assert_eq!(res["error"]["code"], PARSE_ERROR);
+        assert_eq!(res["id"], Value::Null);
+    }
+
+    #[test]
+    fn test_read_http_request() {
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 4\r\n\r\nbodyextra";
+        let request = read_http_request(&mut Cursor::new(request)).unwrap();
+        assert_eq!(request.body, b"body");
+        assert!(!is_authorized(&request, b"user:pass"));
+
+        read_http_request(&mut Cursor::new("GET / HTTP/1.1\r\n\r\n")).unwrap_err();
+        read_http_request(&mut Cursor::new("POST / HTTP/1.1\r\n\r\n")).unwrap_err();
+
+        // dXNlcjpwYXNz is user:pass
+        let request =
+            "POST / HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\nContent-Length: 0\r\n\r\n";
+        let request = read_http_request(&mut Cursor::new(request)).unwrap();
+        assert!(is_authorized(&request, b"user:pass"));
+        assert!(!is_authorized(&request, b"user:other"));
+
+        let long_header = format!("X-Pad: {}\r\n", "a".repeat(MAX_HEADERS_LEN));
+        let request = format!(
+            "POST / HTTP/1.1\r\n{}Content-Length: 0\r\n\r\n",
+            long_header
+        );
+        read_http_request(&mut Cursor::new(request)).unwrap_err();
+        let request = format!("POST {} HTTP/1.1\r\n", "a".repeat(MAX_HEADERS_LEN));
+        read_http_request(&mut Cursor::new(request)).unwrap_err();
+    }
+
+    #[test]
+    fn test_as_amount() {
+        assert_eq!(as_amount(&json!(150_000_000)), Some(150_000_000));
+        assert_eq!(as_amount(&json!("1.5")), Some(150_000_000));
+        assert_eq!(as_amount(&json!(MAX_MONEY + 1)), None);
+        assert_eq!(as_amount(&json!("1.000000001")), None);
+        assert_eq!(as_amount(&json!(1.5)), None);
+        assert_eq!(as_amount(&json!(-1)), None);
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64("Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64("Zm9v").unwrap(), b"foo");
+        assert!(decode_base64("Zm9").is_none());
+        assert!(decode_base64("Z===").is_none());
+        assert!(decode_base64("Zg=v").is_none());
+        assert!(decode_base64("Zm9*").is_none());
+        assert_eq!(parse_basic_auth("basic Zm9v").unwrap(), b"foo");
+        assert!(parse_basic_auth("Bearer Zm9v").is_none());
+    }
+}
@@ -0,0 +1,510 @@
+//! locking scripts
+//!
+//! Outputs are locked by a `script_pubkey` and spent by an input whose
+//! `script_sig` satisfies it. Both are run on the same stack, the `script_sig`
+//! first, and the spend is valid when the `script_pubkey` leaves a true value
+//! on top of the stack.
+//!
+//! An output paying to a script hash is spent by a `script_sig` whose last push
+//! is the serialized redeem script hashing to it, the redeem script is then run
+//! on the rest of the stack.
+
+use super::*;
+use crate::transaction::*;
+use crate::wallets::*;
+use bincode::{deserialize, serialize};
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use failure::Fail;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// MAX_SCRIPT_OPS bounds the number of operations of a script
+pub const MAX_SCRIPT_OPS: usize = 201;
+/// MAX_STACK_SIZE bounds the number of items on the stack
+pub const MAX_STACK_SIZE: usize = 1000;
+/// MAX_MULTISIG_KEYS bounds the number of keys of CheckMultiSig
+pub const MAX_MULTISIG_KEYS: usize = 20;
+
+/// Op is a script operation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Op {
+    /// Push pushes data on the stack
+    Push(Vec<u8>),
+    /// Dup duplicates the top item
+    Dup,
+    /// Drop removes the top item
+    Drop,
+    /// Equal pushes whether the two top items are equal
+    Equal,
+    /// EqualVerify fails unless the two top items are equal
+    EqualVerify,
+    /// Verify fails unless the top item is true
+    Verify,
+    /// Hash160 replaces the top item with its ripemd160(sha256()), as for addresses
+    Hash160,
+    /// Sha256 replaces the top item with its sha256
+    Sha256,
+    /// CheckSig pops a public key and a signature and pushes whether the signature is valid
+    CheckSig,
+    /// CheckSigVerify fails unless the signature is valid
+    CheckSigVerify,
+    /// CheckMultiSig pops `n`, n public keys, `m` and m signatures and pushes
+    /// whether the signatures match m of the keys, in order
+    CheckMultiSig,
+    /// CheckLockTimeVerify fails unless the lock time of the transaction is at
+    /// least the height on top of the stack, which is kept
+    CheckLockTimeVerify,
+}
+
+/// Script is a list of operations
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Script(pub Vec<Op>);
+
+/// ScriptError is the reason a script fails
+#[derive(Debug)]
+pub enum ScriptError {
+    NotPushOnly,
+    TooManyOps(usize),
+    StackUnderflow,
+    StackOverflow,
+    BadNumber,
+    VerifyFailed,
+    BadMultisig,
+    BadRedeemScript,
+    LockTime(i64, i32),
+    EvalFalse,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::NotPushOnly => write!(f, "script_sig may only push data"),
+            ScriptError::TooManyOps(len) => write!(f, "script of {} operations is too long", len),
+            ScriptError::StackUnderflow => write!(f, "operation on an empty stack"),
+            ScriptError::StackOverflow => write!(f, "stack is too large"),
+            ScriptError::BadNumber => write!(f, "stack item is not a number"),
+            ScriptError::VerifyFailed => write!(f, "verify operation failed"),
+            ScriptError::BadMultisig => write!(f, "bad number of multisig keys or signatures"),
+            ScriptError::BadRedeemScript => write!(f, "redeem script cannot be decoded"),
+            ScriptError::LockTime(height, lock_time) => write!(
+                f,
+                "output is locked until height {}, transaction lock time is {}",
+                height, lock_time
+            ),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+        }
+    }
+}
+
+impl Fail for ScriptError {}
+
+impl Script {
+    /// PayToPubKeyHash locks an output to the owner of the key hashing to pub_key_hash
+    pub fn pay_to_pub_key_hash(pub_key_hash: &[u8]) -> Script {
+        Script(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(pub_key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// HashLock locks an output to the owner of pub_key_hash who also reveals
+    /// the preimage of the sha256 hash
+    ///
+    /// It is spent with `<signature> <pub key> <preimage>`
+    pub fn hash_lock(hash: &[u8], pub_key_hash: &[u8]) -> Script {
+        let mut ops = vec![Op::Sha256, Op::Push(hash.to_vec()), Op::EqualVerify];
+        ops.append(&mut Script::pay_to_pub_key_hash(pub_key_hash).0);
+        Script(ops)
+    }
+
+    /// TimeLock locks an output to the owner of pub_key_hash until the block
+    /// at height, the spending transaction needs a lock time of at least height
+    pub fn time_lock(height: i32, pub_key_hash: &[u8]) -> Script {
+        let mut ops = vec![
+            Op::Push(encode_num(height as i64)),
+            Op::CheckLockTimeVerify,
+            Op::Drop,
+        ];
+        ops.append(&mut Script::pay_to_pub_key_hash(pub_key_hash).0);
+        Script(ops)
+    }
+
+    /// Multisig is the redeem script of outputs spendable by m of the pub_keys
+    ///
+    /// It is spent with the signatures of the keys, in the order of the keys
+    pub fn multisig(m: usize, pub_keys: &[Vec<u8>]) -> Script {
+        let mut ops = vec![Op::Push(encode_num(m as i64))];
+        for pub_key in pub_keys {
+            ops.push(Op::Push(pub_key.clone()));
+        }
+        ops.push(Op::Push(encode_num(pub_keys.len() as i64)));
+        ops.push(Op::CheckMultiSig);
+        Script(ops)
+    }
+
+    /// PayToScriptHash locks an output to the redeem script hashing to script_hash
+    pub fn pay_to_script_hash(script_hash: &[u8]) -> Script {
+        Script(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    /// Hash returns the hash of the script used by PayToScriptHash
+    pub fn hash(&self) -> Result<Vec<u8>> {
+        let mut data = serialize(self)?;
+        hash_pub_key(&mut data);
+        Ok(data)
+    }
+
+    /// GetMultisig returns m and the keys of a multisig script
+    pub fn get_multisig(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let (last, ops) = self.0.split_last()?;
+        if *last != Op::CheckMultiSig || ops.len() < 2 {
+            return None;
+        }
+        let mut items = Vec::new();
+        for op in ops {
+            match op {
+                Op::Push(data) => items.push(data),
+                _ => return None,
+            }
+        }
+        let m = decode_num(items[0]).ok()?;
+        let n = decode_num(items[items.len() - 1]).ok()?;
+        let pub_keys: Vec<Vec<u8>> = items[1..items.len() - 1]
+            .iter()
+            .map(|k| k.to_vec())
+            .collect();
+        if n as usize != pub_keys.len() || m < 0 || m > n {
+            return None;
+        }
+        Some((m as usize, pub_keys))
+    }
+
+    /// GetScriptHash returns the script hash of a pay to script hash script
+    pub fn get_script_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Hash160, Op::Push(script_hash), Op::Equal] => Some(script_hash),
+            _ => None,
+        }
+    }
+
+    /// GetPubKeyHash returns the key hash of a pay to pub key hash script
+    pub fn get_pub_key_hash(&self) -> Option<&[u8]> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(pub_key_hash), Op::EqualVerify, Op::CheckSig] => {
+                Some(pub_key_hash)
+            }
+            _ => None,
+        }
+    }
+
+    /// IsPushOnly checks that the script only pushes data
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, Op::Push(_)))
+    }
+}
+
+/// EncodeNum encodes a number pushed on the stack
+pub fn encode_num(n: i64) -> Vec<u8> {
+    n.to_le_bytes().to_vec()
+}
+
+/// DecodeNum decodes a number from a stack item, the empty item is 0
+fn decode_num(item: &[u8]) -> Result<i64> {
+    match item.len() {
+        0 => Ok(0),
+        8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(item);
+            Ok(i64::from_le_bytes(bytes))
+        }
+        _ => Err(ScriptError::BadNumber.into()),
+    }
+}
+
+fn is_true(item: &[u8]) -> bool {
+    item.iter().any(|b| *b != 0)
+}
+
+fn bool_item(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack
+        .pop()
+        .ok_or_else(|| ScriptError::StackUnderflow.into())
+}
+
+/// VerifyScript checks that script_sig of input in_id of tx satisfies script_pubkey
+pub fn verify_script(
+    script_sig: &Script,
+    script_pubkey: &Script,
+    tx: &Transaction,
+    in_id: usize,
+) -> Result<()> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::NotPushOnly.into());
+    }
+    let mut stack = Vec::new();
+    eval(script_sig, &mut stack, tx, in_id)?;
+    let redeem_stack = if script_pubkey.get_script_hash().is_some() {
+        Some(stack.clone())
+    } else {
+        None
+    };
+    eval(script_pubkey, &mut stack, tx, in_id)?;
+    check_top(&stack)?;
+
+    if let Some(mut stack) = redeem_stack {
+        let redeem_script: Script =
+            deserialize(&pop(&mut stack)?).map_err(|_| ScriptError::BadRedeemScript)?;
+        eval(&redeem_script, &mut stack, tx, in_id)?;
+        check_top(&stack)?;
+    }
+    Ok(())
+}
+
+fn check_top(stack: &[Vec<u8>]) -> Result<()> {
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse.into()),
+    }
+}
+
+/// Eval runs a script on the stack
+///
+/// Signatures are checked against the signature hash of input in_id of tx
+/// with script as its script code
+fn eval(script: &Script, stack: &mut Vec<Vec<u8>>, tx: &Transaction, in_id: usize) -> Result<()> {
+    if script.0.len() > MAX_SCRIPT_OPS {
+        return Err(ScriptError::TooManyOps(script.0.len()).into());
+    }
+
+    for op in &script.0 {
+        match op {
+            Op::Push(data) => stack.push(data.clone()),
+            Op::Dup => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            }
+            Op::Drop => {
+                pop(stack)?;
+            }
+            Op::Equal | Op::EqualVerify => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                if *op == Op::EqualVerify {
+                    if a != b {
+                        return Err(ScriptError::VerifyFailed.into());
+                    }
+                } else {
+                    stack.push(bool_item(a == b));
+                }
+            }
+            Op::Verify => {
+                if !is_true(&pop(stack)?) {
+                    return Err(ScriptError::VerifyFailed.into());
+                }
+            }
+            Op::Hash160 => {
+                let mut item = pop(stack)?;
+                hash_pub_key(&mut item);
+                stack.push(item);
+            }
+            Op::Sha256 => {
+                let item = pop(stack)?;
+                let mut hash = vec![0; 32];
+                let mut hasher = Sha256::new();
+                hasher.input(&item);
+                hasher.result(&mut hash);
+                stack.push(hash);
+            }
+            Op::CheckSig | Op::CheckSigVerify => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = check_sig(&signature, &pub_key, tx, in_id, script)?;
+                if *op == Op::CheckSigVerify {
+                    if !valid {
+                        return Err(ScriptError::VerifyFailed.into());
+                    }
+                } else {
+                    stack.push(bool_item(valid));
+                }
+            }
+            Op::CheckMultiSig => {
+                let n = decode_num(&pop(stack)?)?;
+                if n < 0 || n as usize > MAX_MULTISIG_KEYS {
+                    return Err(ScriptError::BadMultisig.into());
+                }
+                let mut pub_keys = Vec::new();
+                for _ in 0..n {
+                    pub_keys.push(pop(stack)?);
+                }
+                pub_keys.reverse();
+                let m = decode_num(&pop(stack)?)?;
+                if m < 0 || m > n {
+                    return Err(ScriptError::BadMultisig.into());
+                }
+                let mut signatures = Vec::new();
+                for _ in 0..m {
+                    signatures.push(pop(stack)?);
+                }
+                signatures.reverse();
+
+                let mut keys = pub_keys.iter();
+                let mut valid = true;
+                for signature in &signatures {
+                    loop {
+                        match keys.next() {
+                            Some(pub_key) => {
+                                if check_sig(signature, pub_key, tx, in_id, script)? {
+                                    break;
+                                }
+                            }
+                            None => {
+                                valid = false;
+                                break;
+                            }
+                        }
+                    }
+                }
+                stack.push(bool_item(valid));
+            }
+            Op::CheckLockTimeVerify => {
+                let height = decode_num(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                if height < 0 || (tx.lock_time as i64) < height {
+                    return Err(ScriptError::LockTime(height, tx.lock_time).into());
+                }
+            }
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow.into());
+        }
+    }
+    Ok(())
+}
+
+fn check_sig(
+    signature: &[u8],
+    pub_key: &[u8],
+    tx: &Transaction,
+    in_id: usize,
+    script_code: &Script,
+) -> Result<bool> {
+    if signature.len() != 64 || pub_key.len() != 32 {
+        return Ok(false);
+    }
+    let hash = tx.signature_hash(in_id, script_code)?;
+    Ok(ed25519::verify(hash.as_bytes(), pub_key, signature))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::ed25519;
+
+    fn spending_tx(lock_time: i32) -> Transaction {
+        Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::from("prev"),
+                vout: 0,
+                script_sig: Script::default(),
+            }],
+            vout: Vec::new(),
+            lock_time,
+        }
+    }
+
+    fn sign(tx: &Transaction, secret_key: &[u8], script_pubkey: &Script) -> Vec<u8> {
+        let hash = tx.signature_hash(0, script_pubkey).unwrap();
+        ed25519::signature(hash.as_bytes(), secret_key).to_vec()
+    }
+
+    #[test]
+    fn test_scripts() {
+        let (secret_key, pub_key) = ed25519::keypair(&[1; 32]);
+        let mut pub_key_hash = pub_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+
+        // pay to pub key hash
+        let mut tx = spending_tx(0);
+        let script_pubkey = Script::pay_to_pub_key_hash(&pub_key_hash);
+        assert_eq!(
+            script_pubkey.get_pub_key_hash(),
+            Some(pub_key_hash.as_slice())
+        );
+        let sig = sign(&tx, &secret_key, &script_pubkey);
+        tx.vin[0].script_sig = Script(vec![Op::Push(sig.clone()), Op::Push(pub_key.to_vec())]);
+        verify_script(&tx.vin[0].script_sig, &script_pubkey, &tx, 0).unwrap();
+        let bad = Script(vec![Op::Push(sig), Op::Push(vec![0; 32])]);
+        verify_script(&bad, &script_pubkey, &tx, 0).unwrap_err();
+        let not_push = Script(vec![Op::Dup]);
+        verify_script(&not_push, &script_pubkey, &tx, 0).unwrap_err();
+
+        // hash lock
+        let preimage = b"secret".to_vec();
+        let mut hash = vec![0; 32];
+        let mut hasher = Sha256::new();
+        hasher.input(&preimage);
+        hasher.result(&mut hash);
+        let script_pubkey = Script::hash_lock(&hash, &pub_key_hash);
+        let sig = sign(&tx, &secret_key, &script_pubkey);
+        let script_sig = Script(vec![
+            Op::Push(sig.clone()),
+            Op::Push(pub_key.to_vec()),
+            Op::Push(preimage),
+        ]);
+        verify_script(&script_sig, &script_pubkey, &tx, 0).unwrap();
+        let script_sig = Script(vec![
+            Op::Push(sig),
+            Op::Push(pub_key.to_vec()),
+            Op::Push(b"guess".to_vec()),
+        ]);
+        verify_script(&script_sig, &script_pubkey, &tx, 0).unwrap_err();
+
+        // time lock
+        let script_pubkey = Script::time_lock(5, &pub_key_hash);
+        for (lock_time, ok) in [(4, false), (5, true)] {
+            let tx = spending_tx(lock_time);
+            let sig = sign(&tx, &secret_key, &script_pubkey);
+            let script_sig = Script(vec![Op::Push(sig), Op::Push(pub_key.to_vec())]);
+            assert_eq!(
+                verify_script(&script_sig, &script_pubkey, &tx, 0).is_ok(),
+                ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_multisig() {
+        let keys: Vec<([u8; 64], [u8; 32])> = (1..4).map(|i| ed25519::keypair(&[i; 32])).collect();
+        let pub_keys: Vec<Vec<u8>> = keys.iter().map(|k| k.1.to_vec()).collect();
+        let script_pubkey = Script::multisig(2, &pub_keys);
+        assert_eq!(script_pubkey.get_multisig(), Some((2, pub_keys.clone())));
+        let tx = spending_tx(0);
+        let sigs: Vec<Vec<u8>> = keys
+            .iter()
+            .map(|k| sign(&tx, &k.0, &script_pubkey))
+            .collect();
+
+        let check = |ids: &[usize]| -> bool {
+            let script_sig = Script(ids.iter().map(|i| Op::Push(sigs[*i].clone())).collect());
+            verify_script(&script_sig, &script_pubkey, &tx, 0).is_ok()
+        };
+        assert!(check(&[0, 1]));
+        assert!(check(&[0, 2]));
+        assert!(!check(&[1, 0]));
+        assert!(!check(&[0, 0]));
+        assert!(!check(&[2]));
+    }
+}
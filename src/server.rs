@@ -1,18 +1,29 @@
 //! server of Blockchain
 
 use super::*;
+use crate::amount::*;
 use crate::block::*;
+use crate::blockchain::*;
+use crate::coinselect::*;
+use crate::download::*;
+use crate::mempool::*;
+use crate::miner::{self, *};
+use crate::protocol::*;
+use crate::rpc::*;
 use crate::transaction::*;
 use crate::utxoset::*;
+use crate::wallets::*;
 use bincode::{deserialize, serialize};
+use bitcoincash_addr::Address;
 use failure::format_err;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::collections::{HashMap, HashSet};
-use std::io::prelude::*;
+use std::convert::TryFrom;
 use std::net::{TcpListener, TcpStream};
 use std::sync::*;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
@@ -20,7 +31,8 @@ enum Message {
     Version(Versionmsg),
     Tx(Txmsg),
     GetData(GetDatamsg),
-    GetBlock(GetBlocksmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
     Inv(Invmsg),
     Block(Blockmsg),
 }
@@ -32,8 +44,15 @@ struct Blockmsg {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlocksmsg {
+struct GetHeadersmsg {
     addr_from: String,
+    locator: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Headersmsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,45 +82,93 @@ struct Versionmsg {
     best_height: i32,
 }
 
+#[derive(Clone)]
 pub struct Server {
     node_address: String,
     mining_address: String,
+    mining_threads: usize,
+    rpc_address: String,
+    rpc_credentials: String,
+    miner: MinerHandle,
     inner: Arc<Mutex<ServerInner>>,
 }
 
 struct ServerInner {
     known_nodes: HashSet<String>,
     utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>,
+    download: BlockDownload,
+    mempool: Mempool,
+    connections: HashMap<String, Arc<Mutex<TcpStream>>>,
+    wallets: Wallets,
 }
 
 const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+const VERSION: i32 = PROTOCOL_VERSION as i32;
+/// WRITE_TIMEOUT bounds how long a send may block on a slow peer
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// DOWNLOAD_CHECK_INTERVAL is how often block requests are checked for timeouts
+const DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
         let mut node_set = HashSet::new();
         node_set.insert(String::from(KNOWN_NODE1));
+        let mut wallets = Wallets::new(utxo.blockchain.data_dir.to_str().unwrap_or_default())?;
+        wallets.sync(&utxo.blockchain)?;
+        let tip = utxo.blockchain.tip.clone();
+        let work = if tip.is_empty() {
+            0
+        } else {
+            utxo.blockchain.get_chain_work(&tip)?
+        };
+        let download = BlockDownload::new(tip, utxo.blockchain.get_best_height()?, work);
         Ok(Server {
             node_address: String::from("localhost:") + port,
             mining_address: miner_address.to_string(),
+            mining_threads: default_threads(),
+            rpc_address: String::new(),
+            rpc_credentials: String::new(),
+            miner: MinerHandle::default(),
             inner: Arc::new(Mutex::new(ServerInner {
                 known_nodes: node_set,
                 utxo,
-                blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                download,
+                mempool: Mempool::new(MAX_MEMPOOL_SIZE),
+                connections: HashMap::new(),
+                wallets,
             })),
         })
     }
 
-    pub fn start_server(&self) -> Result<()> {
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
+    /// SetMiningThreads sets the number of threads searching the nonce of a block
+    pub fn set_mining_threads(&mut self, threads: usize) {
+        self.mining_threads = threads;
+    }
+
+    /// EnableRPC makes start_server also answer JSON-RPC requests on port,
+    /// authenticated with credentials given as user:password
+    ///
+    /// Without credentials, new ones are written to the cookie file of the
+    /// data dir
+    pub fn enable_rpc(&mut self, port: &str, credentials: Option<String>) -> Result<()> {
+        self.rpc_credentials = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                let data_dir = self.data_dir();
+                let credentials = write_cookie(&data_dir)?;
+                info!(
+                    "RPC credentials written to {}",
+                    data_dir.join(COOKIE_FILE).display()
+                );
+                credentials
+            }
         };
+        self.rpc_address = String::from("localhost:") + port;
+        Ok(())
+    }
+
+    pub fn start_server(&self) -> Result<()> {
+        let server1 = self.clone();
         info!(
             "Start server at {}, minning address: {}",
             &self.node_address, &self.mining_address
@@ -109,23 +176,49 @@ impl Server {
 
         thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height()? == -1 {
-                server1.request_blocks()
-            } else {
-                server1.send_version(KNOWN_NODE1)
+            server1.send_version(KNOWN_NODE1)
+        });
+
+        let server1 = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(DOWNLOAD_CHECK_INTERVAL);
+            if let Err(e) = server1.check_downloads() {
+                warn!("cannot check block downloads: {}", e);
             }
         });
 
+        if !self.mining_address.is_empty() {
+            let server1 = self.clone();
+            let server2 = self.clone();
+            let miner = self.miner.clone();
+            let threads = self.mining_threads;
+            thread::spawn(move || {
+                miner::run(
+                    miner,
+                    threads,
+                    move || server1.block_template(),
+                    move |block| server2.submit_block(block),
+                )
+            });
+        }
+
+        if !self.rpc_address.is_empty() {
+            let server1 = self.clone();
+            thread::spawn(move || {
+                let address = server1.rpc_address.clone();
+                let credentials = server1.rpc_credentials.clone();
+                rpc::serve(&address, &credentials, move |method, params| {
+                    server1.handle_rpc(method, params)
+                })
+            });
+        }
+
         let listener = TcpListener::bind(&self.node_address).unwrap();
         info!("Server listen...");
 
         for stream in listener.incoming() {
             let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
-            };
+            let server1 = self.clone();
             thread::spawn(move || server1.handle_connection(stream));
         }
 
@@ -141,7 +234,9 @@ impl Server {
     /* ------------------- inner halp functions ----------------------------------*/
 
     fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+        let mut inner = self.inner.lock().unwrap();
+        inner.known_nodes.remove(addr);
+        inner.download.remove_peer(addr);
     }
 
     fn add_nodes(&self, addr: &str) {
@@ -160,114 +255,238 @@ impl Server {
         self.inner.lock().unwrap().known_nodes.get(addr).is_some()
     }
 
-    fn replace_in_transit(&self, hashs: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&hashs);
+    fn get_mempool_tx(&self, txid: &str) -> Option<Transaction> {
+        let inner = self.inner.lock().unwrap();
+        inner.mempool.get(txid).map(|entry| entry.tx.clone())
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    /// AcceptToMempool validates the transaction against the UTXO set and the
+    /// mempool, and adds it to the mempool
+    fn accept_to_mempool(&self, tx: Transaction) -> Result<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        inner.mempool.add(tx, &inner.utxo)
     }
 
-    fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
-    }
-
-    fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
-    }
-
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn get_block_transactions(&self) -> Result<(Vec<Transaction>, Amount)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .mempool
+            .block_transactions(MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE)
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
+    fn data_dir(&self) -> std::path::PathBuf {
+        self.inner.lock().unwrap().utxo.blockchain.data_dir.clone()
     }
 
     fn get_best_height(&self) -> Result<i32> {
         self.inner.lock().unwrap().utxo.blockchain.get_best_height()
     }
 
-    fn get_block_hashs(&self) -> Vec<String> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block_hashs()
-    }
-
-    fn get_block(&self, block_hash: &str) -> Result<Block> {
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .get_block(block_hash)
+            .has_block(block_hash)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
+    fn get_block(&self, block_hash: &str) -> Result<Block> {
         self.inner
             .lock()
             .unwrap()
             .utxo
             .blockchain
-            .verify_transacton(tx)
+            .get_block(block_hash)
     }
 
+    /// AddBlock stores the block and moves the UTXO set, the mempool and the
+    /// wallet history along any change of the active chain
     fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+        {
+            let mut guard = self.inner.lock().unwrap();
+            let inner = &mut *guard;
+            let update = inner.utxo.blockchain.add_block(block)?;
+            if let Err(e) = inner.utxo.apply_chain_update(&update) {
+                warn!("cannot update UTXO set: {}, reindex it", e);
+                inner.utxo.reindex()?;
+            }
+            inner.mempool.apply_chain_update(&update, &inner.utxo);
+            if !update.connected.is_empty() {
+                // the block being mined no longer extends the tip
+                self.miner.cancel();
+                let bc = &inner.utxo.blockchain;
+                inner.download.update_best_header(
+                    bc.tip.clone(),
+                    bc.get_best_height()?,
+                    bc.get_chain_work(&bc.tip)?,
+                );
+            }
+        }
+        self.sync_wallets();
+        Ok(())
+    }
+
+    /// BlockTemplate returns a block on top of the tip paying the mining
+    /// address, with the transactions of the mempool, or None when the mempool
+    /// is empty
+    fn block_template(&self) -> Result<Option<Block>> {
+        let inner = self.inner.lock().unwrap();
+        if inner.mempool.is_empty() {
+            return Ok(None);
+        }
+        let (mut txs, fees) = inner
+            .mempool
+            .block_transactions(MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE)?;
+        let cbtx = Transaction::new_coinbase(
+            self.mining_address.clone(),
+            String::new(),
+            inner.utxo.blockchain.get_block_reward(fees)?,
+        )?;
+        txs.insert(0, cbtx);
+        Ok(Some(inner.utxo.blockchain.new_block_template(txs)?))
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    /// SubmitBlock adds a block mined by this node and announces it
+    fn submit_block(&self, block: Block) -> Result<()> {
+        let block_hash = block.get_hash();
+        self.add_block(block)?;
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, "block", vec![block_hash.clone()])?;
+            }
+        }
+        Ok(())
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    /// SyncWallets brings the wallet history up to date with the active chain
+    fn sync_wallets(&self) {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if let Err(e) = inner.wallets.sync(&inner.utxo.blockchain) {
+            warn!("cannot update wallet history: {}", e);
+        }
     }
 
     /* -----------------------------------------------------*/
 
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+    /// GetConnection returns the open connection to addr, connecting to it if needed
+    fn get_connection(&self, addr: &str) -> Option<Arc<Mutex<TcpStream>>> {
+        if let Some(conn) = self.inner.lock().unwrap().connections.get(addr) {
+            return Some(Arc::clone(conn));
+        }
+        let stream = TcpStream::connect(addr).ok()?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok()?;
+        let conn = Arc::new(Mutex::new(stream));
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .insert(String::from(addr), Arc::clone(&conn));
+        Some(conn)
+    }
+
+    fn drop_connection(&self, addr: &str) {
+        self.inner.lock().unwrap().connections.remove(addr);
+    }
+
+    /// SendData sends a framed message on the connection kept open to addr
+    fn send_data(&self, addr: &str, cmd: &str, payload: &[u8]) -> Result<()> {
+        if addr == self.node_address {
             return Ok(());
         }
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
+        // a cached connection may have been closed by the peer, so retry once
+        // on a fresh one before giving up on the node
+        for _ in 0..2 {
+            let conn = match self.get_connection(addr) {
+                Some(conn) => conn,
+                None => break,
+            };
+            let result = write_frame(&mut *conn.lock().unwrap(), cmd, payload);
+            match result {
+                Ok(()) => {
+                    info!("data send successfully");
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("connection to {} is broken: {}", addr, e);
+                    self.drop_connection(addr);
+                }
             }
-        };
+        }
+
+        self.remove_node(addr);
+        Ok(())
+    }
 
-        stream.write(data)?;
+    /// QueueMissingBlocks queues the download of the blocks of the best header
+    /// chain that are not stored yet, once it has more work than the active chain
+    fn queue_missing_blocks(&self) -> Result<()> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let bc = &inner.utxo.blockchain;
+        let (best, _, work) = inner.download.best_header();
+        if best.is_empty() || (!bc.tip.is_empty() && work <= bc.get_chain_work(&bc.tip)?) {
+            return Ok(());
+        }
 
-        info!("data send successfully");
+        let mut missing = Vec::new();
+        let mut hash = best;
+        while !hash.is_empty() && !bc.has_block(&hash)? && !inner.download.is_tracked(&hash) {
+            let (header, height) = match bc.get_header(&hash)? {
+                Some(header) => header,
+                None => return Err(format_err!("ERROR: Header {} is not found", hash)),
+            };
+            missing.push((hash, height));
+            hash = header.prev_block_hash;
+        }
+        missing.reverse();
+        inner.download.enqueue(missing);
         Ok(())
     }
 
+    /// RequestBlocks sends the block requests scheduled by the download
     fn request_blocks(&self) -> Result<()> {
-        for node in self.get_known_nodes() {
-            self.send_get_blocks(&node)?
+        let requests = self.inner.lock().unwrap().download.schedule(Instant::now());
+        for (peer, block_hash) in requests {
+            self.send_get_data(&peer, "block", &block_hash)?;
+        }
+        Ok(())
+    }
+
+    /// CheckDownloads sends again to other peers the block requests that timed out
+    fn check_downloads(&self) -> Result<()> {
+        let expired = self.inner.lock().unwrap().download.expire(Instant::now());
+        if expired > 0 {
+            self.request_blocks()?;
         }
         Ok(())
     }
 
+    /// SyncProgress returns the height of the active chain and of the best header chain
+    fn sync_progress(&self) -> Result<(i32, i32)> {
+        let inner = self.inner.lock().unwrap();
+        Ok((
+            inner.utxo.blockchain.get_best_height()?,
+            inner.download.best_header().1,
+        ))
+    }
+
     fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
         info!("send block data to: {} block hash: {}", addr, b.get_hash());
         let data = Blockmsg {
             addr_from: self.node_address.clone(),
             block: b.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "block", &serialize(&data)?)
     }
 
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("send address info to: {}", addr);
         let nodes = self.get_known_nodes();
-        let data = serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "addr", &serialize(&nodes)?)
     }
 
     fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
@@ -280,17 +499,31 @@ impl Server {
             kind: kind.to_string(),
             items,
         };
-        let data = serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "inv", &serialize(&data)?)
+    }
+
+    /// SendGetHeaders asks addr for the headers following the best header chain
+    fn send_get_headers(&self, addr: &str) -> Result<()> {
+        let locator = {
+            let inner = self.inner.lock().unwrap();
+            let (best, _, _) = inner.download.best_header();
+            inner.utxo.blockchain.get_block_locator(&best)?
+        };
+        info!("send get headers message to: {}", addr);
+        let data = GetHeadersmsg {
+            addr_from: self.node_address.clone(),
+            locator,
+        };
+        self.send_data(addr, "getheaders", &serialize(&data)?)
     }
 
-    fn send_get_blocks(&self, addr: &str) -> Result<()> {
-        info!("send get blocks message to: {}", addr);
-        let data = GetBlocksmsg {
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to: {}", headers.len(), addr);
+        let data = Headersmsg {
             addr_from: self.node_address.clone(),
+            headers,
         };
-        let data = serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "headers", &serialize(&data)?)
     }
 
     fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
@@ -303,8 +536,7 @@ impl Server {
             kind: kind.to_string(),
             id: id.to_string(),
         };
-        let data = serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "getdata", &serialize(&data)?)
     }
 
     pub fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
@@ -313,8 +545,7 @@ impl Server {
             addr_from: self.node_address.clone(),
             transaction: tx.clone(),
         };
-        let data = serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "tx", &serialize(&data)?)
     }
 
     fn send_version(&self, addr: &str) -> Result<()> {
@@ -324,15 +555,19 @@ impl Server {
             best_height: self.get_best_height()?,
             version: VERSION,
         };
-        let data = serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        self.send_data(addr, "version", &serialize(&data)?)
     }
 
     fn handle_version(&self, msg: Versionmsg) -> Result<()> {
         info!("receive version msg: {:#?}", msg);
+        self.inner
+            .lock()
+            .unwrap()
+            .download
+            .set_peer_height(&msg.addr_from, msg.best_height);
         let my_best_height = self.get_best_height()?;
         if my_best_height < msg.best_height {
-            self.send_get_blocks(&msg.addr_from)?;
+            self.send_get_headers(&msg.addr_from)?;
         } else if my_best_height > msg.best_height {
             self.send_version(&msg.addr_from)?;
         }
@@ -342,15 +577,21 @@ impl Server {
         if !self.node_is_known(&msg.addr_from) {
             self.add_nodes(&msg.addr_from);
         }
-        Ok(())
+        // the peer may have blocks still to download
+        self.request_blocks()
     }
 
     fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
         info!("receive address msg: {:#?}", msg);
         for node in msg {
+            let is_new = !self.node_is_known(&node);
             self.add_nodes(&node);
+            // exchange versions with new nodes, to learn which blocks they
+            // have and download from them too
+            if is_new && node != self.node_address {
+                self.send_version(&node)?;
+            }
         }
-        //self.request_blocks()?;
         Ok(())
     }
 
@@ -360,201 +601,584 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
+        let block_hash = msg.block.get_hash();
+        let expected = self
+            .inner
+            .lock()
+            .unwrap()
+            .download
+            .block_received(&block_hash);
+        let prev_hash = msg.block.get_prev_hash();
+        if !prev_hash.is_empty() && !self.has_block(&prev_hash)? {
+            if expected {
+                // blocks are downloaded in parallel, so the parent may still be on its way
+                self.inner.lock().unwrap().download.add_orphan(msg.block);
+                return self.request_blocks();
+            }
+            info!("block {} is an orphan, request the headers", block_hash);
+            return self.send_get_headers(&msg.addr_from);
         }
 
-        Ok(())
+        let received_hash = block_hash;
+        let mut next = Some(msg.block);
+        while let Some(block) = next {
+            let block_hash = block.get_hash();
+            if let Err(e) = self.add_block(block) {
+                match e.downcast_ref::<BlockError>() {
+                    Some(reason) if reason.is_missing_data() => {
+                        info!(
+                            "block {} cannot be connected: {}, request the headers",
+                            block_hash, reason
+                        );
+                        return self.send_get_headers(&msg.addr_from);
+                    }
+                    Some(reason) => {
+                        warn!(
+                            "reject block {} from {}: {}",
+                            block_hash, msg.addr_from, reason
+                        );
+                        if !reason.breaks_consensus() {
+                            return Ok(());
+                        }
+                        {
+                            let mut guard = self.inner.lock().unwrap();
+                            let inner = &mut *guard;
+                            let bc = &inner.utxo.blockchain;
+                            bc.mark_invalid(&block_hash)?;
+                            let best = inner.download.best_header();
+                            if bc.is_invalid(&best.0)? {
+                                let work = if bc.tip.is_empty() {
+                                    0
+                                } else {
+                                    bc.get_chain_work(&bc.tip)?
+                                };
+                                inner.download.reset_best_header(
+                                    bc.tip.clone(),
+                                    bc.get_best_height()?,
+                                    work,
+                                );
+                            }
+                        }
+                        // orphans were served by other peers, so only the
+                        // peer of this message is dropped
+                        if block_hash == received_hash {
+                            warn!("drop peer {} for serving an invalid block", msg.addr_from);
+                            self.remove_node(&msg.addr_from);
+                            self.drop_connection(&msg.addr_from);
+                        }
+                        return Ok(());
+                    }
+                    None => return Err(e),
+                }
+            }
+            next = self.inner.lock().unwrap().download.take_orphan(&block_hash);
+        }
+
+        let (height, best_height) = self.sync_progress()?;
+        if height < best_height {
+            info!(
+                "sync progress: block {} of {} ({:.1}%)",
+                height,
+                best_height,
+                100.0 * (height + 1) as f64 / (best_height + 1) as f64
+            );
+        }
+        self.request_blocks()
     }
 
     fn handle_inv(&self, msg: Invmsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-            let mut new_in_transit = Vec::new();
-            for b in &msg.items {
-                if b != block_hash {
-                    new_in_transit.push(b.clone());
+            // a block is announced by its hash only, so ask for the headers
+            // leading to it, which tell where it belongs in the chain
+            for block_hash in &msg.items {
+                if !self.has_block(block_hash)? {
+                    return self.send_get_headers(&msg.addr_from);
                 }
             }
-            self.replace_in_transit(new_in_transit);
         } else if msg.kind == "tx" {
             let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
-                    }
-                }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+            if self.get_mempool_tx(txid).is_none() {
+                self.send_get_data(&msg.addr_from, "tx", txid)?
             }
         }
         Ok(())
     }
 
-    fn handle_get_blocks(&self, msg: GetBlocksmsg) -> Result<()> {
-        info!("receive get blocks msg: {:#?}", msg);
-        let block_hashs = self.get_block_hashs();
-        self.send_inv(&msg.addr_from, "block", block_hashs)?;
-        Ok(())
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<()> {
+        info!(
+            "receive get headers msg: {} with {} locator hashes",
+            msg.addr_from,
+            msg.locator.len()
+        );
+        let headers = self
+            .inner
+            .lock()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_headers_after(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(&msg.addr_from, headers)
+    }
+
+    /// HandleHeaders validates the headers and stores them, asks for more while
+    /// the peer sends full batches, and downloads the blocks of the best header
+    /// chain once it has more work than the active chain
+    fn handle_headers(&self, msg: Headersmsg) -> Result<()> {
+        info!(
+            "receive headers msg: {} with {} headers",
+            msg.addr_from,
+            msg.headers.len()
+        );
+        let mut complete = true;
+        {
+            let mut guard = self.inner.lock().unwrap();
+            let inner = &mut *guard;
+            for header in &msg.headers {
+                match inner.utxo.blockchain.add_header(header) {
+                    Ok((hash, height, work)) => {
+                        inner.download.set_peer_height(&msg.addr_from, height);
+                        inner.download.update_best_header(hash, height, work);
+                    }
+                    Err(e) => match e.downcast_ref::<BlockError>() {
+                        Some(reason) => {
+                            warn!("reject header from {}: {}", msg.addr_from, reason);
+                            complete = false;
+                            break;
+                        }
+                        None => return Err(e),
+                    },
+                }
+            }
+        }
+
+        if complete && msg.headers.len() == MAX_HEADERS {
+            self.send_get_headers(&msg.addr_from)?;
+        }
+        let (height, best_height) = self.sync_progress()?;
+        if height < best_height {
+            info!("sync headers up to height {}", best_height);
+        }
+        self.queue_missing_blocks()?;
+        self.request_blocks()
     }
 
     fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
         info!("receive get data msg: {:#?}", msg);
         if msg.kind == "block" {
+            if !self.has_block(&msg.id)? {
+                return Ok(());
+            }
             let block = self.get_block(&msg.id)?;
             self.send_block(&msg.addr_from, &block)?;
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            // the transaction may have been mined or evicted since it was announced
+            if let Some(tx) = self.get_mempool_tx(&msg.id) {
+                self.send_tx(&msg.addr_from, &tx)?;
+            }
         }
         Ok(())
     }
 
     fn handle_tx(&self, msg: Txmsg) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        let txid = msg.transaction.id.clone();
+        if let Err(e) = self.accept_to_mempool(msg.transaction) {
+            match e.downcast_ref::<MempoolError>() {
+                Some(reason) => {
+                    info!("reject tx {} from {}: {}", txid, msg.addr_from, reason);
+                    return Ok(());
+                }
+                None => return Err(e),
+            }
+        }
+        self.relay_tx(&msg.addr_from, &txid)
+    }
 
-        let known_nodes = self.get_known_nodes();
+    /// RelayTx announces a transaction accepted to the mempool, or wakes the
+    /// miner of a mining node
+    fn relay_tx(&self, addr_from: &str, txid: &str) -> Result<()> {
         if self.node_address == KNOWN_NODE1 {
-            for node in known_nodes {
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+            for node in self.get_known_nodes() {
+                if node != self.node_address && node != addr_from {
+                    self.send_inv(&node, "tx", vec![txid.to_string()])?;
                 }
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    let mut txs = Vec::new();
-
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
-
-                    let cbtx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cbtx);
-
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+        } else if !self.mining_address.is_empty() {
+            self.miner.notify();
+        }
+        Ok(())
+    }
 
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    /// SubmitTransaction adds a transaction made on this node to the mempool
+    /// and relays it
+    fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        let txid = tx.id.clone();
+        if let Err(e) = self.accept_to_mempool(tx.clone()) {
+            return Err(match e.downcast_ref::<MempoolError>() {
+                Some(reason) => format_err!("ERROR: Invalid transaction: {}", reason),
+                None => e,
+            });
+        }
+        self.send_tx(KNOWN_NODE1, &tx)?;
+        let addr_from = self.node_address.clone();
+        self.relay_tx(&addr_from, &txid)
+    }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
+    /// HandleRPC runs a JSON-RPC method against the state of the node
+    fn handle_rpc(&self, method: &str, params: &[Value]) -> RpcResult {
+        match method {
+            "getbestheight" => Ok(json!(self.get_best_height()?)),
+            "getblockhash" => {
+                let height = match i32::try_from(param_i64(params, 0)?) {
+                    Ok(height) => height,
+                    Err(_) => {
+                        return Err(RpcError::new(INVALID_PARAMS, "block height out of range"))
                     }
-
-                    if mempool.len() == 0 {
-                        break;
+                };
+                let inner = self.inner.lock().unwrap();
+                match inner.utxo.blockchain.get_block_by_height(height)? {
+                    Some(block) => Ok(json!(block.get_hash())),
+                    None => Err(RpcError::new(INVALID_PARAMS, "block height out of range")),
+                }
+            }
+            "getblock" => {
+                let hash = param_str(params, 0)?;
+                if !self.has_block(hash)? {
+                    return Err(RpcError::new(INVALID_PARAMS, "block not found"));
+                }
+                let block = self.get_block(hash)?;
+                let txids: Vec<String> = block
+                    .get_transaction()
+                    .iter()
+                    .map(|tx| tx.id.clone())
+                    .collect();
+                Ok(json!({
+                    "hash": block.get_hash(),
+                    "version": block.get_header().version,
+                    "prev_block_hash": block.get_prev_hash(),
+                    "merkle_root": hex::encode(&block.get_header().merkle_root),
+                    "height": block.get_height(),
+                    "timestamp": block.get_timestamp() as u64,
+                    "bits": block.get_bits(),
+                    "nonce": block.get_nonce(),
+                    "tx": txids,
+                }))
+            }
+            "getbalance" => {
+                let pub_key_hash = decode_address(param_str(params, 0)?)?;
+                let balance = self.inner.lock().unwrap().utxo.get_balance(&pub_key_hash)?;
+                Ok(json!({"balance": balance.spendable, "immature": balance.immature}))
+            }
+            "sendtoaddress" => {
+                let from = param_str(params, 0)?;
+                let to = param_str(params, 1)?;
+                decode_address(to)?;
+                let amount = param_amount(params, 2)?;
+                let fee = param_amount_or(params, 3, 0)?;
+
+                let tx = {
+                    let inner = self.inner.lock().unwrap();
+                    let wallet = wallet_of(&inner.wallets, from)?;
+                    // outputs spent by the pool are not spendable until the next block
+                    let coin_control = CoinControl {
+                        exclude: inner.mempool.spent_outpoints(),
+                        ..Default::default()
+                    };
+                    Transaction::new_UTXO(wallet, to, amount, fee, &coin_control, &inner.utxo)?
+                };
+                let txid = tx.id.clone();
+                self.submit_transaction(tx)?;
+                Ok(json!(txid))
+            }
+            "sendmany" => {
+                let from = param_str(params, 0)?;
+                let amounts = params.get(1).and_then(Value::as_object).ok_or_else(|| {
+                    RpcError::new(INVALID_PARAMS, "param 1 must be an object of amounts")
+                })?;
+                let mut recipients = Vec::new();
+                for (to, amount) in amounts {
+                    decode_address(to)?;
+                    let amount = as_amount(amount).ok_or_else(|| {
+                        RpcError::new(
+                            INVALID_PARAMS,
+                            "amounts must be integers of units or strings of coins",
+                        )
+                    })?;
+                    recipients.push((to.clone(), amount));
+                }
+                let fee = param_amount_or(params, 2, 0)?;
+
+                let tx = {
+                    let inner = self.inner.lock().unwrap();
+                    let wallet = wallet_of(&inner.wallets, from)?;
+                    // outputs spent by the pool are not spendable until the next block
+                    let coin_control = CoinControl {
+                        exclude: inner.mempool.spent_outpoints(),
+                        ..Default::default()
+                    };
+                    Transaction::new_send_many(
+                        wallet,
+                        &recipients,
+                        fee,
+                        &coin_control,
+                        &inner.utxo,
+                    )?
+                };
+                let txid = tx.id.clone();
+                self.submit_transaction(tx)?;
+                Ok(json!(txid))
+            }
+            "getrawtransaction" => {
+                let txid = param_str(params, 0)?;
+                let verbose = params.get(1).and_then(Value::as_bool).unwrap_or(false);
+                let tx = match self.get_mempool_tx(txid) {
+                    Some(tx) => tx,
+                    None => self
+                        .inner
+                        .lock()
+                        .unwrap()
+                        .utxo
+                        .blockchain
+                        .find_transacton(txid)?,
+                };
+                if verbose {
+                    Ok(serde_json::to_value(&tx).map_err(failure::Error::from)?)
+                } else {
+                    let data = serialize(&tx).map_err(failure::Error::from)?;
+                    Ok(json!(hex::encode(data)))
+                }
+            }
+            "sendrawtransaction" => {
+                let data = hex::decode(param_str(params, 0)?)
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "transaction is not hex"))?;
+                let tx: Transaction = deserialize(&data)
+                    .map_err(|_| RpcError::new(INVALID_PARAMS, "transaction decode failed"))?;
+                let txid = tx.id.clone();
+                self.submit_transaction(tx)?;
+                Ok(json!(txid))
+            }
+            "encryptwallet" => {
+                let passphrase = param_str(params, 0)?;
+                self.inner
+                    .lock()
+                    .unwrap()
+                    .wallets
+                    .encrypt(passphrase, KeyParams::default())?;
+                Ok(json!(null))
+            }
+            "walletpassphrase" => {
+                let passphrase = param_str(params, 0)?;
+                let timeout = param_i64(params, 1)?;
+                if timeout <= 0 {
+                    return Err(RpcError::new(INVALID_PARAMS, "timeout must be positive"));
+                }
+                let timeout = Duration::from_secs(timeout as u64);
+                // reload the wallets, to unlock the keys created since the node started
+                let mut wallets = Wallets::new(self.data_dir().to_str().unwrap_or_default())?;
+                wallets.unlock(passphrase, timeout)?;
+                self.inner.lock().unwrap().wallets = wallets;
+                // forget the keys once the timeout has passed, unless unlocked again since
+                let server1 = self.clone();
+                thread::spawn(move || {
+                    thread::sleep(timeout);
+                    if server1.inner.lock().unwrap().wallets.lock_if_expired() {
+                        info!("wallet locked");
                     }
+                });
+                Ok(json!(null))
+            }
+            "listtransactions" => {
+                let address = params.first().and_then(Value::as_str);
+                let inner = self.inner.lock().unwrap();
+                let best_height = inner.utxo.blockchain.get_best_height()?;
+                let txs: Vec<Value> = inner
+                    .wallets
+                    .get_history(address)
+                    .iter()
+                    .map(|wtx| {
+                        json!({
+                            "txid": wtx.txid,
+                            "height": wtx.height,
+                            "timestamp": wtx.timestamp as u64,
+                            "amount": match address {
+                                Some(address) => wtx.ledger[address],
+                                None => wtx.amount,
+                            },
+                            "coinbase": wtx.is_coinbase,
+                            "confirmations": wtx.confirmations(best_height),
+                            "counterparties": wtx.counterparties,
+                        })
+                    })
+                    .collect();
+                Ok(json!(txs))
+            }
+            "walletlock" => {
+                let mut inner = self.inner.lock().unwrap();
+                if !inner.wallets.is_encrypted() {
+                    return Err(RpcError::new(MISC_ERROR, "wallet is not encrypted"));
                 }
-                self.clear_mempool();
+                inner.wallets.lock();
+                Ok(json!(null))
+            }
+            "getmempool" => {
+                let (txs, _) = self.get_block_transactions()?;
+                let txids: Vec<String> = txs.into_iter().map(|tx| tx.id).collect();
+                Ok(json!(txids))
             }
+            "getmininginfo" => Ok(json!({
+                "mining": !self.mining_address.is_empty(),
+                "threads": self.mining_threads,
+                "hashespersec": self.miner.hashrate(),
+                "blocks": self.miner.blocks_mined(),
+            })),
+            "getsyncinfo" => {
+                let (height, best_height) = self.sync_progress()?;
+                let inner = self.inner.lock().unwrap();
+                Ok(json!({
+                    "blocks": height,
+                    "headers": best_height,
+                    "progress": if best_height < 0 {
+                        1.0
+                    } else {
+                        (height + 1) as f64 / (best_height + 1) as f64
+                    },
+                    "inflight": inner.download.in_flight(),
+                    "pending": inner.download.pending(),
+                }))
+            }
+            "getmempoolinfo" => {
+                let inner = self.inner.lock().unwrap();
+                Ok(json!({
+                    "size": inner.mempool.len(),
+                    "bytes": inner.mempool.size(),
+                    "maxbytes": MAX_MEMPOOL_SIZE,
+                }))
+            }
+            "getpeerinfo" => {
+                let inner = self.inner.lock().unwrap();
+                let peers: Vec<Value> = inner
+                    .known_nodes
+                    .iter()
+                    .map(|node| {
+                        json!({
+                            "address": node,
+                            "connected": inner.connections.contains_key(node),
+                        })
+                    })
+                    .collect();
+                Ok(json!(peers))
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
         }
-
-        Ok(())
     }
 
+    /// HandleConnection reads and handles messages until the peer closes the connection
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = Vec::new();
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
-
-        let cmd = bytes_to_cmd(&buffer)?;
+        let peer = stream.peer_addr()?;
+        info!("Accept connection from {}", peer);
+
+        loop {
+            let (cmd, payload) = match read_frame(&mut stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("drop connection from {}: {}", peer, e);
+                    break;
+                }
+            };
+            info!("Accept request: {} length {}", cmd, payload.len());
 
-        match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
-            Message::Inv(data) => self.handle_inv(data)?,
-            Message::GetBlock(data) => self.handle_get_blocks(data)?,
-            Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?,
+            let msg = match decode_message(&cmd, &payload) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("drop connection from {}: {}", peer, e);
+                    break;
+                }
+            };
+            let result = match msg {
+                Message::Addr(data) => self.handle_addr(data),
+                Message::Block(data) => self.handle_block(data),
+                Message::Inv(data) => self.handle_inv(data),
+                Message::GetData(data) => self.handle_get_data(data),
+                Message::GetHeaders(data) => self.handle_get_headers(data),
+                Message::Headers(data) => self.handle_headers(data),
+                Message::Tx(data) => self.handle_tx(data),
+                Message::Version(data) => self.handle_version(data),
+            };
+            if let Err(e) = result {
+                warn!("failed to handle {} from {}: {}", cmd, peer, e);
+            }
         }
 
+        info!("Connection from {} closed", peer);
         Ok(())
     }
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
+/// DecodeAddress returns the public key hash of an address given to an RPC method
+fn decode_address(address: &str) -> std::result::Result<Vec<u8>, RpcError> {
+    match Address::decode(address) {
+        Ok(addr) => Ok(addr.body),
+        Err(_) => Err(RpcError::new(INVALID_PARAMS, "invalid address")),
     }
-    data
 }
 
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
+/// WalletOf returns the wallet of an address given to an RPC method
+fn wallet_of<'a>(wallets: &'a Wallets, address: &str) -> std::result::Result<&'a Wallet, RpcError> {
+    match wallets.get_wallet(address) {
+        Some(wallet) => Ok(wallet),
+        None if wallets.get_public_key(address).is_some() => Err(RpcError::new(
+            WALLET_UNLOCK_NEEDED,
+            "wallet is locked, call walletpassphrase first",
+        )),
+        None => Err(RpcError::new(INVALID_PARAMS, "address not in wallet")),
     }
-    info!("cmd: {}", String::from_utf8(cmd.clone())?);
+}
 
-    if cmd == "addr".as_bytes() {
+/// DecodeMessage deserializes the payload of a frame according to its command
+fn decode_message(cmd: &str, data: &[u8]) -> Result<Message> {
+    if cmd == "addr" {
         let data: Vec<String> = deserialize(data)?;
         Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
+    } else if cmd == "block" {
         let data: Blockmsg = deserialize(data)?;
         Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
+    } else if cmd == "inv" {
         let data: Invmsg = deserialize(data)?;
         Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data: GetBlocksmsg = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
+    } else if cmd == "getdata" {
         let data: GetDatamsg = deserialize(data)?;
         Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
+    } else if cmd == "getheaders" {
+        let data: GetHeadersmsg = deserialize(data)?;
+        Ok(Message::GetHeaders(data))
+    } else if cmd == "headers" {
+        let data: Headersmsg = deserialize(data)?;
+        Ok(Message::Headers(data))
+    } else if cmd == "tx" {
         let data: Txmsg = deserialize(data)?;
         Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
+    } else if cmd == "version" {
         let data: Versionmsg = deserialize(data)?;
         Ok(Message::Version(data))
     } else {
-        Err(format_err!("Unknown command in the server"))
+        Err(format_err!("Unknown command {} in the server", cmd))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::blockchain::*;
-    use crate::wallets::*;
 
     #[test]
     fn test_cmd() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
-        let bc = Blockchain::create_blockchain(wa1).unwrap();
-        let utxo_set = UTXOSet { blockchain: bc };
+        let data_dir = "data/test_cmd";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let bc = Blockchain::create_blockchain(wa1, data_dir).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
         let server = Server::new("7878", "localhost:3001", utxo_set).unwrap();
 
         let vmsg = Versionmsg {
@@ -562,11 +1186,232 @@ mod test {
             best_height: server.get_best_height().unwrap(),
             version: VERSION,
         };
-        let data = serialize(&(cmd_to_bytes("version"), vmsg.clone())).unwrap();
-        if let Message::Version(v) = bytes_to_cmd(&data).unwrap() {
+        let frame = encode_frame("version", &serialize(&vmsg).unwrap()).unwrap();
+        let (cmd, payload) = read_frame(&mut frame.as_slice()).unwrap().unwrap();
+        if let Message::Version(v) = decode_message(&cmd, &payload).unwrap() {
             assert_eq!(v, vmsg);
         } else {
             panic!("wrong!");
         }
     }
+
+    #[test]
+    fn test_block_of_header_only_parent() {
+        let data_dir = "data/test_header_only_parent";
+        let peer_dir = "data/test_header_only_parent_peer";
+        std::fs::remove_dir_all(data_dir).ok();
+        std::fs::remove_dir_all(peer_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let mut peer = Blockchain::create_blockchain(wa1.clone(), peer_dir).unwrap();
+        let mut blocks = vec![peer.get_block(&peer.tip).unwrap()];
+        for _ in 0..2 {
+            let cbtx =
+                Transaction::new_coinbase(wa1.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            blocks.push(peer.mine_block(vec![cbtx]).unwrap());
+        }
+
+        let mut bc = Blockchain::new(data_dir).unwrap();
+        bc.add_block(blocks[0].clone()).unwrap();
+        for block in &blocks {
+            bc.add_header(block.get_header()).unwrap();
+        }
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let server = Server::new("7880", "", utxo_set).unwrap();
+        let addr_from = String::from("localhost:1");
+        let err = server.add_block(blocks[2].clone()).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::MissingParent(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // an unsolicited block is dropped until its parent is stored
+        server
+            .handle_block(Blockmsg {
+                addr_from: addr_from.clone(),
+                block: blocks[2].clone(),
+            })
+            .unwrap();
+        assert!(!server.has_block(&blocks[2].get_hash()).unwrap());
+
+        // a block to download waits for its parent
+        server
+            .inner
+            .lock()
+            .unwrap()
+            .download
+            .enqueue(vec![(blocks[2].get_hash(), 2)]);
+        server
+            .handle_block(Blockmsg {
+                addr_from: addr_from.clone(),
+                block: blocks[2].clone(),
+            })
+            .unwrap();
+        assert_eq!(server.inner.lock().unwrap().download.pending(), 1);
+        server
+            .handle_block(Blockmsg {
+                addr_from,
+                block: blocks[1].clone(),
+            })
+            .unwrap();
+        assert_eq!(server.get_best_height().unwrap(), 2);
+        assert_eq!(server.inner.lock().unwrap().download.pending(), 0);
+    }
+
+    #[test]
+    fn test_invalid_block() {
+        let data_dir = "data/test_invalid_block";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let bc = Blockchain::create_blockchain(wa1.clone(), data_dir).unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let bits = genesis.get_header().bits;
+        // the coinbase of the first block creates more than the subsidy
+        let cbtx =
+            Transaction::new_coinbase(wa1.clone(), String::new(), 2 * INITIAL_SUBSIDY).unwrap();
+        let invalid = Block::new_block(vec![cbtx], genesis.get_hash(), 1, bits).unwrap();
+        let cbtx = Transaction::new_coinbase(wa1, String::new(), INITIAL_SUBSIDY).unwrap();
+        let child = Block::new_block(vec![cbtx], invalid.get_hash(), 2, bits).unwrap();
+        let headers = vec![invalid.get_header().clone(), child.get_header().clone()];
+        for header in &headers {
+            bc.add_header(header).unwrap();
+        }
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        let server = Server::new("7881", "", utxo_set).unwrap();
+        let addr_from = String::from("localhost:1");
+        server.add_nodes(&addr_from);
+        server
+            .handle_headers(Headersmsg {
+                addr_from: addr_from.clone(),
+                headers: headers.clone(),
+            })
+            .unwrap();
+        assert_eq!(server.inner.lock().unwrap().download.best_header().1, 2);
+
+        // the invalid block is remembered, the best header goes back to the
+        // tip and the peer serving it is dropped
+        server
+            .handle_block(Blockmsg {
+                addr_from: addr_from.clone(),
+                block: invalid.clone(),
+            })
+            .unwrap();
+        {
+            let inner = server.inner.lock().unwrap();
+            let bc = &inner.utxo.blockchain;
+            assert!(bc.is_invalid(&invalid.get_hash()).unwrap());
+            assert!(bc.is_invalid(&child.get_hash()).unwrap());
+            assert!(!bc.is_invalid(&genesis.get_hash()).unwrap());
+            assert_eq!(inner.download.best_header().0, genesis.get_hash());
+            assert!(!inner.known_nodes.contains(&addr_from));
+        }
+
+        // the headers of the invalid chain and the block itself are refused again
+        server
+            .handle_headers(Headersmsg {
+                addr_from: addr_from.clone(),
+                headers: headers.clone(),
+            })
+            .unwrap();
+        assert_eq!(
+            server.inner.lock().unwrap().download.best_header().0,
+            genesis.get_hash()
+        );
+        for header in &headers {
+            let err = server
+                .inner
+                .lock()
+                .unwrap()
+                .utxo
+                .blockchain
+                .add_header(header)
+                .unwrap_err();
+            match err.downcast_ref::<BlockError>() {
+                Some(BlockError::InvalidChain(_)) => {}
+                _ => panic!("unexpected error: {}", err),
+            }
+        }
+        let err = server.add_block(invalid).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::InvalidChain(_)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(server.get_best_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_send_twice() {
+        let data_dir = "data/test_send_twice";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let wa2 = ws.create_wallet().unwrap();
+        ws.save_all().unwrap();
+        let mut bc = Blockchain::create_blockchain(wa1.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let cbtx = Transaction::new_coinbase(wa1.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        bc.mine_block(vec![cbtx]).unwrap();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+        let server = Server::new("7882", "", utxo_set).unwrap();
+
+        // the second payment spends another output than the first, still in the pool
+        let txid1 = server
+            .handle_rpc("sendtoaddress", &[json!(wa1), json!(wa2), json!("6")])
+            .unwrap();
+        let txid2 = server
+            .handle_rpc("sendmany", &[json!(wa1), json!({ wa2.clone(): "6" })])
+            .unwrap();
+        assert_ne!(txid1, txid2);
+        assert_eq!(server.inner.lock().unwrap().mempool.len(), 2);
+        // and nothing is left to spend until the next block
+        server
+            .handle_rpc("sendtoaddress", &[json!(wa1), json!(wa2), json!("1")])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_rpc() {
+        let data_dir = "data/test_rpc";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let bc = Blockchain::create_blockchain(wa1.clone(), data_dir).unwrap();
+        let genesis = bc.tip.clone();
+        let utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+        let server = Server::new("7879", "", utxo_set).unwrap();
+
+        assert_eq!(server.handle_rpc("getbestheight", &[]).unwrap(), json!(0));
+        assert_eq!(
+            server.handle_rpc("getblockhash", &[json!(0)]).unwrap(),
+            json!(genesis)
+        );
+        let block = server.handle_rpc("getblock", &[json!(genesis)]).unwrap();
+        assert_eq!(block["height"], 0);
+        assert_eq!(block["tx"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            server.handle_rpc("getbalance", &[json!(wa1)]).unwrap(),
+            json!({"balance": 0, "immature": 10 * COIN})
+        );
+
+        let err = server.handle_rpc("getblockhash", &[json!(1)]).unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+        // a height wrapping around to 0 when truncated
+        let err = server
+            .handle_rpc("getblockhash", &[json!(1i64 << 32)])
+            .unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+        let err = server
+            .handle_rpc("getbalance", &[json!("bad")])
+            .unwrap_err();
+        assert_eq!(err.code, INVALID_PARAMS);
+        let err = server.handle_rpc("stop", &[]).unwrap_err();
+        assert_eq!(err.code, METHOD_NOT_FOUND);
+    }
 }
@@ -1,10 +1,13 @@
 //! transaction implement
 
 use super::*;
+use crate::amount::*;
+use crate::coinselect::*;
+use crate::script::*;
 use crate::utxoset::*;
 use crate::wallets::*;
 use bincode::serialize;
-use bitcoincash_addr::Address;
+use bitcoincash_addr::{Address, HashType};
 use crypto::digest::Digest;
 use crypto::ed25519;
 use crypto::sha2::Sha256;
@@ -13,121 +16,292 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-const SUBSIDY: i32 = 10;
+/// EXTRA_NONCE_LEN is the size of the extra-nonce ending the data of a coinbase
+pub const EXTRA_NONCE_LEN: usize = 8;
 
 /// TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    pub script_sig: Script,
 }
 
 /// TXOutput represents a transaction output
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TXOutput {
-    pub value: i32,
-    pub pub_key_hash: Vec<u8>,
+    pub value: Amount,
+    pub script_pubkey: Script,
 }
 
-// TXOutputs collects TXOutput
+// TXOutputs collects the unspent TXOutput of a transaction by output index
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: HashMap<i32, TXOutput>,
+    /// Height is the height of the block of the transaction
+    pub height: i32,
+    pub is_coinbase: bool,
 }
 
 /// Transaction represents a Bitcoin transaction
+///
+/// It cannot be included in a block below height lock_time
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    pub lock_time: i32,
 }
 
 impl Transaction {
     /// NewUTXOTransaction creates a new transaction
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, utxo: &UTXOSet) -> Result<Transaction> {
+    ///
+    /// The inputs not claimed by the outputs, `fee`, are collected by the miner
+    pub fn new_UTXO(
+        wallet: &Wallet,
+        to: &str,
+        amount: Amount,
+        fee: Amount,
+        coin_control: &CoinControl,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        let recipients = [(to.to_string(), amount)];
+        Transaction::new_send_many(wallet, &recipients, fee, coin_control, utxo)
+    }
+
+    /// NewSendManyTransaction creates a new transaction paying each recipient,
+    /// given as address and amount, with a single change output
+    pub fn new_send_many(
+        wallet: &Wallet,
+        recipients: &[(String, Amount)],
+        fee: Amount,
+        coin_control: &CoinControl,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        let mut outputs = Vec::new();
+        for (to, amount) in recipients {
+            outputs.push(TXOutput::new(*amount, to.clone())?);
+        }
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let change = Script::pay_to_pub_key_hash(&pub_key_hash);
+        Transaction::new_to_outputs(wallet, outputs, change, fee, coin_control, utxo)
+    }
+
+    /// NewToOutputsTransaction creates a new transaction paying the outputs,
+    /// with the change locked by change
+    pub fn new_to_outputs(
+        wallet: &Wallet,
+        outputs: Vec<TXOutput>,
+        change: Script,
+        fee: Amount,
+        coin_control: &CoinControl,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
         info!(
-            "new UTXO Transaction from: {} to: {}",
+            "new UTXO Transaction from: {} to {} outputs fee: {}",
             wallet.get_address(),
-            to
+            outputs.len(),
+            fee
         );
-        let mut vin = Vec::new();
-
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
 
-        let acc_v = utxo.find_spendable_outputs(&pub_key_hash, amount)?;
+        let mut tx =
+            Transaction::new_unsigned(&pub_key_hash, change, outputs, fee, coin_control, utxo)?;
+        utxo.blockchain
+            .sign_transacton(&mut tx, &wallet.secret_key)?;
+        Ok(tx)
+    }
 
-        if acc_v.0 < amount {
-            error!("Not Enough balance");
+    /// NewUnsignedTransaction creates a transaction paying the outputs from
+    /// outputs locked to the key or script hash from_hash, which still needs
+    /// to be signed
+    ///
+    /// The outputs spent are chosen by the selector of coin_control among
+    /// those it does not exclude, and the change goes back to an output locked by change unless it is dust, which
+    /// is left to the miner
+    pub fn new_unsigned(
+        from_hash: &[u8],
+        change: Script,
+        outputs: Vec<TXOutput>,
+        fee: Amount,
+        coin_control: &CoinControl,
+        utxo: &UTXOSet,
+    ) -> Result<Transaction> {
+        if outputs.is_empty() {
+            return Err(format_err!("ERROR: Transaction pays no one"));
+        }
+        if outputs.iter().any(|out| out.value == 0) {
+            return Err(format_err!("ERROR: Amount must be positive"));
+        }
+        if outputs
+            .iter()
+            .any(|out| out.value < coin_control.dust_threshold)
+        {
             return Err(format_err!(
-                "Not Enough balance: current balance {}",
-                acc_v.0
+                "ERROR: Amount is below the dust threshold {}",
+                coin_control.dust_threshold
             ));
         }
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
-            }
-        }
+        let total = checked_add(checked_sum(outputs.iter().map(|out| out.value))?, fee)?;
+        let mut utxos = utxo.find_spendable(from_hash)?;
+        utxos.retain(|u| !coin_control.exclude.contains(&(u.txid.clone(), u.vout)));
+        let selected =
+            match coin_control
+                .selector
+                .select(&utxos, total, coin_control.dust_threshold)
+            {
+                Some(selected) => selected,
+                None => {
+                    error!("Not Enough balance");
+                    return Err(format_err!(
+                        "Not Enough balance: current balance {}",
+                        checked_sum(utxos.iter().map(|u| u.value))?
+                    ));
+                }
+            };
+        let accumulated = checked_sum(selected.iter().map(|u| u.value))?;
+
+        let vin = selected
+            .into_iter()
+            .map(|u| TXInput {
+                txid: u.txid,
+                vout: u.vout,
+                script_sig: Script::default(),
+            })
+            .collect();
 
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+        let mut vout = outputs;
+        if accumulated - total >= coin_control.dust_threshold.max(1) {
+            vout.push(TXOutput {
+                value: accumulated - total,
+                script_pubkey: change,
+            })
         }
 
         let mut tx = Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
-        utxo.blockchain
-            .sign_transacton(&mut tx, &wallet.secret_key)?;
         Ok(tx)
     }
 
-    /// NewCoinbaseTX creates a new coinbase transaction
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        info!("new coinbase Transaction to: {}", to);
+    /// NewCoinbaseTX creates a new coinbase transaction paying reward
+    ///
+    /// The reward is at most the block subsidy plus the fees of the other
+    /// transactions in the block. The data ends with an extra-nonce, 0 until
+    /// the miner has tried every nonce of the block
+    pub fn new_coinbase(to: String, mut data: String, reward: Amount) -> Result<Transaction> {
+        info!("new coinbase Transaction to: {} reward: {}", to, reward);
         let mut key: [u8; 32] = [0; 32];
         if data.is_empty() {
             let mut rand = rand::OsRng::new().unwrap();
             rand.fill_bytes(&mut key);
             data = format!("Reward to '{}'", to);
         }
-        let mut pub_key = Vec::from(data.as_bytes());
-        pub_key.append(&mut Vec::from(key));
+        let mut data = Vec::from(data.as_bytes());
+        data.append(&mut Vec::from(key));
+        data.extend_from_slice(&0u64.to_le_bytes());
 
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: Vec::new(),
-                pub_key,
+                script_sig: Script(vec![Op::Push(data)]),
             }],
-            vout: vec![TXOutput::new(SUBSIDY, to)?],
+            vout: vec![TXOutput::new(reward, to)?],
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    /// ExtraNonce returns the extra-nonce ending the data of a coinbase
+    pub fn extra_nonce(&self) -> Option<u64> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        match self.vin[0].script_sig.0.first() {
+            Some(Op::Push(data)) if data.len() >= EXTRA_NONCE_LEN => {
+                let mut bytes = [0; EXTRA_NONCE_LEN];
+                bytes.copy_from_slice(&data[data.len() - EXTRA_NONCE_LEN..]);
+                Some(u64::from_le_bytes(bytes))
+            }
+            _ => None,
+        }
+    }
+
+    /// SetExtraNonce replaces the extra-nonce ending the data of a coinbase,
+    /// and recomputes its id
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) -> Result<()> {
+        if self.extra_nonce().is_none() {
+            return Err(format_err!("ERROR: Coinbase has no extra-nonce"));
+        }
+        if let Some(Op::Push(data)) = self.vin[0].script_sig.0.first_mut() {
+            let start = data.len() - EXTRA_NONCE_LEN;
+            data[start..].copy_from_slice(&extra_nonce.to_le_bytes());
+        }
+        self.id = self.hash()?;
+        Ok(())
+    }
+
     /// IsCoinbase checks whether the transaction is coinbase
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    /// Verify verifies signatures of Transaction inputs
+    /// IsFinal checks whether the transaction may be included in a block at height
+    pub fn is_final(&self, height: i32) -> bool {
+        self.lock_time <= height
+    }
+
+    /// Fee returns the value of the inputs that is not claimed by the outputs
+    ///
+    /// It fails if a value or a sum of values is above MAX_MONEY, or the
+    /// outputs spend more than the inputs
+    pub fn fee(&self, prev_TXs: &HashMap<String, Transaction>) -> Result<Amount> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+
+        let mut input_value: Amount = 0;
+        for vin in &self.vin {
+            let prev_out = prev_TXs
+                .get(&vin.txid)
+                .and_then(|prev_Tx| prev_Tx.vout.get(vin.vout as usize))
+                .ok_or_else(|| format_err!("ERROR: Previous output is not found"))?;
+            input_value = checked_add(input_value, prev_out.value)
+                .map_err(|_| format_err!("ERROR: Input value out of range"))?;
+        }
+
+        let output_value = self.output_value()?;
+
+        if output_value > input_value {
+            return Err(format_err!(
+                "ERROR: Outputs {} exceed inputs {}",
+                output_value,
+                input_value
+            ));
+        }
+        Ok(input_value - output_value)
+    }
+
+    /// OutputValue returns the value of the outputs
+    ///
+    /// It fails if an output or their sum is above MAX_MONEY
+    pub fn output_value(&self) -> Result<Amount> {
+        checked_sum(self.vout.iter().map(|out| out.value))
+            .map_err(|_| format_err!("ERROR: Output value out of range"))
+    }
+
+    /// Verify runs the scripts of the Transaction inputs and checks that it does not create value
     pub fn verify(&self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
@@ -138,23 +312,13 @@ impl Transaction {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
+        self.fee(&prev_TXs)?;
 
-        let mut tx_copy = self.trim_copy();
-
-        for in_id in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[self.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[in_id].pub_key,
-                &self.vin[in_id].signature,
-            ) {
+        for (in_id, vin) in self.vin.iter().enumerate() {
+            let prev_Tx = prev_TXs.get(&vin.txid).unwrap();
+            let script_pubkey = &prev_Tx.vout[vin.vout as usize].script_pubkey;
+            if let Err(e) = verify_script(&vin.script_sig, script_pubkey, self, in_id) {
+                debug!("input {} of {} is not valid: {}", in_id, self.id, e);
                 return Ok(false);
             }
         }
@@ -162,7 +326,8 @@ impl Transaction {
         Ok(true)
     }
 
-    /// Sign signs each input of a Transaction
+    /// Sign signs the inputs of a Transaction spending pay to pub key hash
+    /// outputs of the key
     pub fn sign(
         &mut self,
         private_key: &[u8],
@@ -178,23 +343,101 @@ impl Transaction {
             }
         }
 
-        let mut tx_copy = self.trim_copy();
+        let pub_key = private_key[32..].to_vec();
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
 
-        for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+        for in_id in 0..self.vin.len() {
+            let prev_Tx = prev_TXs.get(&self.vin[in_id].txid).unwrap();
+            let script_pubkey = &prev_Tx.vout[self.vin[in_id].vout as usize].script_pubkey;
+            if script_pubkey.get_pub_key_hash() != Some(pub_key_hash.as_slice()) {
+                continue;
+            }
+            let hash = self.signature_hash(in_id, script_pubkey)?;
+            let signature = ed25519::signature(hash.as_bytes(), private_key);
+            self.vin[in_id].script_sig = Script(vec![
+                Op::Push(signature.to_vec()),
+                Op::Push(pub_key.clone()),
+            ]);
         }
 
         Ok(())
     }
 
+    /// SignMultisig adds the signature of the key to the inputs of a Transaction
+    /// spending outputs paying to the hash of the multisig redeem_script
+    ///
+    /// The signatures already in the script_sig are kept in the order of the
+    /// keys, the input is complete once it holds m of them. It returns the
+    /// number of inputs signed
+    pub fn sign_multisig(
+        &mut self,
+        private_key: &[u8],
+        redeem_script: &Script,
+        prev_TXs: HashMap<String, Transaction>,
+    ) -> Result<usize> {
+        let (m, pub_keys) = match redeem_script.get_multisig() {
+            Some(multisig) => multisig,
+            None => return Err(format_err!("ERROR: Redeem script is not a multisig")),
+        };
+        let pub_key = private_key[32..].to_vec();
+        let key_id = match pub_keys.iter().position(|k| *k == pub_key) {
+            Some(key_id) => key_id,
+            None => return Err(format_err!("ERROR: Key is not part of the multisig")),
+        };
+        let script_hash = redeem_script.hash()?;
+        let redeem_data = serialize(redeem_script)?;
+
+        let mut signed = 0;
+        for in_id in 0..self.vin.len() {
+            let prev_out = prev_TXs
+                .get(&self.vin[in_id].txid)
+                .and_then(|prev_Tx| prev_Tx.vout.get(self.vin[in_id].vout as usize))
+                .ok_or_else(|| format_err!("ERROR: Previous output is not found"))?;
+            if prev_out.script_pubkey.get_script_hash() != Some(script_hash.as_slice()) {
+                continue;
+            }
+
+            let hash = self.signature_hash(in_id, redeem_script)?;
+            let mut signatures: Vec<(usize, Vec<u8>)> = Vec::new();
+            if let Some((_, pushes)) = self.vin[in_id].script_sig.0.split_last() {
+                for op in pushes {
+                    if let Op::Push(signature) = op {
+                        let signer = pub_keys.iter().position(|k| {
+                            signature.len() == 64 && ed25519::verify(hash.as_bytes(), k, signature)
+                        });
+                        if let Some(signer) = signer {
+                            signatures.push((signer, signature.clone()));
+                        }
+                    }
+                }
+            }
+            if !signatures.iter().any(|(signer, _)| *signer == key_id) {
+                let signature = ed25519::signature(hash.as_bytes(), private_key);
+                signatures.push((key_id, signature.to_vec()));
+            }
+            signatures.sort_by_key(|(signer, _)| *signer);
+            signatures.truncate(m);
+
+            let mut ops: Vec<Op> = signatures.into_iter().map(|(_, s)| Op::Push(s)).collect();
+            ops.push(Op::Push(redeem_data.clone()));
+            self.vin[in_id].script_sig = Script(ops);
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    /// SignatureHash returns the hash signed for input in_id
+    ///
+    /// It is the hash of the transaction without any script_sig, where the
+    /// script_sig of input in_id is replaced by script_code, the script of the
+    /// output it spends
+    pub fn signature_hash(&self, in_id: usize, script_code: &Script) -> Result<String> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[in_id].script_sig = script_code.clone();
+        tx_copy.hash()
+    }
+
     /// Hash returns the hash of the Transaction
     pub fn hash(&self) -> Result<String> {
         let mut copy = self.clone();
@@ -205,52 +448,61 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    /// HasValidId checks that the id is the hash of the transaction without its
+    /// script_sigs, except for a coinbase whose script_sig is its data
+    pub fn has_valid_id(&self) -> Result<bool> {
+        if self.is_coinbase() {
+            return Ok(self.hash()? == self.id);
+        }
+        Ok(self.trim_copy().hash()? == self.id)
+    }
+
     /// TrimmedCopy creates a trimmed copy of Transaction to be used in signing
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::new();
-        let mut vout = Vec::new();
 
         for v in &self.vin {
             vin.push(TXInput {
                 txid: v.txid.clone(),
-                vout: v.vout.clone(),
-                signature: Vec::new(),
-                pub_key: Vec::new(),
-            })
-        }
-
-        for v in &self.vout {
-            vout.push(TXOutput {
-                value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
+                vout: v.vout,
+                script_sig: Script::default(),
             })
         }
 
         Transaction {
             id: self.id.clone(),
             vin,
-            vout,
+            vout: self.vout.clone(),
+            lock_time: self.lock_time,
         }
     }
 }
 
 impl TXOutput {
-    /// IsLockedWithKey checks if the output can be used by the owner of the pubkey
+    /// IsLockedWithKey checks if the output pays to the pubkey hash or to the
+    /// script hash, that is to the body of an address
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
-        self.pub_key_hash == pub_key_hash
+        self.script_pubkey.get_pub_key_hash() == Some(pub_key_hash)
+            || self.script_pubkey.get_script_hash() == Some(pub_key_hash)
     }
-    /// Lock signs the output
+    /// Lock locks the output to the address
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
-        debug!("lock: {}", address);
-        self.pub_key_hash = pub_key_hash;
+        let address = match Address::decode(address) {
+            Ok(address) => address,
+            Err(_) => return Err(format_err!("ERROR: Invalid address {}", address)),
+        };
+        debug!("lock: {:?}", address);
+        self.script_pubkey = match address.hash_type {
+            HashType::Key => Script::pay_to_pub_key_hash(&address.body),
+            HashType::Script => Script::pay_to_script_hash(&address.body),
+        };
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
-            pub_key_hash: Vec::new(),
+            script_pubkey: Script::default(),
         };
         txo.lock(&address)?;
         Ok(txo)
@@ -260,20 +512,58 @@ impl TXOutput {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::blockchain::INITIAL_SUBSIDY;
 
     #[test]
     fn test_signature() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let data_dir = "data/test_signature";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
         let w = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
         drop(ws);
 
         let data = String::from("test");
-        let tx = Transaction::new_coinbase(wa1, data).unwrap();
+        let tx = Transaction::new_coinbase(wa1, data, INITIAL_SUBSIDY).unwrap();
         assert!(tx.is_coinbase());
 
         let signature = ed25519::signature(tx.id.as_bytes(), &w.secret_key);
         assert!(ed25519::verify(tx.id.as_bytes(), &w.public_key, &signature));
     }
+
+    #[test]
+    fn test_fee() {
+        let data_dir = "data/test_fee";
+        std::fs::remove_dir_all(data_dir).ok();
+        let address = Wallets::new(data_dir).unwrap().create_wallet().unwrap();
+        let prev =
+            Transaction::new_coinbase(address.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let mut prev_TXs = HashMap::new();
+        prev_TXs.insert(prev.id.clone(), prev.clone());
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                script_sig: Script::default(),
+            }],
+            vout: vec![TXOutput::new(INITIAL_SUBSIDY - 3, address.clone()).unwrap()],
+            lock_time: 0,
+        };
+        assert_eq!(tx.fee(&prev_TXs).unwrap(), 3);
+
+        tx.vout[0].value = INITIAL_SUBSIDY + 1;
+        tx.fee(&prev_TXs).unwrap_err();
+
+        tx.vout[0].value = MAX_MONEY + 1;
+        tx.fee(&prev_TXs).unwrap_err();
+        tx.vout[0].value = 4;
+        tx.vout.push(TXOutput::new(MAX_MONEY, address).unwrap());
+        tx.output_value().unwrap_err();
+
+        TXOutput::new(1, String::from("not an address")).unwrap_err();
+        Transaction::new_coinbase(String::from("not an address"), String::new(), 1).unwrap_err();
+    }
 }
@@ -1,75 +1,148 @@
 //! unspend transaction output set
 
 use super::*;
+use crate::amount::*;
 use crate::block::*;
 use crate::blockchain::*;
+use crate::coinselect::*;
 use crate::transaction::*;
 use bincode::{deserialize, serialize};
-use sled;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+};
+use sled::Transactional;
 use std::collections::HashMap;
 
+/// UTXO_VERSION is the format of the UTXO db, which is rebuilt when it changes
+const UTXO_VERSION: &[u8] = b"4";
+/// UNDO_TREE maps the hash of each block applied to the UTXO set to the
+/// outputs it spent
+const UNDO_TREE: &str = "undo";
+/// META_TREE keeps the format of the UTXO db and the block it is at
+const META_TREE: &str = "meta";
+const VERSION_KEY: &str = "version";
+/// TIP_KEY is the hash of the last block applied to the UTXO set
+const TIP_KEY: &str = "tip";
+
 /// UTXOSet represents UTXO set
 pub struct UTXOSet {
     pub blockchain: Blockchain,
+    db: sled::Db,
+}
+
+/// Balance is the value of the unspent outputs of an address
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Balance {
+    /// Spendable is the value the next block may spend
+    pub spendable: Amount,
+    /// Immature is the value of the coinbase outputs that did not mature yet
+    pub immature: Amount,
+}
+
+/// SpentOutput is the undo data kept for an output removed by Update
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SpentOutput {
+    txid: String,
+    vout: i32,
+    output: TXOutput,
+    height: i32,
+    is_coinbase: bool,
 }
 
 impl UTXOSet {
-    /// FindUnspentTransactions returns a list of transactions containing unspent outputs
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
-
-        let db = sled::open("data/utxos")?;
+    /// NewUTXOSet opens the UTXO db kept next to the blocks of the blockchain
+    ///
+    /// A db of an older format, or left behind the tip of the blockchain by a
+    /// node stopped between storing a block and updating the UTXO set, is rebuilt
+    pub fn new(blockchain: Blockchain) -> Result<UTXOSet> {
+        let db = open_db(&blockchain.data_dir.join("utxos"))?;
+        let utxo = UTXOSet { blockchain, db };
+        let meta = utxo.db.open_tree(META_TREE)?;
+        if meta.get(VERSION_KEY)?.as_deref() != Some(UTXO_VERSION) {
+            info!("rebuild the UTXO set of an older format");
+            utxo.reindex()?;
+        } else if meta.get(TIP_KEY)?.as_deref() != Some(utxo.blockchain.tip.as_bytes()) {
+            warn!("the UTXO set is not at the tip of the blockchain, rebuild it");
+            utxo.reindex()?;
+        }
+        Ok(utxo)
+    }
+
+    /// FindSpendable returns the unspent outputs locked to the key or script
+    /// hash that the next block may spend, leaving out immature coinbases
+    pub fn find_spendable(&self, pub_key_hash: &[u8]) -> Result<Vec<Utxo>> {
+        let spend_height = self.blockchain.get_best_height()? + 1;
+        let mut utxos = Vec::new();
+        let db = &self.db;
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let outs: TXOutputs = deserialize(&v)?;
+            if !self.is_mature(&outs, spend_height) {
+                continue;
+            }
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+            for (out_idx, out) in &outs.outputs {
+                if out.is_locked_with_key(pub_key_hash) {
+                    utxos.push(Utxo {
+                        txid: txid.clone(),
+                        vout: *out_idx,
+                        value: out.value,
+                        height: outs.height,
+                    });
                 }
             }
         }
+        utxos.sort_by(|a, b| (&a.txid, a.vout).cmp(&(&b.txid, b.vout)));
+        Ok(utxos)
+    }
 
-        Ok((accumulated, unspent_outputs))
+    /// GetOutputs returns the unspent outputs of a transaction
+    pub fn get_outputs(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.db.get(txid)? {
+            Some(data) => Ok(Some(deserialize(&data)?)),
+            None => Ok(None),
+        }
     }
 
-    /// FindUTXO finds UTXO for a public key hash
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs {
-            outputs: Vec::new(),
-        };
-        let db = sled::open("data/utxos")?;
+    /// GetBalance returns the value of the unspent outputs locked to the key or
+    /// script hash
+    pub fn get_balance(&self, pub_key_hash: &[u8]) -> Result<Balance> {
+        let spend_height = self.blockchain.get_best_height()? + 1;
+        let mut balance = Balance::default();
+        let db = &self.db;
 
         for kv in db.iter() {
             let (_, v) = kv?;
             let outs: TXOutputs = deserialize(&v.to_vec())?;
+            let mature = self.is_mature(&outs, spend_height);
 
-            for out in outs.outputs {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.outputs.push(out.clone())
+            for out in outs.outputs.values() {
+                if !out.is_locked_with_key(pub_key_hash) {
+                    continue;
+                }
+                if mature {
+                    balance.spendable = checked_add(balance.spendable, out.value)?;
+                } else {
+                    balance.immature = checked_add(balance.immature, out.value)?;
                 }
             }
         }
 
-        Ok(utxos)
+        Ok(balance)
+    }
+
+    /// IsMature checks whether the block at spend_height may spend the outputs
+    fn is_mature(&self, outs: &TXOutputs, spend_height: i32) -> bool {
+        !outs.is_coinbase || self.blockchain.params.is_mature(outs.height, spend_height)
     }
 
     /// CountTransactions returns the number of transactions in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
+        let db = &self.db;
         for kv in db.iter() {
             kv?;
             counter += 1;
@@ -78,55 +151,291 @@ impl UTXOSet {
     }
 
     /// Reindex rebuilds the UTXO set
+    ///
+    /// The undo data of the blocks of the chain is lost, so that they can no
+    /// longer be rolled back
     pub fn reindex(&self) -> Result<()> {
-        std::fs::remove_dir_all("data/utxos").ok();
-        let db = sled::open("data/utxos")?;
+        self.db.clear()?;
+        self.db.open_tree(UNDO_TREE)?.clear()?;
+        let meta = self.db.open_tree(META_TREE)?;
+        meta.insert(VERSION_KEY, UTXO_VERSION)?;
+        meta.insert(TIP_KEY, self.blockchain.tip.as_bytes())?;
 
         let utxos = self.blockchain.find_UTXO();
 
         for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), serialize(&outs)?)?;
+            self.db.insert(txid.as_bytes(), serialize(&outs)?)?;
         }
 
+        self.db.flush()?;
         Ok(())
     }
 
     /// Update updates the UTXO set with transactions from the Block
     ///
-    /// The Block is considered to be the tip of a blockchain
+    /// The Block is considered to be the tip of a blockchain. The outputs it
+    /// spends are kept as undo data so that Rollback can restore them, and are
+    /// written with the UTXO set in one transaction
     pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let meta = self.db.open_tree(META_TREE)?;
+        (&*self.db, &undo_tree, &meta)
+            .transaction(|(utxos, undo_tree, meta)| {
+                let mut undo = Vec::new();
+                for tx in block.get_transaction() {
+                    if !tx.is_coinbase() {
+                        for vin in &tx.vin {
+                            let mut update_outputs: TXOutputs = match utxos.get(&vin.txid)? {
+                                Some(data) => deserialize(&data).or_else(abort)?,
+                                None => {
+                                    return abort(format_err!(
+                                        "ERROR: Transaction {} is not in the UTXO set",
+                                        vin.txid
+                                    ))
+                                }
+                            };
+                            if let Some(output) = update_outputs.outputs.remove(&vin.vout) {
+                                undo.push(SpentOutput {
+                                    txid: vin.txid.clone(),
+                                    vout: vin.vout,
+                                    output,
+                                    height: update_outputs.height,
+                                    is_coinbase: update_outputs.is_coinbase,
+                                });
+                            }
 
-        for tx in block.get_transaction() {
-            if !tx.is_coinbase() {
-                for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new(),
-                    };
-                    let outs: TXOutputs = deserialize(&db.get(&vin.txid)?.unwrap().to_vec())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
+                            if update_outputs.outputs.is_empty() {
+                                utxos.remove(vin.txid.as_bytes())?;
+                            } else {
+                                let data = serialize(&update_outputs).or_else(abort)?;
+                                utxos.insert(vin.txid.as_bytes(), data)?;
+                            }
                         }
                     }
 
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), serialize(&update_outputs)?)?;
+                    let mut new_outputs = TXOutputs {
+                        outputs: HashMap::new(),
+                        height: block.get_height(),
+                        is_coinbase: tx.is_coinbase(),
+                    };
+                    for (out_idx, out) in tx.vout.iter().enumerate() {
+                        new_outputs.outputs.insert(out_idx as i32, out.clone());
                     }
+                    utxos.insert(tx.id.as_bytes(), serialize(&new_outputs).or_else(abort)?)?;
                 }
-            }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new(),
-            };
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
-            }
+                undo_tree.insert(
+                    block.get_hash().as_bytes(),
+                    serialize(&undo).or_else(abort)?,
+                )?;
+                meta.insert(TIP_KEY, block.get_hash().as_bytes())?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Rollback reverts Update for a Block being disconnected from the tip
+    pub fn rollback(&self, block: &Block) -> Result<()> {
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let meta = self.db.open_tree(META_TREE)?;
+        (&*self.db, &undo_tree, &meta)
+            .transaction(|(utxos, undo_tree, meta)| {
+                let mut undo: Vec<SpentOutput> = match undo_tree.get(block.get_hash())? {
+                    Some(data) => deserialize(&data).or_else(abort)?,
+                    None => {
+                        return abort(format_err!(
+                            "ERROR: No undo data for block {}",
+                            block.get_hash()
+                        ))
+                    }
+                };
+
+                for tx in block.get_transaction().iter().rev() {
+                    utxos.remove(tx.id.as_bytes())?;
+                    if tx.is_coinbase() {
+                        continue;
+                    }
+                    for _ in &tx.vin {
+                        let spent = match undo.pop() {
+                            Some(spent) => spent,
+                            None => return abort(format_err!("ERROR: Undo data is incomplete")),
+                        };
+                        let mut outs = match utxos.get(&spent.txid)? {
+                            Some(data) => deserialize(&data).or_else(abort)?,
+                            None => TXOutputs {
+                                outputs: HashMap::new(),
+                                height: spent.height,
+                                is_coinbase: spent.is_coinbase,
+                            },
+                        };
+                        outs.outputs.insert(spent.vout, spent.output);
+                        utxos.insert(spent.txid.as_bytes(), serialize(&outs).or_else(abort)?)?;
+                    }
+                }
 
-            db.insert(tx.id.as_bytes(), serialize(&new_outputs)?)?;
+                undo_tree.remove(block.get_hash().as_bytes())?;
+                meta.insert(TIP_KEY, block.get_prev_hash().as_bytes())?;
+                Ok(())
+            })
+            .map_err(transaction_error)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// ApplyChainUpdate rolls back the disconnected blocks and applies the connected ones
+    pub fn apply_chain_update(&self, update: &ChainUpdate) -> Result<()> {
+        for block in &update.disconnected {
+            self.rollback(block)?;
+        }
+        for block in &update.connected {
+            self.update(block)?;
         }
         Ok(())
     }
 }
+
+/// Abort aborts a transaction of the UTXO db with an error
+fn abort<T, E: Into<failure::Error>>(e: E) -> ConflictableTransactionResult<T, failure::Error> {
+    Err(ConflictableTransactionError::Abort(e.into()))
+}
+
+/// TransactionError returns the error a transaction of the UTXO db failed with
+fn transaction_error(e: TransactionError<failure::Error>) -> failure::Error {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::script::*;
+    use crate::wallets::*;
+    use bitcoincash_addr::Address;
+
+    fn balance(utxo_set: &UTXOSet, address: &str) -> Balance {
+        let pub_key_hash = Address::decode(address).unwrap().body;
+        utxo_set.get_balance(&pub_key_hash).unwrap()
+    }
+
+    #[test]
+    fn test_reorganize() {
+        let data_dir = "data/test_reorganize";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addr1 = ws.create_wallet().unwrap();
+        let addr2 = ws.create_wallet().unwrap();
+        let mut bc = Blockchain::create_blockchain(addr1.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let genesis = bc.tip.clone();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+
+        let cbtx =
+            Transaction::new_coinbase(addr1.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let a1 = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo_set.update(&a1).unwrap();
+        assert_eq!(balance(&utxo_set, &addr1).spendable, 20 * COIN);
+
+        let cbtx =
+            Transaction::new_coinbase(addr2.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let b1 = Block::new_block(vec![cbtx], genesis, 1, INITIAL_BITS).unwrap();
+        let update = utxo_set.blockchain.add_block(b1.clone()).unwrap();
+        assert!(update.connected.is_empty());
+        assert_eq!(utxo_set.blockchain.tip, a1.get_hash());
+
+        let cbtx =
+            Transaction::new_coinbase(addr2.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let b2 = Block::new_block(vec![cbtx], b1.get_hash(), 2, INITIAL_BITS).unwrap();
+        let update = utxo_set.blockchain.add_block(b2.clone()).unwrap();
+        let disconnected: Vec<String> = update.disconnected.iter().map(|b| b.get_hash()).collect();
+        let connected: Vec<String> = update.connected.iter().map(|b| b.get_hash()).collect();
+        assert_eq!(disconnected, vec![a1.get_hash()]);
+        assert_eq!(connected, vec![b1.get_hash(), b2.get_hash()]);
+        assert_eq!(utxo_set.blockchain.tip, b2.get_hash());
+
+        utxo_set.apply_chain_update(&update).unwrap();
+        assert_eq!(balance(&utxo_set, &addr1).spendable, 10 * COIN);
+        assert_eq!(balance(&utxo_set, &addr2).spendable, 20 * COIN);
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let data_dir = "data/test_coinbase_maturity";
+        std::fs::remove_dir_all(data_dir).ok();
+        let params = ChainParams::default();
+        assert_eq!(params.coinbase_maturity, COINBASE_MATURITY);
+        assert!(!params.is_mature(5, 5 + COINBASE_MATURITY - 1));
+        assert!(params.is_mature(5, 5 + COINBASE_MATURITY));
+
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addr1 = ws.create_wallet().unwrap();
+        let addr2 = ws.create_wallet().unwrap();
+        let wallet = ws.get_wallet(&addr1).unwrap().clone();
+        let mut bc = Blockchain::create_blockchain(addr1.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 3,
+            ..Default::default()
+        })
+        .unwrap();
+        let genesis = bc.get_block(&bc.tip).unwrap();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+
+        let immature = Balance {
+            spendable: 0,
+            immature: INITIAL_SUBSIDY,
+        };
+        assert_eq!(balance(&utxo_set, &addr1), immature);
+        Transaction::new_UTXO(&wallet, &addr2, COIN, 0, &CoinControl::default(), &utxo_set)
+            .unwrap_err();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis.get_transaction()[0].id.clone(),
+                vout: 0,
+                script_sig: Script::default(),
+            }],
+            vout: vec![TXOutput::new(COIN, addr2.clone()).unwrap()],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        utxo_set
+            .blockchain
+            .sign_transacton(&mut tx, &wallet.secret_key)
+            .unwrap();
+        assert!(!utxo_set.blockchain.verify_transacton(&tx).unwrap());
+        let cbtx =
+            Transaction::new_coinbase(addr2.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let block =
+            Block::new_block(vec![cbtx, tx.clone()], genesis.get_hash(), 1, INITIAL_BITS).unwrap();
+        let err = utxo_set.blockchain.validate_block(&block).unwrap_err();
+        match err.downcast_ref::<BlockError>() {
+            Some(BlockError::ImmatureSpend(_, _)) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        for _ in 0..2 {
+            let cbtx =
+                Transaction::new_coinbase(addr1.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo_set.update(&block).unwrap();
+        }
+        assert!(utxo_set.blockchain.verify_transacton(&tx).unwrap());
+        let mature = Balance {
+            spendable: INITIAL_SUBSIDY,
+            immature: 2 * INITIAL_SUBSIDY,
+        };
+        assert_eq!(balance(&utxo_set, &addr1), mature);
+        Transaction::new_UTXO(&wallet, &addr2, COIN, 0, &CoinControl::default(), &utxo_set)
+            .unwrap();
+    }
+}
@@ -1,16 +1,44 @@
 //! bitcoin wallet
 
 use super::*;
+use crate::amount::*;
+use crate::block::*;
+use crate::blockchain::*;
+use crate::script::*;
+use crate::transaction::*;
 use bincode::{deserialize, serialize};
+use bip39::Mnemonic;
 use bitcoincash_addr::*;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
 use crypto::digest::Digest;
 use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::ripemd160::Ripemd160;
-use crypto::sha2::Sha256;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::{Sha256, Sha512};
+use failure::format_err;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sled;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// SCRYPT_LOG_N is the cost of deriving the encryption key from the passphrase
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// PASSPHRASE_CHECK is encrypted along with the keys to check a passphrase
+const PASSPHRASE_CHECK: &[u8] = b"wallet passphrase";
+/// MNEMONIC_ENTROPY_LEN is the entropy of a new mnemonic, 16 bytes make 12 words
+const MNEMONIC_ENTROPY_LEN: usize = 16;
+/// HD_PATH is the derivation path of the keys, m/44'/0'/0'/0'/index'
+///
+/// Every level is hardened, ed25519 has no public derivation
+const HD_PATH: [u32; 4] = [44, 0, 0, 0];
+const HARDENED: u32 = 0x8000_0000;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
@@ -19,12 +47,18 @@ pub struct Wallet {
 }
 
 impl Wallet {
-    /// NewWallet creates and returns a Wallet
+    /// NewWallet creates and returns a Wallet with a random key
+    #[cfg(test)]
     fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         let mut rand = rand::OsRng::new().unwrap();
         rand.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        Wallet::from_key(&key)
+    }
+
+    /// FromKey creates the Wallet of a 32 bytes ed25519 private key
+    fn from_key(key: &[u8]) -> Self {
+        let (secret_key, public_key) = ed25519::keypair(key);
         let secret_key = secret_key.to_vec();
         let public_key = public_key.to_vec();
         Wallet {
@@ -40,13 +74,27 @@ impl Wallet {
         let address = Address {
             body: pub_hash,
             scheme: Scheme::Base58,
-            hash_type: HashType::Script,
+            hash_type: HashType::Key,
             ..Default::default()
         };
         address.encode().unwrap()
     }
 }
 
+/// GetScriptAddress returns the address of outputs paying to the redeem script
+pub fn get_script_address(redeem_script: &Script) -> Result<String> {
+    let address = Address {
+        body: redeem_script.hash()?,
+        scheme: Scheme::Base58,
+        hash_type: HashType::Script,
+        ..Default::default()
+    };
+    match address.encode() {
+        Ok(address) => Ok(address),
+        Err(_) => Err(format_err!("ERROR: cannot encode script address")),
+    }
+}
+
 /// HashPubKey hashes public key
 pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
@@ -58,35 +106,535 @@ pub fn hash_pub_key(pubKey: &mut Vec<u8>) {
     hasher2.result(pubKey);
 }
 
+/// WalletTx is a transaction of the active chain paying to or from the wallet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WalletTx {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    pub timestamp: u128,
+    pub is_coinbase: bool,
+    /// Amount is what the wallet received minus what it spent
+    pub amount: i64,
+    /// Ledger is the amount received minus spent by each address of the wallet
+    pub ledger: BTreeMap<String, i64>,
+    /// Credits are the outputs paying to the wallet: vout, address and value
+    pub credits: Vec<(i32, String, Amount)>,
+    /// Counterparties are the outputs paying outside the wallet: address and value
+    pub counterparties: Vec<(String, Amount)>,
+}
+
+impl WalletTx {
+    /// Confirmations returns the number of blocks on top of the transaction, its own included
+    pub fn confirmations(&self, best_height: i32) -> i32 {
+        best_height - self.height + 1
+    }
+}
+
+/// Sealed is data encrypted with ChaCha20Poly1305
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Sealed {
+    nonce: Vec<u8>,
+    data: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+/// EncryptedWallet is a Wallet whose secret key is encrypted with the passphrase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedWallet {
+    public_key: Vec<u8>,
+    secret_key: Sealed,
+}
+
+/// KeyParams are how the encryption key is derived from the passphrase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyParams {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    check: Sealed,
+}
+
+impl EncryptedWallet {
+    fn new(key: &[u8], wallet: &Wallet) -> EncryptedWallet {
+        EncryptedWallet {
+            public_key: wallet.public_key.clone(),
+            secret_key: seal(key, &wallet.secret_key, &wallet.public_key),
+        }
+    }
+}
+
+impl KeyParams {
+    /// NewKeyParams creates KeyParams deriving the key with scrypt at the given
+    /// cost, the salt is chosen when a wallet is encrypted
+    pub fn new(log_n: u8, r: u32, p: u32) -> KeyParams {
+        KeyParams {
+            salt: Vec::new(),
+            log_n,
+            r,
+            p,
+            check: Sealed::default(),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Vec<u8> {
+        let mut key = vec![0; 32];
+        let params = ScryptParams::new(self.log_n, self.r, self.p);
+        scrypt(passphrase.as_bytes(), &self.salt, &params, &mut key);
+        key
+    }
+}
+
+impl Default for KeyParams {
+    fn default() -> Self {
+        KeyParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)
+    }
+}
+
+/// ExtendedKey is a SLIP-0010 ed25519 private key with its chain code
+struct ExtendedKey {
+    key: Vec<u8>,
+    chain_code: Vec<u8>,
+}
+
+impl ExtendedKey {
+    fn hmac(key: &[u8], data: &[&[u8]]) -> ExtendedKey {
+        let mut hmac = Hmac::new(Sha512::new(), key);
+        for d in data {
+            hmac.input(d);
+        }
+        let result = hmac.result();
+        let (key, chain_code) = result.code().split_at(32);
+        ExtendedKey {
+            key: key.to_vec(),
+            chain_code: chain_code.to_vec(),
+        }
+    }
+
+    /// Master returns the master key of a seed
+    fn master(seed: &[u8]) -> ExtendedKey {
+        ExtendedKey::hmac(b"ed25519 seed", &[seed])
+    }
+
+    /// Child returns the hardened child key at index
+    fn child(&self, index: u32) -> ExtendedKey {
+        let index = (index | HARDENED).to_be_bytes();
+        ExtendedKey::hmac(&self.chain_code, &[&[0], &self.key, &index])
+    }
+}
+
+fn seal(key: &[u8], data: &[u8], aad: &[u8]) -> Sealed {
+    let mut nonce = vec![0; 8];
+    rand::OsRng::new().unwrap().fill_bytes(&mut nonce);
+    let mut sealed = Sealed {
+        nonce,
+        data: vec![0; data.len()],
+        tag: vec![0; 16],
+    };
+    ChaCha20Poly1305::new(key, &sealed.nonce, aad).encrypt(data, &mut sealed.data, &mut sealed.tag);
+    sealed
+}
+
+fn open(key: &[u8], sealed: &Sealed, aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.nonce.len() != 8 || sealed.tag.len() != 16 {
+        return None;
+    }
+    let mut data = vec![0; sealed.data.len()];
+    if ChaCha20Poly1305::new(key, &sealed.nonce, aad).decrypt(&sealed.data, &mut data, &sealed.tag)
+    {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// Wallets keeps the wallets of the node
+///
+/// The keys are derived from the seed of a mnemonic, so that the mnemonic alone
+/// restores them. Once encrypted, the secret keys and the mnemonic are only
+/// stored encrypted with a key derived from the passphrase, and are only
+/// available between Unlock and Lock
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    encrypted: HashMap<String, EncryptedWallet>,
+    mnemonic: Option<String>,
+    sealed_mnemonic: Option<Sealed>,
+    next_index: u32,
+    history: HashMap<String, WalletTx>,
+    synced_tip: String,
+    multisig: HashMap<String, Script>,
+    key_params: Option<KeyParams>,
+    key: Option<Vec<u8>>,
+    unlocked_until: Option<Instant>,
+    db: sled::Db,
 }
 
 impl Wallets {
-    /// NewWallets creates Wallets and fills it from a file if it exists
-    pub fn new() -> Result<Wallets> {
+    /// NewWallets creates Wallets and fills it from the db in data_dir if it exists
+    pub fn new(data_dir: &str) -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            encrypted: HashMap::new(),
+            mnemonic: None,
+            sealed_mnemonic: None,
+            next_index: 0,
+            history: HashMap::new(),
+            synced_tip: String::new(),
+            multisig: HashMap::new(),
+            key_params: None,
+            key: None,
+            unlocked_until: None,
+            db: open_db(&Path::new(data_dir).join("wallets"))?,
         };
-        let db = sled::open("data/wallets")?;
+        let db = wlt.db.clone();
 
         for item in db.into_iter() {
+            let i = item?;
+            let wallet: Wallet = deserialize(&i.1)?;
+            // the address is derived again, as its format changed over time
+            wlt.wallets.insert(wallet.get_address(), wallet);
+        }
+        for item in db.open_tree("multisig")?.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+            let redeem_script = deserialize(&i.1)?;
+            wlt.multisig.insert(address, redeem_script);
+        }
+        for item in db.open_tree("encrypted")?.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            let wallet = deserialize(&i.1)?;
+            wlt.encrypted.insert(address, wallet);
+        }
+        if let Some(params) = db.open_tree("encryption")?.get("params")? {
+            wlt.key_params = Some(deserialize(&params)?);
+        }
+        let hd = db.open_tree("hd")?;
+        if let Some(mnemonic) = hd.get("mnemonic")? {
+            wlt.mnemonic = Some(String::from_utf8(mnemonic.to_vec())?);
+        }
+        if let Some(sealed) = hd.get("sealed_mnemonic")? {
+            wlt.sealed_mnemonic = Some(deserialize(&sealed)?);
+        }
+        if let Some(next_index) = hd.get("next_index")? {
+            wlt.next_index = deserialize(&next_index)?;
+        }
+        for item in db.open_tree("history")?.into_iter() {
+            let i = item?;
+            let wtx: WalletTx = deserialize(&i.1)?;
+            wlt.history.insert(wtx.txid.clone(), wtx);
+        }
+        if let Some(tip) = db.open_tree("sync")?.get("tip")? {
+            wlt.synced_tip = String::from_utf8(tip.to_vec())?;
         }
-        drop(db);
         Ok(wlt)
     }
 
-    /// CreateWallet adds a Wallet to Wallets
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    /// IsEncrypted tells whether the secret keys are encrypted with a passphrase
+    pub fn is_encrypted(&self) -> bool {
+        self.key_params.is_some()
+    }
+
+    /// IsLocked tells whether the secret keys of an encrypted wallet are unavailable
+    pub fn is_locked(&self) -> bool {
+        match self.unlocked_until {
+            Some(until) => self.is_encrypted() && Instant::now() >= until,
+            None => self.is_encrypted(),
+        }
+    }
+
+    /// LockIfExpired forgets the secret keys once the timeout given to Unlock
+    /// has passed, and tells whether they were forgotten
+    pub fn lock_if_expired(&mut self) -> bool {
+        match self.unlocked_until {
+            Some(until) if Instant::now() >= until => {
+                self.lock();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Encrypt encrypts the secret keys with a key derived from the passphrase
+    /// with params, the Wallets are saved and locked
+    pub fn encrypt(&mut self, passphrase: &str, mut params: KeyParams) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(format_err!("ERROR: wallet is already encrypted"));
+        }
+        if passphrase.is_empty() {
+            return Err(format_err!("ERROR: passphrase is empty"));
+        }
+        params.salt = vec![0; 16];
+        rand::OsRng::new()?.fill_bytes(&mut params.salt);
+        let key = params.derive_key(passphrase);
+        params.check = seal(&key, PASSPHRASE_CHECK, &[]);
+        for (address, wallet) in &self.wallets {
+            self.encrypted
+                .insert(address.clone(), EncryptedWallet::new(&key, wallet));
+        }
+        if let Some(mnemonic) = &self.mnemonic {
+            self.sealed_mnemonic = Some(seal(&key, mnemonic.as_bytes(), &[]));
+        }
+        self.key_params = Some(params);
+        self.save_all()?;
+        self.lock();
+        info!("wallet encrypted");
+        Ok(())
+    }
+
+    /// Unlock decrypts the secret keys for timeout
+    pub fn unlock(&mut self, passphrase: &str, timeout: Duration) -> Result<()> {
+        let params = match &self.key_params {
+            Some(params) => params,
+            None => return Err(format_err!("ERROR: wallet is not encrypted")),
+        };
+        let key = params.derive_key(passphrase);
+        if open(&key, &params.check, &[]).as_deref() != Some(PASSPHRASE_CHECK) {
+            return Err(format_err!("ERROR: wrong passphrase"));
+        }
+        for (address, wallet) in &self.encrypted {
+            let secret_key = match open(&key, &wallet.secret_key, &wallet.public_key) {
+                Some(secret_key) => secret_key,
+                None => return Err(format_err!("ERROR: cannot decrypt {}", address)),
+            };
+            self.wallets.insert(
+                address.clone(),
+                Wallet {
+                    secret_key,
+                    public_key: wallet.public_key.clone(),
+                },
+            );
+        }
+        if let Some(sealed) = &self.sealed_mnemonic {
+            match open(&key, sealed, &[]) {
+                Some(mnemonic) => self.mnemonic = Some(String::from_utf8(mnemonic)?),
+                None => return Err(format_err!("ERROR: cannot decrypt the mnemonic")),
+            }
+        }
+        self.key = Some(key);
+        self.unlocked_until = Some(Instant::now() + timeout);
+        Ok(())
+    }
+
+    /// Lock forgets the secret keys of an encrypted wallet
+    pub fn lock(&mut self) {
+        if !self.is_encrypted() {
+            return;
+        }
+        for address in self.encrypted.keys() {
+            self.wallets.remove(address);
+        }
+        if self.sealed_mnemonic.is_some() {
+            self.mnemonic = None;
+        }
+        self.key = None;
+        self.unlocked_until = None;
+    }
+
+    /// HasSeed tells whether the keys are derived from a mnemonic, even when locked
+    pub fn has_seed(&self) -> bool {
+        self.mnemonic.is_some() || self.sealed_mnemonic.is_some()
+    }
+
+    /// GetMnemonic returns the mnemonic backing up the keys, when available
+    pub fn get_mnemonic(&self) -> Option<&str> {
+        if self.sealed_mnemonic.is_some() && self.is_locked() {
+            return None;
+        }
+        self.mnemonic.as_deref()
+    }
+
+    /// Seed returns the seed of the mnemonic, creating a new mnemonic if there is none
+    fn seed(&mut self) -> Result<[u8; 64]> {
+        if !self.has_seed() {
+            let mut entropy = [0; MNEMONIC_ENTROPY_LEN];
+            rand::OsRng::new()?.fill_bytes(&mut entropy);
+            self.mnemonic = Some(Mnemonic::from_entropy(&entropy)?.to_string());
+            info!("create mnemonic");
+        }
+        let mnemonic = match self.get_mnemonic() {
+            Some(mnemonic) => Mnemonic::parse(mnemonic)?,
+            None => return Err(format_err!("ERROR: wallet is locked")),
+        };
+        Ok(mnemonic.to_seed(""))
+    }
+
+    /// CreateWallet adds the Wallet of the next key derived from the seed to Wallets
+    pub fn create_wallet(&mut self) -> Result<String> {
+        let seed = self.seed()?;
+        let wallet = derive_wallet(&seed, self.next_index);
+        self.next_index += 1;
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
         info!("create wallet: {}", address);
-        address
+        Ok(address)
+    }
+
+    /// NonHdKeyCount returns the number of keys not derived from the mnemonic,
+    /// created at random before the wallet had one, which the mnemonic does
+    /// not back up
+    pub fn non_hd_key_count(&self) -> usize {
+        self.get_all_addresses()
+            .len()
+            .saturating_sub(self.next_index as usize)
+    }
+
+    /// Restore sets the mnemonic of an empty Wallets and derives its keys up to
+    /// the last one is_used tells was used, and gap more
+    ///
+    /// is_used is given the key hash of each derived key, and restore returns
+    /// the number of keys found used
+    pub fn restore<F>(&mut self, mnemonic: &str, gap: u32, is_used: F) -> Result<usize>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        if self.has_seed() || !self.get_all_addresses().is_empty() {
+            return Err(format_err!("ERROR: can only restore into an empty wallet"));
+        }
+        let mnemonic = Mnemonic::parse(mnemonic.trim())?;
+        let seed = mnemonic.to_seed("");
+        self.mnemonic = Some(mnemonic.to_string());
+
+        let mut used = 0;
+        let mut unused = 0;
+        let mut index = 0;
+        while unused < gap {
+            let wallet = derive_wallet(&seed, index);
+            let mut pub_key_hash = wallet.public_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            if is_used(&pub_key_hash) {
+                used += 1;
+                unused = 0;
+                self.next_index = index + 1;
+            } else {
+                unused += 1;
+            }
+            index += 1;
+        }
+        // always restore the first key
+        for index in 0..self.next_index.max(1) {
+            let wallet = derive_wallet(&seed, index);
+            self.wallets.insert(wallet.get_address(), wallet);
+        }
+        self.next_index = self.next_index.max(1);
+        self.reset_history();
+        Ok(used)
+    }
+
+    /// GetHistory returns the transactions of the wallet in chain order,
+    /// only those of address when it is given
+    pub fn get_history(&self, address: Option<&str>) -> Vec<&WalletTx> {
+        let mut history: Vec<&WalletTx> = self
+            .history
+            .values()
+            .filter(|wtx| address.is_none_or(|address| wtx.ledger.contains_key(address)))
+            .collect();
+        history.sort_by_key(|wtx| (wtx.height, !wtx.is_coinbase, wtx.txid.clone()));
+        history
+    }
+
+    /// Sync brings the history up to date with the active chain of bc
+    pub fn sync(&mut self, bc: &Blockchain) -> Result<()> {
+        let update = bc.chain_update_since(&self.synced_tip)?;
+        if update.disconnected.is_empty() && update.connected.is_empty() {
+            return Ok(());
+        }
+        // the chain is connected from the genesis block when the synced tip is
+        // gone, e.g. after createblockchain
+        if update.connected.first().map(Block::get_height) == Some(0) {
+            self.history.clear();
+        }
+        self.apply_chain_update(&update);
+        self.synced_tip = bc.tip.clone();
+        self.save_history(&self.db)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// ResetHistory forgets the history, so that Sync rebuilds it from the genesis block
+    fn reset_history(&mut self) {
+        self.history.clear();
+        self.synced_tip.clear();
+    }
+
+    /// ApplyChainUpdate removes the transactions of the disconnected blocks from
+    /// the history and adds those of the connected blocks concerning the wallet
+    fn apply_chain_update(&mut self, update: &ChainUpdate) {
+        for block in &update.disconnected {
+            for tx in block.get_transaction() {
+                self.history.remove(&tx.id);
+            }
+        }
+        let mut addresses: HashSet<String> = self.get_all_addresses().into_iter().collect();
+        addresses.extend(self.get_multisig_addresses());
+        for block in &update.connected {
+            for tx in block.get_transaction() {
+                if let Some(wtx) = self.wallet_tx(block, tx, &addresses) {
+                    self.history.insert(wtx.txid.clone(), wtx);
+                }
+            }
+        }
+    }
+
+    /// WalletTx returns the WalletTx of tx when it pays to or from addresses
+    fn wallet_tx(
+        &self,
+        block: &Block,
+        tx: &Transaction,
+        addresses: &HashSet<String>,
+    ) -> Option<WalletTx> {
+        let mut wtx = WalletTx {
+            txid: tx.id.clone(),
+            block_hash: block.get_hash(),
+            height: block.get_height(),
+            timestamp: block.get_timestamp(),
+            is_coinbase: tx.is_coinbase(),
+            amount: 0,
+            ledger: BTreeMap::new(),
+            credits: Vec::new(),
+            counterparties: Vec::new(),
+        };
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                let prev = match self.history.get(&vin.txid) {
+                    Some(prev) => prev,
+                    None => continue,
+                };
+                for (vout, address, value) in &prev.credits {
+                    if *vout == vin.vout {
+                        *wtx.ledger.entry(address.clone()).or_insert(0) -= *value as i64;
+                        wtx.amount -= *value as i64;
+                    }
+                }
+            }
+        }
+        for (vout, out) in tx.vout.iter().enumerate() {
+            let address = output_address(&out.script_pubkey);
+            if addresses.contains(&address) {
+                *wtx.ledger.entry(address.clone()).or_insert(0) += out.value as i64;
+                wtx.amount += out.value as i64;
+                wtx.credits.push((vout as i32, address, out.value));
+            } else {
+                wtx.counterparties.push((address, out.value));
+            }
+        }
+        if wtx.ledger.is_empty() {
+            None
+        } else {
+            Some(wtx)
+        }
+    }
+
+    fn save_history(&self, db: &sled::Db) -> Result<()> {
+        let history = db.open_tree("history")?;
+        history.clear()?;
+        for (txid, wtx) in &self.history {
+            history.insert(txid, serialize(wtx)?)?;
+        }
+        db.open_tree("sync")?
+            .insert("tip", self.synced_tip.as_bytes())?;
+        Ok(())
     }
 
     /// GetAddresses returns an array of addresses stored in the wallet file
@@ -95,32 +643,157 @@ impl Wallets {
         for (address, _) in &self.wallets {
             addresses.push(address.clone());
         }
+        for address in self.encrypted.keys() {
+            if !self.wallets.contains_key(address) {
+                addresses.push(address.clone());
+            }
+        }
         addresses
     }
 
     /// GetWallet returns a Wallet by its address
+    ///
+    /// The Wallets of an encrypted wallet are only returned while it is unlocked
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
+        if self.encrypted.contains_key(address) && self.is_locked() {
+            return None;
+        }
         self.wallets.get(address)
     }
 
+    /// GetPublicKey returns the public key of an address, even when locked
+    pub fn get_public_key(&self, address: &str) -> Option<&Vec<u8>> {
+        match self.encrypted.get(address) {
+            Some(wallet) => Some(&wallet.public_key),
+            None => self.wallets.get(address).map(|wallet| &wallet.public_key),
+        }
+    }
+
+    /// AddMultisig adds the m of pub_keys multisig address to Wallets
+    pub fn add_multisig(&mut self, m: usize, pub_keys: &[Vec<u8>]) -> Result<String> {
+        if m == 0 || m > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format_err!(
+                "ERROR: cannot require {} of {} keys",
+                m,
+                pub_keys.len()
+            ));
+        }
+        if pub_keys.iter().any(|pub_key| pub_key.len() != 32) {
+            return Err(format_err!("ERROR: public keys must be 32 bytes"));
+        }
+        let redeem_script = Script::multisig(m, pub_keys);
+        let address = get_script_address(&redeem_script)?;
+        self.multisig.insert(address.clone(), redeem_script);
+        // the address may have been paid already
+        self.reset_history();
+        info!("add {} of {} multisig: {}", m, pub_keys.len(), address);
+        Ok(address)
+    }
+
+    /// GetMultisigAddresses returns the multisig addresses stored in the wallet file
+    pub fn get_multisig_addresses(&self) -> Vec<String> {
+        self.multisig.keys().cloned().collect()
+    }
+
+    /// GetRedeemScript returns the redeem script of a multisig address
+    pub fn get_redeem_script(&self, address: &str) -> Option<&Script> {
+        self.multisig.get(address)
+    }
+
     /// SaveToFile saves wallets to a file
-    pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+    ///
+    /// New keys of an encrypted wallet are encrypted, which needs it unlocked,
+    /// and keys saved before the wallet was encrypted are removed
+    pub fn save_all(&mut self) -> Result<()> {
+        let db = self.db.clone();
 
-        for (address, wallet) in &self.wallets {
-            let data = serialize(wallet)?;
-            db.insert(address, data)?;
+        if let Some(params) = &self.key_params {
+            let encrypted = db.open_tree("encrypted")?;
+            for (address, wallet) in &self.wallets {
+                if !self.encrypted.contains_key(address) {
+                    let key = match (&self.key, self.is_locked()) {
+                        (Some(key), false) => key,
+                        _ => return Err(format_err!("ERROR: wallet is locked")),
+                    };
+                    self.encrypted
+                        .insert(address.clone(), EncryptedWallet::new(key, wallet));
+                }
+                encrypted.insert(address, serialize(&self.encrypted[address])?)?;
+            }
+            if let (Some(mnemonic), None) = (&self.mnemonic, &self.sealed_mnemonic) {
+                let key = match (&self.key, self.is_locked()) {
+                    (Some(key), false) => key,
+                    _ => return Err(format_err!("ERROR: wallet is locked")),
+                };
+                self.sealed_mnemonic = Some(seal(key, mnemonic.as_bytes(), &[]));
+            }
+            if let Some(sealed) = &self.sealed_mnemonic {
+                let hd = db.open_tree("hd")?;
+                hd.insert("sealed_mnemonic", serialize(sealed)?)?;
+                hd.remove("mnemonic")?;
+            }
+            db.open_tree("encryption")?
+                .insert("params", serialize(params)?)?;
+            encrypted.flush()?;
+            db.clear()?;
+        } else {
+            for (address, wallet) in &self.wallets {
+                let data = serialize(wallet)?;
+                db.insert(address, data)?;
+            }
+            if let Some(mnemonic) = &self.mnemonic {
+                db.open_tree("hd")?
+                    .insert("mnemonic", mnemonic.as_bytes())?;
+            }
         }
+        db.open_tree("hd")?
+            .insert("next_index", serialize(&self.next_index)?)?;
+        let multisig = db.open_tree("multisig")?;
+        for (address, redeem_script) in &self.multisig {
+            multisig.insert(address, serialize(redeem_script)?)?;
+        }
+        self.save_history(&db)?;
 
         db.flush()?;
-        drop(db);
         Ok(())
     }
 }
 
+/// OutputAddress returns the address an output script pays to, or "nonstandard"
+fn output_address(script_pubkey: &Script) -> String {
+    let (body, hash_type) = match (
+        script_pubkey.get_pub_key_hash(),
+        script_pubkey.get_script_hash(),
+    ) {
+        (Some(pub_key_hash), _) => (pub_key_hash, HashType::Key),
+        (_, Some(script_hash)) => (script_hash, HashType::Script),
+        _ => return String::from("nonstandard"),
+    };
+    let address = Address {
+        body: body.to_vec(),
+        scheme: Scheme::Base58,
+        hash_type,
+        ..Default::default()
+    };
+    address
+        .encode()
+        .unwrap_or_else(|_| String::from("nonstandard"))
+}
+
+/// DeriveWallet returns the Wallet of the key at index of the seed
+fn derive_wallet(seed: &[u8], index: u32) -> Wallet {
+    let mut key = ExtendedKey::master(seed);
+    for i in HD_PATH.iter() {
+        key = key.child(*i);
+    }
+    Wallet::from_key(&key.child(index).key)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::coinselect::*;
+    use crate::utxoset::*;
 
     #[test]
     fn test_create_wallet_and_hash() {
@@ -138,22 +811,228 @@ mod test {
 
     #[test]
     fn test_wallets() {
-        let mut ws = Wallets::new().unwrap();
-        let wa1 = ws.create_wallet();
+        let data_dir = "data/test_wallets";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
         let w1 = ws.get_wallet(&wa1).unwrap().clone();
         ws.save_all().unwrap();
 
-        let ws2 = Wallets::new().unwrap();
+        let ws2 = Wallets::new(data_dir).unwrap();
         let w2 = ws2.get_wallet(&wa1).unwrap();
         assert_eq!(&w1, w2);
     }
 
     #[test]
-    #[should_panic]
+    fn test_multisig() {
+        let data_dir = "data/test_wallets_multisig";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let pub_keys: Vec<Vec<u8>> = (0..3).map(|_| Wallet::new().public_key).collect();
+        ws.add_multisig(4, &pub_keys).unwrap_err();
+        let address = ws.add_multisig(2, &pub_keys).unwrap();
+        let decoded = Address::decode(&address).unwrap();
+        assert_eq!(decoded.hash_type, HashType::Script);
+        ws.save_all().unwrap();
+
+        let ws2 = Wallets::new(data_dir).unwrap();
+        let redeem_script = ws2.get_redeem_script(&address).unwrap();
+        assert_eq!(redeem_script.get_multisig(), Some((2, pub_keys)));
+        assert_eq!(redeem_script.hash().unwrap(), decoded.body);
+    }
+
+    #[test]
+    fn test_hd() {
+        // SLIP-0010 ed25519 test vector 1
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed);
+        assert_eq!(
+            hex::encode(&master.key),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            hex::encode(&master.chain_code),
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb"
+        );
+        assert_eq!(
+            hex::encode(&master.child(0).key),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+
+        let data_dir = "data/test_wallets_hd";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addresses: Vec<String> = (0..3).map(|_| ws.create_wallet().unwrap()).collect();
+        let mnemonic = ws.get_mnemonic().unwrap().to_string();
+        assert_eq!(mnemonic.split_whitespace().count(), 12);
+        ws.save_all().unwrap();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa4 = ws.create_wallet().unwrap();
+        assert!(!addresses.contains(&wa4));
+        assert_eq!(ws.non_hd_key_count(), 0);
+        // a random key of a wallet created before mnemonics is not backed up
+        let legacy = Wallet::new();
+        ws.wallets.insert(legacy.get_address(), legacy);
+        assert_eq!(ws.non_hd_key_count(), 1);
+
+        // only the second key was used, the gap limit stops at the fourth
+        let used = Address::decode(&addresses[1]).unwrap().body;
+        std::fs::remove_dir_all("data/test_wallets_hd_restore").ok();
+        let mut restored = Wallets::new("data/test_wallets_hd_restore").unwrap();
+        restored
+            .restore(&mnemonic, 2, |pub_key_hash| pub_key_hash == used.as_slice())
+            .unwrap();
+        let mut restored_addresses = restored.get_all_addresses();
+        restored_addresses.sort();
+        let mut expected = addresses[..2].to_vec();
+        expected.sort();
+        assert_eq!(restored_addresses, expected);
+        assert_eq!(restored.create_wallet().unwrap(), addresses[2]);
+        restored.restore(&mnemonic, 1, |_| false).unwrap_err();
+        std::fs::remove_dir_all("data/test_wallets_hd_bad").ok();
+        Wallets::new("data/test_wallets_hd_bad")
+            .unwrap()
+            .restore("not a mnemonic", 1, |_| false)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_history() {
+        let data_dir = "data/test_wallets_history";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let addr1 = ws.create_wallet().unwrap();
+        let addr2 = ws.create_wallet().unwrap();
+        let other = Wallet::new().get_address();
+        ws.save_all().unwrap();
+        let mut bc = Blockchain::create_blockchain(addr1.clone(), data_dir).unwrap();
+        bc.set_params(ChainParams {
+            coinbase_maturity: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let genesis = bc.tip.clone();
+        let mut utxo_set = UTXOSet::new(bc).unwrap();
+        utxo_set.reindex().unwrap();
+
+        let wallet = ws.get_wallet(&addr1).unwrap();
+        let tx1 = Transaction::new_UTXO(
+            wallet,
+            &addr2,
+            3 * COIN,
+            0,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let cbtx =
+            Transaction::new_coinbase(other.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+        let block = utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx1.clone()])
+            .unwrap();
+        utxo_set.update(&block).unwrap();
+        let wallet = ws.get_wallet(&addr2).unwrap();
+        let tx2 = Transaction::new_UTXO(
+            wallet,
+            &other,
+            2 * COIN,
+            COIN,
+            &CoinControl::default(),
+            &utxo_set,
+        )
+        .unwrap();
+        let cbtx = Transaction::new_coinbase(other.clone(), String::new(), INITIAL_SUBSIDY + COIN)
+            .unwrap();
+        utxo_set
+            .blockchain
+            .mine_block(vec![cbtx, tx2.clone()])
+            .unwrap();
+        bc = utxo_set.blockchain;
+
+        ws.sync(&bc).unwrap();
+        let history = ws.get_history(None);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].txid, tx1.id);
+        assert_eq!(history[1].amount, 0);
+        assert_eq!(history[1].ledger[&addr2], 3 * COIN as i64);
+        assert_eq!(history[2].amount, -3 * COIN as i64);
+        assert_eq!(history[2].counterparties, vec![(other.clone(), 2 * COIN)]);
+        assert_eq!(history[2].confirmations(2), 1);
+        let history = ws.get_history(Some(&addr2));
+        assert_eq!(history.len(), 2);
+
+        // a longer chain from the genesis block disconnects both payments
+        let mut prev = genesis;
+        for height in 1..4 {
+            let cbtx =
+                Transaction::new_coinbase(other.clone(), String::new(), INITIAL_SUBSIDY).unwrap();
+            let block = Block::new_block(vec![cbtx], prev, height, INITIAL_BITS).unwrap();
+            prev = block.get_hash();
+            bc.add_block(block).unwrap();
+        }
+        ws.sync(&bc).unwrap();
+        assert_eq!(ws.get_history(None).len(), 1);
+        drop(bc);
+        let ws = Wallets::new(data_dir).unwrap();
+        assert_eq!(ws.get_history(None).len(), 1);
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let data_dir = "data/test_wallets_encrypt";
+        std::fs::remove_dir_all(data_dir).ok();
+        let mut ws = Wallets::new(data_dir).unwrap();
+        let wa1 = ws.create_wallet().unwrap();
+        let w1 = ws.get_wallet(&wa1).unwrap().clone();
+        ws.save_all().unwrap();
+
+        // an existing unencrypted wallet is migrated
+        let mut ws = Wallets::new(data_dir).unwrap();
+        // a low cost keeps the test fast
+        let params = KeyParams::new(4, SCRYPT_R, SCRYPT_P);
+        ws.encrypt("secret", params.clone()).unwrap();
+        ws.encrypt("secret", params).unwrap_err();
+        assert!(ws.is_locked());
+        assert!(ws.get_wallet(&wa1).is_none());
+        assert_eq!(ws.get_public_key(&wa1), Some(&w1.public_key));
+        assert!(ws.get_mnemonic().is_none());
+        ws.create_wallet().unwrap_err();
+
+        let mut ws = Wallets::new(data_dir).unwrap();
+        assert!(ws.is_encrypted());
+        assert_eq!(ws.get_all_addresses(), vec![wa1.clone()]);
+        assert!(ws.get_wallet(&wa1).is_none());
+        ws.unlock("wrong", Duration::from_secs(60)).unwrap_err();
+        ws.unlock("secret", Duration::from_secs(60)).unwrap();
+        assert_eq!(ws.get_wallet(&wa1), Some(&w1));
+        assert!(ws.get_mnemonic().is_some());
+        let wa2 = ws.create_wallet().unwrap();
+        ws.save_all().unwrap();
+        ws.lock();
+        assert!(ws.get_wallet(&wa1).is_none());
+
+        let db = open_db(&Path::new(data_dir).join("wallets")).unwrap();
+        assert!(db.get(&wa1).unwrap().is_none());
+        let mut ws = Wallets::new(data_dir).unwrap();
+        assert_eq!(ws.get_all_addresses().len(), 2);
+        ws.unlock("secret", Duration::from_secs(60)).unwrap();
+        assert!(!ws.lock_if_expired());
+        ws.unlock("secret", Duration::from_millis(0)).unwrap();
+        assert!(ws.is_locked());
+        assert!(ws.get_wallet(&wa2).is_none());
+        assert!(ws.lock_if_expired());
+        assert!(ws.wallets.is_empty());
+        assert!(ws.key.is_none());
+    }
+
+    #[test]
     fn test_wallets_not_exist() {
+        let data_dir = "data/test_wallets_not_exist";
+        std::fs::remove_dir_all(data_dir).ok();
         let w3 = Wallet::new();
-        let ws2 = Wallets::new().unwrap();
-        ws2.get_wallet(&w3.get_address()).unwrap();
+        let ws2 = Wallets::new(data_dir).unwrap();
+        assert!(ws2.get_wallet(&w3.get_address()).is_none());
     }
 
     #[test]